@@ -0,0 +1,411 @@
+//! Writing a flash image with the serial bootloader in the ROM of the ESP32-S3,
+//! for boards that don't run the Esparrier firmware yet.
+//!
+//! The image is sent compressed, the ROM inflates it while writing, and the
+//! result is checked with the MD5 the ROM computes of the flash.
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use md5::{Digest, Md5};
+use serialport::{SerialPort, SerialPortType, UsbPortInfo};
+
+/// The USB VID and PID of the USB-Serial-JTAG port of the ESP32-S3 itself.
+const USB_JTAG_SERIAL: (u16, u16) = (0x303a, 0x1001);
+
+/// USB vendors of the serial ports boards have, Espressif for the chip's own
+/// port, and Silicon Labs, WCH and FTDI for USB to UART bridges.
+const BOARD_PORT_VIDS: [u16; 4] = [0x303a, 0x10c4, 0x1a86, 0x0403];
+
+/// The ROM bootloader talks at this baud rate until told otherwise.
+const ROM_BAUD: u32 = 115200;
+
+const FLASH_DEFL_BEGIN: u8 = 0x10;
+const FLASH_DEFL_DATA: u8 = 0x11;
+const FLASH_DEFL_END: u8 = 0x12;
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0a;
+const SPI_SET_PARAMS: u8 = 0x0b;
+const SPI_ATTACH: u8 = 0x0d;
+const CHANGE_BAUDRATE: u8 = 0x0f;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// The register with a value telling the chips apart, and the value of the ESP32-S3.
+const CHIP_DETECT_MAGIC_REG: u32 = 0x4000_1000;
+const ESP32S3_MAGIC: u32 = 0x9;
+
+/// How much data each `FLASH_DEFL_DATA` command carries.
+const FLASH_WRITE_SIZE: usize = 0x400;
+/// The flash size told to the ROM, big enough for the images of all the boards.
+const FLASH_SIZE: u32 = 16 * 1024 * 1024;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long erasing, writing and hashing the flash may take per MB.
+const ERASE_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+const WRITE_TIMEOUT_PER_MB: Duration = Duration::from_secs(40);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
+
+/// How many times the chip is reset into the bootloader before giving up.
+const CONNECT_ATTEMPTS: usize = 5;
+
+/// The serial ports that may belong to an ESP32-S3 board.
+pub fn board_ports() -> anyhow::Result<Vec<(String, UsbPortInfo)>> {
+    let mut ports = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) if BOARD_PORT_VIDS.contains(&info.vid) => {
+                Some((port.port_name, info))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ports.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(ports)
+}
+
+/// A connection to the ROM bootloader.
+pub struct Flasher {
+    port: Box<dyn SerialPort>,
+}
+
+impl Flasher {
+    /// Open the port, reset the chip into the bootloader and check that it's
+    /// an ESP32-S3. `usb_jtag` tells if the port is the chip's own, which is
+    /// reset differently from one behind a USB to UART bridge.
+    pub fn connect(port_name: &str, usb_jtag: bool) -> anyhow::Result<Self> {
+        let port = serialport::new(port_name, ROM_BAUD)
+            .timeout(DEFAULT_TIMEOUT)
+            .open()
+            .map_err(|e| anyhow::anyhow!("Failed to open {port_name}: {e}"))?;
+        let mut flasher = Self { port };
+        let mut synced = false;
+        for attempt in 0..CONNECT_ATTEMPTS {
+            log::debug!("Resetting into the bootloader, attempt {}", attempt + 1);
+            flasher.reset_into_bootloader(usb_jtag)?;
+            if flasher.sync()? {
+                synced = true;
+                break;
+            }
+        }
+        if !synced {
+            anyhow::bail!(
+                "The bootloader on {port_name} doesn't answer. Hold the BOOT button \
+                 while plugging in the board, then try again."
+            );
+        }
+        let magic = flasher.command(READ_REG, &CHIP_DETECT_MAGIC_REG.to_le_bytes(), 0)?;
+        if magic != ESP32S3_MAGIC {
+            anyhow::bail!("The chip on {port_name} is not an ESP32-S3 (magic {magic:#x}).");
+        }
+        flasher.command(SPI_ATTACH, &[0; 8], 0)?;
+        let params = [0, FLASH_SIZE, 0x10000, 0x1000, 0x100, 0xffff];
+        flasher.command(SPI_SET_PARAMS, &words(&params), 0)?;
+        Ok(flasher)
+    }
+
+    /// Switch both ends to a faster baud rate for the transfer.
+    pub fn set_baud(&mut self, baud: u32) -> anyhow::Result<()> {
+        if baud == ROM_BAUD {
+            return Ok(());
+        }
+        self.command(CHANGE_BAUDRATE, &words(&[baud, 0]), 0)?;
+        self.port.set_baud_rate(baud)?;
+        std::thread::sleep(Duration::from_millis(50));
+        self.port.clear(serialport::ClearBuffer::Input)?;
+        Ok(())
+    }
+
+    /// Write the image at `offset` and verify it, `progress` is called with
+    /// the bytes written so far and the total.
+    pub fn write_flash<F>(
+        &mut self,
+        offset: u32,
+        image: &[u8],
+        mut progress: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(image)?;
+        let compressed = encoder.finish()?;
+        let blocks = compressed.chunks(FLASH_WRITE_SIZE).count();
+        // The ROM erases the whole region first
+        let erase_size = image.len().div_ceil(FLASH_WRITE_SIZE) * FLASH_WRITE_SIZE;
+        let begin = [
+            erase_size as u32,
+            blocks as u32,
+            FLASH_WRITE_SIZE as u32,
+            offset,
+            0,
+        ];
+        self.command_with_timeout(
+            FLASH_DEFL_BEGIN,
+            &words(&begin),
+            0,
+            per_mb(ERASE_TIMEOUT_PER_MB, erase_size),
+        )?;
+        // Inflate along to know how much flash each block writes, for its timeout
+        let mut inflater = flate2::Decompress::new(true);
+        let mut scratch = vec![0; 64 * 1024];
+        for (seq, block) in compressed.chunks(FLASH_WRITE_SIZE).enumerate() {
+            let written = inflater.total_out();
+            let mut input = block;
+            while !input.is_empty() {
+                let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+                inflater.decompress(input, &mut scratch, flate2::FlushDecompress::None)?;
+                input = &input[(inflater.total_in() - total_in) as usize..];
+                if inflater.total_in() == total_in && inflater.total_out() == total_out {
+                    break;
+                }
+            }
+            let size = (inflater.total_out() - written) as usize;
+            let mut data = words(&[block.len() as u32, seq as u32, 0, 0]);
+            data.extend_from_slice(block);
+            let checksum = block.iter().fold(0xef, |sum, b| sum ^ b);
+            self.command_with_timeout(
+                FLASH_DEFL_DATA,
+                &data,
+                checksum as u32,
+                per_mb(WRITE_TIMEOUT_PER_MB, size),
+            )?;
+            progress(inflater.total_out(), image.len() as u64);
+        }
+        let expected = hex(&Md5::digest(image));
+        let md5 = self.flash_md5(offset, image.len())?;
+        if md5 != expected {
+            anyhow::bail!("The flash doesn't match the image, MD5 {md5} instead of {expected}.");
+        }
+        // Stay in the bootloader, the chip is reset afterwards
+        self.command(FLASH_DEFL_END, &words(&[1]), 0)?;
+        Ok(())
+    }
+
+    /// Reset the chip so it runs the new firmware.
+    pub fn hard_reset(&mut self) -> anyhow::Result<()> {
+        self.port.write_request_to_send(true)?;
+        std::thread::sleep(Duration::from_millis(100));
+        self.port.write_request_to_send(false)?;
+        Ok(())
+    }
+
+    /// The MD5 of the flash region as hex, the ROM sends it as 32 hex digits.
+    fn flash_md5(&mut self, offset: u32, size: usize) -> anyhow::Result<String> {
+        let data = words(&[offset, size as u32, 0, 0]);
+        let timeout = per_mb(MD5_TIMEOUT_PER_MB, size);
+        let response = self.exchange(SPI_FLASH_MD5, &data, 0, timeout)?;
+        let md5 = response
+            .get(..32)
+            .ok_or_else(|| anyhow::anyhow!("Invalid MD5 response"))?;
+        Ok(String::from_utf8_lossy(md5).to_lowercase())
+    }
+
+    /// Pulse EN with IO0 held low, through RTS and DTR like esptool does.
+    fn reset_into_bootloader(&mut self, usb_jtag: bool) -> anyhow::Result<()> {
+        let pause = Duration::from_millis(100);
+        if usb_jtag {
+            self.set_lines(false, false)?;
+            std::thread::sleep(pause);
+            self.set_lines(true, false)?;
+            std::thread::sleep(pause);
+            // Through (1, 1) rather than (0, 0), RTS is set again for Windows
+            self.set_lines(false, true)?;
+            self.port.write_request_to_send(true)?;
+            std::thread::sleep(pause);
+            self.set_lines(false, false)?;
+        } else {
+            // RTS pulls EN and DTR pulls IO0 low on the boards
+            self.set_lines(false, true)?;
+            std::thread::sleep(pause);
+            self.set_lines(true, false)?;
+            std::thread::sleep(Duration::from_millis(50));
+            self.port.write_data_terminal_ready(false)?;
+        }
+        self.port.clear(serialport::ClearBuffer::Input)?;
+        Ok(())
+    }
+
+    fn set_lines(&mut self, dtr: bool, rts: bool) -> anyhow::Result<()> {
+        self.port.write_data_terminal_ready(dtr)?;
+        self.port.write_request_to_send(rts)?;
+        Ok(())
+    }
+
+    /// Send the sync command until the bootloader answers, it answers a few times.
+    fn sync(&mut self) -> anyhow::Result<bool> {
+        let mut data = vec![0x07, 0x07, 0x12, 0x20];
+        data.extend_from_slice(&[0x55; 32]);
+        for _ in 0..5 {
+            if self.exchange(SYNC, &data, 0, SYNC_TIMEOUT).is_ok() {
+                // Drop the other answers
+                while self.read_frame(SYNC_TIMEOUT).is_ok() {}
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn command(&mut self, op: u8, data: &[u8], checksum: u32) -> anyhow::Result<u32> {
+        self.command_with_timeout(op, data, checksum, DEFAULT_TIMEOUT)
+    }
+
+    /// Run a command and return the value of the response.
+    fn command_with_timeout(
+        &mut self,
+        op: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> anyhow::Result<u32> {
+        let response = self.exchange(op, data, checksum, timeout)?;
+        Ok(u32::from_le_bytes(response[..4].try_into().unwrap()))
+    }
+
+    /// Run a command, returns the value of the response followed by its data
+    /// without the status bytes.
+    fn exchange(
+        &mut self,
+        op: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.port
+            .write_all(&slip_encode(&command_packet(op, data, checksum)))?;
+        self.port.flush()?;
+        // Stale answers, e.g. to earlier syncs, are skipped
+        for _ in 0..100 {
+            let frame = self.read_frame(timeout)?;
+            if let Some(response) = parse_response(op, &frame)? {
+                return Ok(response);
+            }
+        }
+        anyhow::bail!("No response to command {op:#04x}")
+    }
+
+    /// Read a SLIP frame, fails if it doesn't arrive in time.
+    fn read_frame(&mut self, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        self.port.set_timeout(timeout)?;
+        let deadline = std::time::Instant::now() + timeout;
+        let mut decoder = SlipDecoder::default();
+        let mut byte = [0];
+        loop {
+            if std::time::Instant::now() > deadline {
+                anyhow::bail!("Timed out waiting for the bootloader");
+            }
+            match self.port.read(&mut byte) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    anyhow::bail!("Timed out waiting for the bootloader")
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(frame) = decoder.push(byte[0]) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+/// The request packet of a command, direction 0, the command, the length of
+/// the data and the checksum, which only the data commands use.
+pub(crate) fn command_packet(op: u8, data: &[u8], checksum: u32) -> Vec<u8> {
+    let mut packet = vec![0, op];
+    packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    packet.extend_from_slice(&checksum.to_le_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// The value of the response to `op` followed by its data without the status
+/// bytes, `None` if the frame is not a response to `op`.
+pub(crate) fn parse_response(op: u8, frame: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    if frame.len() < 8 || frame[0] != 1 || frame[1] != op {
+        return Ok(None);
+    }
+    // The ROM ends the data with 4 status bytes, the first one is 0 on success
+    let body = &frame[8..];
+    let Some(status_at) = body.len().checked_sub(4) else {
+        anyhow::bail!("Invalid response to command {op:#04x}");
+    };
+    if body[status_at] != 0 {
+        anyhow::bail!(
+            "Command {op:#04x} failed with error {:#04x}",
+            body[status_at + 1]
+        );
+    }
+    let mut response = frame[4..8].to_vec();
+    response.extend_from_slice(&body[..status_at]);
+    Ok(Some(response))
+}
+
+/// Collects the bytes of a SLIP frame, anything before the first 0xc0 is dropped.
+#[derive(Default)]
+pub(crate) struct SlipDecoder {
+    frame: Vec<u8>,
+    started: bool,
+    escaped: bool,
+}
+
+impl SlipDecoder {
+    /// Add a byte, returns the frame once it's complete.
+    pub(crate) fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            // Back to back delimiters are an empty frame, skipped
+            0xc0 if !self.started || self.frame.is_empty() => self.started = true,
+            0xc0 => {
+                self.started = false;
+                return Some(std::mem::take(&mut self.frame));
+            }
+            _ if !self.started => {}
+            0xdb if !self.escaped => self.escaped = true,
+            b => {
+                self.frame.push(match (b, self.escaped) {
+                    (0xdc, true) => 0xc0,
+                    (0xdd, true) => 0xdb,
+                    (b, _) => b,
+                });
+                self.escaped = false;
+            }
+        }
+        None
+    }
+}
+
+/// Frame a packet, 0xc0 delimits it and is escaped inside like 0xdb.
+pub(crate) fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(packet.len() + 2);
+    frame.push(0xc0);
+    for &b in packet {
+        match b {
+            0xc0 => frame.extend_from_slice(&[0xdb, 0xdc]),
+            0xdb => frame.extend_from_slice(&[0xdb, 0xdd]),
+            b => frame.push(b),
+        }
+    }
+    frame.push(0xc0);
+    frame
+}
+
+pub(crate) fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Scale the timeout to the size, but never below the default.
+pub(crate) fn per_mb(timeout: Duration, size: usize) -> Duration {
+    let scaled = timeout.mul_f64(size as f64 / (1024.0 * 1024.0));
+    scaled.max(DEFAULT_TIMEOUT)
+}
+
+/// Whether the port is the USB-Serial-JTAG of the chip itself.
+pub fn is_usb_jtag(info: &UsbPortInfo) -> bool {
+    (info.vid, info.pid) == USB_JTAG_SERIAL
+}
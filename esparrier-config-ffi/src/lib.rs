@@ -0,0 +1,386 @@
+//! C bindings of `esparrier-config`, suitable for `cbindgen`.
+//!
+//! All functions return `ESPARRIER_OK` (0) on success or one of the negative
+//! `ESPARRIER_ERR_*` codes, the message of the last error on the calling
+//! thread is available from [`esparrier_last_error_message`].
+//!
+//! Strings passed in are UTF-8 C strings owned by the caller. Strings
+//! returned are UTF-8 C strings owned by the library and must be released
+//! with [`esparrier_string_free`].
+//!
+//! A handle must not be used by more than one thread at a time.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use esparrier_config::{blocking::Esparrier, Error, EsparrierConfig, OtaProgress};
+
+pub const ESPARRIER_OK: c_int = 0;
+/// A pointer argument is null, or a string is not valid UTF-8 or JSON.
+pub const ESPARRIER_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const ESPARRIER_ERR_DEVICE_NOT_FOUND: c_int = -2;
+pub const ESPARRIER_ERR_PERMISSION_DENIED: c_int = -3;
+/// More than one device is connected.
+pub const ESPARRIER_ERR_AMBIGUOUS_DEVICE: c_int = -4;
+pub const ESPARRIER_ERR_TIMEOUT: c_int = -5;
+pub const ESPARRIER_ERR_DISCONNECTED: c_int = -6;
+/// The configuration failed validation.
+pub const ESPARRIER_ERR_INVALID_CONFIG: c_int = -7;
+/// The firmware doesn't implement the command.
+pub const ESPARRIER_ERR_NOT_SUPPORTED: c_int = -8;
+pub const ESPARRIER_ERR_OTA_FAILED: c_int = -9;
+/// The device sent something unexpected.
+pub const ESPARRIER_ERR_PROTOCOL: c_int = -10;
+/// Any other USB or I/O failure.
+pub const ESPARRIER_ERR_IO: c_int = -11;
+/// The device is used by another program.
+pub const ESPARRIER_ERR_DEVICE_BUSY: c_int = -12;
+/// A bug in the library, please report it.
+pub const ESPARRIER_ERR_INTERNAL: c_int = -100;
+
+/// An opaque handle to a device.
+pub struct EsparrierHandle {
+    inner: Esparrier,
+}
+
+/// OTA progress callback, called with the bytes sent, acknowledged by the
+/// device and the total, and the `user_data` given to [`esparrier_upload_ota`].
+pub type EsparrierProgressFn =
+    Option<extern "C" fn(sent: usize, acknowledged: usize, total: usize, user_data: *mut c_void)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs would truncate the message anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+fn error_code(e: &Error) -> c_int {
+    match e {
+        Error::DeviceNotFound | Error::UnknownDevice => ESPARRIER_ERR_DEVICE_NOT_FOUND,
+        Error::PermissionDenied => ESPARRIER_ERR_PERMISSION_DENIED,
+        Error::DeviceBusy => ESPARRIER_ERR_DEVICE_BUSY,
+        Error::OpenFailed { source, .. } => error_code(source),
+        Error::AmbiguousDevice(_) => ESPARRIER_ERR_AMBIGUOUS_DEVICE,
+        Error::Timeout => ESPARRIER_ERR_TIMEOUT,
+        Error::Disconnected { .. } => ESPARRIER_ERR_DISCONNECTED,
+        Error::ConfigError(_) | Error::VerificationFailed { .. } => ESPARRIER_ERR_INVALID_CONFIG,
+        Error::NotSupported { .. } | Error::OtaNotSupported => ESPARRIER_ERR_NOT_SUPPORTED,
+        Error::OtaError(_) => ESPARRIER_ERR_OTA_FAILED,
+        Error::InvalidResponse | Error::ProtocolDesync { .. } | Error::FormatError(_) => {
+            ESPARRIER_ERR_PROTOCOL
+        }
+        _ => ESPARRIER_ERR_IO,
+    }
+}
+
+/// Run `f`, recording the error message and turning errors and panics into codes.
+fn call(f: impl FnOnce() -> Result<(), (c_int, String)>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => {
+            LAST_ERROR.with(|e| *e.borrow_mut() = None);
+            ESPARRIER_OK
+        }
+        Ok(Err((code, message))) => {
+            set_last_error(message);
+            code
+        }
+        Err(_) => {
+            set_last_error("Internal error".to_string());
+            ESPARRIER_ERR_INTERNAL
+        }
+    }
+}
+
+fn device_error(e: Error) -> (c_int, String) {
+    (error_code(&e), e.to_string())
+}
+
+fn invalid_argument(message: &str) -> (c_int, String) {
+    (ESPARRIER_ERR_INVALID_ARGUMENT, message.to_string())
+}
+
+unsafe fn handle_ref<'a>(handle: *const EsparrierHandle) -> Result<&'a Esparrier, (c_int, String)> {
+    handle
+        .as_ref()
+        .map(|h| &h.inner)
+        .ok_or_else(|| invalid_argument("Handle is null"))
+}
+
+unsafe fn write_string(out: *mut *mut c_char, s: String) -> Result<(), (c_int, String)> {
+    if out.is_null() {
+        return Err(invalid_argument("Output pointer is null"));
+    }
+    let s = CString::new(s).map_err(|_| invalid_argument("String contains NUL"))?;
+    *out = s.into_raw();
+    Ok(())
+}
+
+/// Open the only connected device, storing the handle in `*out`.
+///
+/// # Safety
+/// `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_open(out: *mut *mut EsparrierHandle) -> c_int {
+    call(|| {
+        if out.is_null() {
+            return Err(invalid_argument("Output pointer is null"));
+        }
+        let inner =
+            Esparrier::auto_detect(false, None, None, None, None, None).map_err(device_error)?;
+        *out = Box::into_raw(Box::new(EsparrierHandle { inner }));
+        Ok(())
+    })
+}
+
+/// Close a handle, null is ignored.
+///
+/// # Safety
+/// `handle` must come from [`esparrier_open`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_close(handle: *mut EsparrierHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Get the device state as JSON, storing the string in `*out`.
+///
+/// # Safety
+/// `handle` must be an open handle and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_get_state_json(
+    handle: *const EsparrierHandle,
+    out: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let state = handle_ref(handle)?.get_state().map_err(device_error)?;
+        let json =
+            serde_json::to_string(&state).map_err(|e| (ESPARRIER_ERR_INTERNAL, e.to_string()))?;
+        write_string(out, json)
+    })
+}
+
+/// Get the configuration as JSON, with the password redacted, storing the string in `*out`.
+///
+/// # Safety
+/// `handle` must be an open handle and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_get_config_json(
+    handle: *const EsparrierHandle,
+    out: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let config = handle_ref(handle)?
+            .get_config()
+            .map_err(device_error)?
+            .redacted();
+        let json =
+            serde_json::to_string(&config).map_err(|e| (ESPARRIER_ERR_INTERNAL, e.to_string()))?;
+        write_string(out, json)
+    })
+}
+
+/// Write the configuration from JSON, it takes effect after [`esparrier_commit`].
+///
+/// # Safety
+/// `handle` must be an open handle and `json` a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_set_config_json(
+    handle: *const EsparrierHandle,
+    json: *const c_char,
+) -> c_int {
+    call(|| {
+        let esparrier = handle_ref(handle)?;
+        if json.is_null() {
+            return Err(invalid_argument("JSON is null"));
+        }
+        let json = CStr::from_ptr(json)
+            .to_str()
+            .map_err(|_| invalid_argument("JSON is not valid UTF-8"))?;
+        let config = EsparrierConfig::from_json_strict(json)
+            .map_err(|e| (ESPARRIER_ERR_INVALID_ARGUMENT, e.to_string()))?;
+        esparrier.set_config(config).map_err(device_error)
+    })
+}
+
+/// Commit the configuration, the device restarts.
+///
+/// The handle is closed whether or not this succeeds.
+///
+/// # Safety
+/// `handle` must be an open handle and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_commit(handle: *mut EsparrierHandle) -> c_int {
+    call(|| {
+        if handle.is_null() {
+            return Err(invalid_argument("Handle is null"));
+        }
+        let handle = Box::from_raw(handle);
+        handle.inner.commit_config().map_err(device_error)
+    })
+}
+
+/// Upload a firmware image via OTA, the device reboots into it when done.
+///
+/// `progress` may be null, it's called on the calling thread with `user_data`.
+///
+/// # Safety
+/// `handle` must be an open handle and `data` point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_upload_ota(
+    handle: *const EsparrierHandle,
+    data: *const u8,
+    len: usize,
+    progress: EsparrierProgressFn,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let esparrier = handle_ref(handle)?;
+        if data.is_null() {
+            return Err(invalid_argument("Firmware data is null"));
+        }
+        let firmware = std::slice::from_raw_parts(data, len);
+        let callback = progress.map(|progress| {
+            move |p: &OtaProgress| progress(p.sent, p.acknowledged, p.total, user_data)
+        });
+        esparrier
+            .upload_ota(firmware, callback)
+            .map_err(device_error)
+    })
+}
+
+/// Get the message of the last error on this thread, or null if the last
+/// call succeeded. Release it with [`esparrier_string_free`].
+#[no_mangle]
+pub extern "C" fn esparrier_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|e| {
+        e.borrow()
+            .clone()
+            .map_or(ptr::null_mut(), CString::into_raw)
+    })
+}
+
+/// Release a string returned by the library, null is ignored.
+///
+/// # Safety
+/// `s` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn esparrier_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> Option<String> {
+        let message = esparrier_last_error_message();
+        if message.is_null() {
+            return None;
+        }
+        let s = unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        unsafe { esparrier_string_free(message) };
+        Some(s)
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        let mut out = ptr::null_mut();
+        assert_eq!(
+            unsafe { esparrier_get_state_json(ptr::null(), &mut out) },
+            ESPARRIER_ERR_INVALID_ARGUMENT
+        );
+        assert!(out.is_null());
+        assert_eq!(last_error().as_deref(), Some("Handle is null"));
+        assert_eq!(
+            unsafe { esparrier_open(ptr::null_mut()) },
+            ESPARRIER_ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            unsafe { esparrier_commit(ptr::null_mut()) },
+            ESPARRIER_ERR_INVALID_ARGUMENT
+        );
+        unsafe {
+            esparrier_close(ptr::null_mut());
+            esparrier_string_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(error_code(&Error::Timeout), ESPARRIER_ERR_TIMEOUT);
+        assert_eq!(
+            error_code(&Error::Disconnected {
+                command: Some(b's')
+            }),
+            ESPARRIER_ERR_DISCONNECTED
+        );
+        assert_eq!(
+            error_code(&Error::PermissionDenied),
+            ESPARRIER_ERR_PERMISSION_DENIED
+        );
+        assert_eq!(
+            error_code(&Error::Io(std::io::ErrorKind::Other.into())),
+            ESPARRIER_ERR_IO
+        );
+        assert_eq!(error_code(&Error::DeviceBusy), ESPARRIER_ERR_DEVICE_BUSY);
+        let open_failed = |source| Error::OpenFailed {
+            device: Box::new(esparrier_config::DeviceSummary {
+                bus_id: "1".to_string(),
+                address: 2,
+                vendor_id: esparrier_config::USB_VID,
+                product_id: esparrier_config::USB_PID,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+                model_id: None,
+                version: None,
+                ip_address: None,
+                probe_error: None,
+            }),
+            source: Box::new(source),
+        };
+        assert_eq!(
+            error_code(&open_failed(Error::PermissionDenied)),
+            ESPARRIER_ERR_PERMISSION_DENIED
+        );
+        assert_eq!(
+            error_code(&open_failed(Error::DeviceBusy)),
+            ESPARRIER_ERR_DEVICE_BUSY
+        );
+        assert_eq!(call(|| Ok(())), ESPARRIER_OK);
+        assert_eq!(last_error(), None);
+        assert_eq!(call(|| panic!("bug")), ESPARRIER_ERR_INTERNAL);
+        assert_eq!(last_error().as_deref(), Some("Internal error"));
+    }
+
+    #[ignore = "This test needs device attached"]
+    #[test]
+    fn test_get_state_json() {
+        let mut handle = ptr::null_mut();
+        assert_eq!(unsafe { esparrier_open(&mut handle) }, ESPARRIER_OK);
+        let mut json = ptr::null_mut();
+        assert_eq!(
+            unsafe { esparrier_get_state_json(handle, &mut json) },
+            ESPARRIER_OK
+        );
+        println!("{:?}", unsafe { CStr::from_ptr(json) });
+        unsafe {
+            esparrier_string_free(json);
+            esparrier_close(handle);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{future::Future, time::Duration};
+
+use log::debug;
+use nusb::DeviceInfo;
+
+use crate::{
+    DeviceFilter, DeviceSummary, Error, NusbTransport, OpenOptions, Transport, USB_PID, USB_VID,
+};
+
+/// A USB stack that can find Esparrier devices and open them.
+///
+/// [`NusbBackend`] is the default, other stacks, e.g. rusb or a USB/IP proxy,
+/// can be used with [`Esparrier::with_backend`](crate::Esparrier::with_backend).
+/// Filtering and picking the device is shared, so all backends behave the same.
+pub trait UsbBackend: Send + Sync {
+    /// A connected device, as listed by the backend.
+    type Device: Send;
+    /// The packet channel to an opened device, bulk transfers on its vendor interface.
+    type Transport: Transport;
+
+    /// List the connected devices.
+    fn list_devices(&self) -> impl Future<Output = Result<Vec<Self::Device>, Error>> + Send;
+
+    /// Describe the device, the `model_id` and `version` are left unset.
+    fn summary(&self, device: &Self::Device) -> DeviceSummary;
+
+    /// Check if the device has the Esparrier vendor interface, class 0xFF,
+    /// subclass 0x0D and protocol 0x0A.
+    fn has_vendor_interface(&self, device: &Self::Device) -> bool;
+
+    /// Open the device and claim its vendor interface.
+    ///
+    /// Fails with `Error::PermissionDenied` or `Error::DeviceBusy` if it can't
+    /// be claimed, and `Error::UnknownDevice` if it has no vendor interface.
+    fn open(
+        &self,
+        device: Self::Device,
+    ) -> impl Future<Output = Result<Self::Transport, Error>> + Send;
+}
+
+/// The default backend, using nusb.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NusbBackend;
+
+impl UsbBackend for NusbBackend {
+    type Device = DeviceInfo;
+    type Transport = NusbTransport;
+
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>, Error> {
+        Ok(nusb::list_devices().await?.collect())
+    }
+
+    fn summary(&self, device: &DeviceInfo) -> DeviceSummary {
+        DeviceSummary::from_device_info(device)
+    }
+
+    fn has_vendor_interface(&self, device: &DeviceInfo) -> bool {
+        device
+            .interfaces()
+            .any(|i| i.class() == 0xFF && i.subclass() == 0x0D && i.protocol() == 0x0A)
+    }
+
+    async fn open(&self, device: DeviceInfo) -> Result<NusbTransport, Error> {
+        NusbTransport::open(device).await
+    }
+}
+
+/// How often [`open_matching`] looks for the device again while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Check if the device has the default Esparrier VID and PID, or the vendor
+/// interface, which is how devices configured with a custom VID and PID are found.
+fn looks_like_esparrier<B: UsbBackend>(backend: &B, device: &B::Device) -> bool {
+    let summary = backend.summary(device);
+    (summary.vendor_id == USB_VID && summary.product_id == USB_PID)
+        || backend.has_vendor_interface(device)
+}
+
+/// The connected devices matching the filter that look like an Esparrier.
+///
+/// Fails with `Error::AmbiguousDevice` if more than one matches, unless
+/// `allow_ambiguous` is set.
+pub(crate) async fn candidates<B: UsbBackend>(
+    backend: &B,
+    filter: &DeviceFilter,
+    options: &OpenOptions,
+) -> Result<Vec<B::Device>, Error> {
+    let candidates = backend
+        .list_devices()
+        .await?
+        .into_iter()
+        .filter(|d| filter.matches(&backend.summary(d)) && looks_like_esparrier(backend, d))
+        .collect::<Vec<_>>();
+    if candidates.len() > 1 && !options.allow_ambiguous {
+        return Err(Error::AmbiguousDevice(
+            candidates.iter().map(|d| backend.summary(d)).collect(),
+        ));
+    }
+    Ok(candidates)
+}
+
+/// Open the first of the candidates that can be opened, see
+/// [`Esparrier::open`](crate::Esparrier::open) for how it fails.
+pub(crate) async fn open_first<B: UsbBackend>(
+    backend: &B,
+    candidates: Vec<B::Device>,
+) -> Result<B::Transport, Error> {
+    let mut failure: Option<(DeviceSummary, Error)> = None;
+    for device in candidates {
+        let summary = backend.summary(&device);
+        match backend.open(device).await {
+            Ok(transport) => return Ok(transport),
+            Err(e) => {
+                debug!("Failed to open device {summary}: {e}");
+                if failure
+                    .as_ref()
+                    .is_none_or(|(_, f)| open_error_rank(&e) >= open_error_rank(f))
+                {
+                    failure = Some((summary, e));
+                }
+            }
+        }
+    }
+    Err(match failure {
+        Some((device, e)) => Error::OpenFailed {
+            device: Box::new(device),
+            source: Box::new(e),
+        },
+        None => Error::DeviceNotFound,
+    })
+}
+
+/// Open the device matching the filter, polling for it if `options.wait` is set.
+pub(crate) async fn open_matching<B: UsbBackend>(
+    backend: &B,
+    filter: &DeviceFilter,
+    options: &OpenOptions,
+) -> Result<B::Transport, Error> {
+    if !options.wait {
+        return open_first(backend, candidates(backend, filter, options).await?).await;
+    }
+    crate::with_timeout(options.timeout, async {
+        loop {
+            match open_first(backend, candidates(backend, filter, options).await?).await {
+                Err(Error::DeviceNotFound) => {}
+                Err(Error::OpenFailed { source, .. }) if matches!(*source, Error::DeviceBusy) => {}
+                result => return result,
+            }
+            futures_timer::Delay::new(POLL_INTERVAL).await;
+        }
+    })
+    .await?
+}
+
+/// How relevant a failure to open a device is to the user, a permission problem
+/// is more likely the cause than some other device not being an Esparrier.
+fn open_error_rank(e: &Error) -> u8 {
+    match e {
+        Error::PermissionDenied => 3,
+        Error::DeviceBusy => 2,
+        Error::UnknownDevice => 0,
+        _ => 1,
+    }
+}
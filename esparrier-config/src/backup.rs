@@ -0,0 +1,93 @@
+//! Saving the configuration of a device to restore it later, see [`Esparrier::backup`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Command, ConfigError, DeviceIdentity, Error, Esparrier, EsparrierConfig, ModelId, Transport,
+    REDACTED,
+};
+
+/// The configuration of a device with the model and firmware it came from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigBackup {
+    /// The password is [`REDACTED`] unless `password_included` is set.
+    pub config: EsparrierConfig,
+    /// Whether the firmware returned the WiFi password, see
+    /// [`get_config_full`](Esparrier::get_config_full).
+    pub password_included: bool,
+    /// Unknown for backups made before these fields were saved.
+    pub model_id: Option<u8>,
+    pub version: Option<(u8, u8, u8)>,
+    /// The USB identity, only known for USB devices.
+    pub device: Option<DeviceIdentity>,
+}
+
+impl ConfigBackup {
+    /// Parse a backup, older backups are only the configuration with the
+    /// password, as returned by [`get_config_full`](Esparrier::get_config_full).
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| Error::FormatError(format!("Invalid backup: {e}")))?;
+        if value.get("config").is_some_and(|c| c.is_object()) {
+            return serde_json::from_value(value)
+                .map_err(|e| Error::FormatError(format!("Invalid backup: {e}")));
+        }
+        let config = serde_json::from_value(value)
+            .map_err(|e| Error::FormatError(format!("Invalid backup: {e}")))?;
+        Ok(Self {
+            config,
+            password_included: true,
+            model_id: None,
+            version: None,
+            device: None,
+        })
+    }
+
+    pub fn model(&self) -> Option<ModelId> {
+        self.model_id.map(ModelId::from)
+    }
+}
+
+impl<T: Transport> Esparrier<T> {
+    /// Read the configuration, with the WiFi password if the firmware
+    /// supports [`Command::FullConfig`], and the model and firmware version.
+    ///
+    /// `device` is left unset, see [`identity`](Esparrier::identity) for USB devices.
+    pub async fn backup(&self) -> Result<ConfigBackup, Error> {
+        let state = self.get_state().await?;
+        let password_included = self.supports(Command::FullConfig).await?;
+        let config = if password_included {
+            self.get_config_full().await?
+        } else {
+            EsparrierConfig {
+                password: REDACTED.to_string(),
+                ..self.get_config().await?
+            }
+        };
+        Ok(ConfigBackup {
+            config,
+            password_included,
+            model_id: Some(state.model_id),
+            version: Some(state.version()),
+            device: None,
+        })
+    }
+
+    /// Upload the configuration of the backup and commit it, the device restarts.
+    ///
+    /// Without the password in the backup the current one is kept, which
+    /// needs firmware that supports [`Command::FullConfig`], otherwise this
+    /// fails with [`ConfigError::PasswordRequired`] before changing anything.
+    pub async fn restore(self, backup: &ConfigBackup) -> Result<(), Error> {
+        let mut config = backup.config.clone();
+        if !backup.password_included {
+            if !self.supports(Command::FullConfig).await? {
+                return Err(ConfigError::PasswordRequired.into());
+            }
+            config.password = self.get_config_full().await?.password;
+        }
+        config.validate_for_version(self.get_state().await?.version())?;
+        self.set_config_verified(config).await?;
+        self.commit_config().await
+    }
+}
@@ -0,0 +1,113 @@
+//! A synchronous wrapper of [`Esparrier`](crate::Esparrier) for programs without an async runtime.
+//!
+//! ```no_run
+//! use esparrier_config::blocking::Esparrier;
+//!
+//! let esparrier = Esparrier::auto_detect(false, None, None, None, None, None).unwrap();
+//! println!("{:?}", esparrier.get_config().unwrap());
+//! ```
+//!
+//! Each handle owns a current-thread Tokio runtime and blocks on it. The
+//! methods panic if called from within an async context, e.g. inside a Tokio
+//! task, use the async API there instead.
+
+use tokio::runtime::Runtime;
+
+use crate::{
+    DeviceFilter, Error, EsparrierConfig, EsparrierState, NusbTransport, OpenOptions, OtaProgress,
+    Transport,
+};
+
+/// A blocking handle to an Esparrier device, see the [module docs](self).
+pub struct Esparrier<T = NusbTransport> {
+    inner: crate::Esparrier<T>,
+    runtime: Runtime,
+}
+
+fn new_runtime() -> Result<Runtime, Error> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+impl<T: Transport> Esparrier<T> {
+    /// Create a handle talking over `transport`, see [`crate::Esparrier::new`].
+    pub fn new(transport: T) -> Result<Self, Error> {
+        Ok(Self {
+            inner: crate::Esparrier::new(transport),
+            runtime: new_runtime()?,
+        })
+    }
+
+    /// Get the async handle, e.g. to change its options.
+    pub fn inner(&self) -> &crate::Esparrier<T> {
+        &self.inner
+    }
+
+    /// Get the async handle back, dropping the runtime.
+    pub fn into_inner(self) -> crate::Esparrier<T> {
+        self.inner
+    }
+
+    /// Get the current state from the device.
+    pub fn get_state(&self) -> Result<EsparrierState, Error> {
+        self.runtime.block_on(self.inner.get_state())
+    }
+
+    /// Get the configuration from the device, see [`crate::Esparrier::get_config`].
+    pub fn get_config(&self) -> Result<EsparrierConfig, Error> {
+        self.runtime.block_on(self.inner.get_config())
+    }
+
+    /// Write the configuration to the device, see [`crate::Esparrier::set_config`].
+    pub fn set_config(&self, config: EsparrierConfig) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.set_config(config))
+    }
+
+    /// Commit the configuration and restart the device, see [`crate::Esparrier::commit_config`].
+    pub fn commit_config(self) -> Result<(), Error> {
+        let Self { inner, runtime } = self;
+        runtime.block_on(inner.commit_config())
+    }
+
+    /// Upload firmware via OTA, see [`crate::Esparrier::upload_ota`].
+    pub fn upload_ota<F>(&self, firmware: &[u8], progress_callback: Option<F>) -> Result<(), Error>
+    where
+        F: FnMut(&OtaProgress),
+    {
+        self.runtime
+            .block_on(self.inner.upload_ota(firmware, progress_callback))
+    }
+}
+
+impl Esparrier {
+    /// Auto detect the device, see [`crate::Esparrier::auto_detect`].
+    pub fn auto_detect<A, B, C, D, E>(
+        wait: bool,
+        vid: A,
+        pid: B,
+        bus: C,
+        address: D,
+        serial: E,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Option<u16>>,
+        B: Into<Option<u16>>,
+        C: Into<Option<String>>,
+        D: Into<Option<u8>>,
+        E: Into<Option<String>>,
+    {
+        let runtime = new_runtime()?;
+        let inner = runtime.block_on(crate::Esparrier::auto_detect(
+            wait, vid, pid, bus, address, serial,
+        ))?;
+        Ok(Self { inner, runtime })
+    }
+
+    /// Open the device matching the filter, see [`crate::Esparrier::open`].
+    pub fn open(filter: &DeviceFilter, options: &OpenOptions) -> Result<Self, Error> {
+        let runtime = new_runtime()?;
+        let inner = runtime.block_on(crate::Esparrier::open(filter, options))?;
+        Ok(Self { inner, runtime })
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::Display;
+
+use crate::{EsparrierState, FeatureFlag, FeatureFlags};
+
+/// The first firmware version implementing the KeepAwake command.
+pub const KEEP_AWAKE_MIN_VERSION: (u8, u8, u8) = (0, 6, 0);
+
+/// The first firmware version keeping a log that can be read with GetLogs.
+pub const LOGS_MIN_VERSION: (u8, u8, u8) = (0, 9, 0);
+
+/// The first firmware version returning the configuration with the password
+/// with ReadFullConfig.
+pub const FULL_CONFIG_MIN_VERSION: (u8, u8, u8) = (0, 10, 0);
+
+/// Device commands whose availability depends on the firmware,
+/// see [`Esparrier::supports`](crate::Esparrier::supports).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Command {
+    KeepAwake,
+    Ota,
+    Clipboard,
+    Brightness,
+    LedColor,
+    Splash,
+    Logs,
+    FullConfig,
+}
+
+impl Command {
+    pub const ALL: [Command; 8] = [
+        Command::KeepAwake,
+        Command::Ota,
+        Command::Clipboard,
+        Command::Brightness,
+        Command::LedColor,
+        Command::Splash,
+        Command::Logs,
+        Command::FullConfig,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::KeepAwake => "KeepAwake",
+            Command::Ota => "OTA",
+            Command::Clipboard => "Clipboard",
+            Command::Brightness => "Brightness",
+            Command::LedColor => "LedColor",
+            Command::Splash => "Splash",
+            Command::Logs => "Logs",
+            Command::FullConfig => "FullConfig",
+        }
+    }
+
+    /// What the firmware needs to implement this command.
+    pub fn requirement(&self) -> Requirement {
+        match self {
+            Command::KeepAwake => Requirement {
+                min_version: Some(KEEP_AWAKE_MIN_VERSION),
+                feature: None,
+            },
+            Command::Ota => Requirement {
+                min_version: None,
+                feature: Some(FeatureFlag::Ota.into()),
+            },
+            Command::Clipboard => Requirement {
+                min_version: None,
+                feature: Some(FeatureFlag::Clipboard.into()),
+            },
+            Command::Brightness => Requirement {
+                min_version: None,
+                feature: Some(FeatureFlag::Led | FeatureFlag::SmartLed),
+            },
+            Command::LedColor => Requirement {
+                min_version: None,
+                feature: Some(FeatureFlag::SmartLed.into()),
+            },
+            Command::Splash => Requirement {
+                min_version: None,
+                feature: Some(FeatureFlag::Graphics.into()),
+            },
+            Command::Logs => Requirement {
+                min_version: Some(LOGS_MIN_VERSION),
+                feature: None,
+            },
+            Command::FullConfig => Requirement {
+                min_version: Some(FULL_CONFIG_MIN_VERSION),
+                feature: None,
+            },
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Minimum firmware version and/or feature flag a command needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Requirement {
+    pub min_version: Option<(u8, u8, u8)>,
+    /// Any one of these flags is enough.
+    pub feature: Option<FeatureFlags>,
+}
+
+impl Requirement {
+    /// Whether the firmware reporting `state` meets the requirement.
+    pub fn is_met_by(&self, state: &EsparrierState) -> bool {
+        self.min_version.is_none_or(|v| state.version() >= v)
+            && self
+                .feature
+                .is_none_or(|f| state.feature_set().intersects(f))
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let feature = self.feature.map(|flags| {
+            flags
+                .iter()
+                .map(|flag| flag.name())
+                .collect::<Vec<_>>()
+                .join(" or ")
+        });
+        match (self.min_version, feature) {
+            (Some((major, minor, patch)), Some(feature)) => write!(
+                f,
+                "firmware {major}.{minor}.{patch} or newer with the {feature} feature"
+            ),
+            (Some((major, minor, patch)), None) => {
+                write!(f, "firmware {major}.{minor}.{patch} or newer")
+            }
+            (None, Some(feature)) => write!(f, "firmware with the {feature} feature"),
+            (None, None) => f.write_str("any firmware"),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Images for the boot splash of the models with a display.
+
+use crate::{Error, ModelId};
+
+/// Pixel format and dimensions of an image sent with
+/// [`Esparrier::upload_image`](crate::Esparrier::upload_image).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// 16-bit 5-6-5 RGB, big-endian as the display controller expects it.
+    Rgb565 { width: u16, height: u16 },
+}
+
+impl ImageFormat {
+    /// The format byte of the UploadImage command.
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            ImageFormat::Rgb565 { .. } => 0,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u16, u16) {
+        match self {
+            ImageFormat::Rgb565 { width, height } => (*width, *height),
+        }
+    }
+
+    /// Size in bytes of an image in this format.
+    pub fn image_size(&self) -> usize {
+        match self {
+            ImageFormat::Rgb565 { width, height } => *width as usize * *height as usize * 2,
+        }
+    }
+
+    /// Check that `data` is an image in this format that fits the display of `model_id`.
+    pub(crate) fn validate(&self, data: &[u8], model_id: u8) -> Result<(), Error> {
+        let Some(display) = model_display_size(model_id) else {
+            return Err(Error::FormatError(format!(
+                "Model id {model_id} has no display"
+            )));
+        };
+        let (width, height) = self.dimensions();
+        if (width, height) != display {
+            return Err(Error::FormatError(format!(
+                "Image is {width}x{height}, the display is {}x{}",
+                display.0, display.1
+            )));
+        }
+        if data.len() != self.image_size() {
+            return Err(Error::FormatError(format!(
+                "Image data is {} bytes, expected {} for {width}x{height}",
+                data.len(),
+                self.image_size()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Get the display size of a model, `None` if it has no display.
+pub fn model_display_size(model_id: u8) -> Option<(u16, u16)> {
+    ModelId::from(model_id).display_size()
+}
+
+/// Convert packed 8-bit RGB pixels to [`ImageFormat::Rgb565`] data.
+pub fn rgb888_to_rgb565(rgb: &[u8]) -> Vec<u8> {
+    rgb.chunks_exact(3)
+        .flat_map(|p| {
+            let pixel =
+                ((p[0] as u16 & 0xF8) << 8) | ((p[1] as u16 & 0xFC) << 3) | (p[2] as u16 >> 3);
+            pixel.to_be_bytes()
+        })
+        .collect()
+}
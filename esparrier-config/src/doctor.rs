@@ -0,0 +1,123 @@
+//! Find out why devices can't be used, see [`Esparrier::diagnose`].
+
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    compare_bus_ids, DeviceSummary, Error, Esparrier, NusbBackend, UsbBackend, PROBE_TIMEOUT,
+    USB_PID, USB_VID,
+};
+
+/// Whether a device can be used, see [`Esparrier::diagnose`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeviceHealth {
+    /// The device answers, with its firmware version.
+    Ok { version: (u8, u8, u8) },
+    /// The user may not open the device, e.g. there's no udev rule on Linux.
+    PermissionDenied,
+    /// Another program has claimed the device.
+    Busy,
+    /// The device has no Esparrier vendor interface, it probably doesn't run
+    /// the Esparrier firmware.
+    NoVendorInterface,
+    /// The device was opened but doesn't answer.
+    NotResponding { error: String },
+    /// The device couldn't be opened for another reason.
+    OpenFailed { error: String },
+}
+
+/// A device that looks like an Esparrier and whether it can be used.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceDiagnosis {
+    pub device: DeviceSummary,
+    pub health: DeviceHealth,
+    /// *(Windows only)* The driver bound to the device, the vendor interface
+    /// needs WinUSB.
+    pub driver: Option<String>,
+}
+
+impl DeviceDiagnosis {
+    pub fn is_usable(&self) -> bool {
+        matches!(self.health, DeviceHealth::Ok { .. })
+    }
+}
+
+impl Esparrier {
+    /// Check each device that looks like an Esparrier, the ones with the
+    /// specified VID and PID, the defaults if not set, and the ones with the
+    /// vendor interface, by opening it and getting its state.
+    ///
+    /// The devices are sorted like [`list_devices_detailed`](Self::list_devices_detailed).
+    pub async fn diagnose(
+        vid: Option<u16>,
+        pid: Option<u16>,
+    ) -> Result<Vec<DeviceDiagnosis>, Error> {
+        #[cfg(target_os = "windows")]
+        let driver = |di: &nusb::DeviceInfo| di.driver().map(str::to_string);
+        #[cfg(not(target_os = "windows"))]
+        let driver = |_: &nusb::DeviceInfo| None;
+        diagnose_with(&NusbBackend, vid, pid, driver).await
+    }
+}
+
+/// Check the devices of the backend like [`Esparrier::diagnose`], `driver`
+/// tells the driver bound to a device, if known.
+pub(crate) async fn diagnose_with<B: UsbBackend>(
+    backend: &B,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    driver: impl Fn(&B::Device) -> Option<String>,
+) -> Result<Vec<DeviceDiagnosis>, Error> {
+    let mut diagnoses = Vec::new();
+    for device in backend.list_devices().await? {
+        let summary = backend.summary(&device);
+        let has_ids = summary.vendor_id == vid.unwrap_or(USB_VID)
+            && summary.product_id == pid.unwrap_or(USB_PID);
+        let has_interface = backend.has_vendor_interface(&device);
+        if !has_ids && !has_interface {
+            continue;
+        }
+        let driver = driver(&device);
+        let health = if has_interface {
+            check_health(backend, device).await
+        } else {
+            DeviceHealth::NoVendorInterface
+        };
+        debug!("Device {summary}: {health:?}");
+        diagnoses.push(DeviceDiagnosis {
+            device: summary,
+            health,
+            driver,
+        });
+    }
+    diagnoses.sort_by(|a, b| {
+        compare_bus_ids(&a.device.bus_id, &b.device.bus_id)
+            .then(a.device.address.cmp(&b.device.address))
+    });
+    Ok(diagnoses)
+}
+
+async fn check_health<B: UsbBackend>(backend: &B, device: B::Device) -> DeviceHealth {
+    let transport = match backend.open(device).await {
+        Ok(transport) => transport,
+        Err(Error::PermissionDenied) => return DeviceHealth::PermissionDenied,
+        Err(Error::DeviceBusy) => return DeviceHealth::Busy,
+        Err(Error::UnknownDevice) => return DeviceHealth::NoVendorInterface,
+        Err(e) => {
+            return DeviceHealth::OpenFailed {
+                error: e.to_string(),
+            }
+        }
+    };
+    let mut esparrier = Esparrier::new(transport);
+    esparrier.set_timeout(PROBE_TIMEOUT);
+    match esparrier.get_state().await {
+        Ok(state) => DeviceHealth::Ok {
+            version: state.version(),
+        },
+        Err(e) => DeviceHealth::NotResponding {
+            error: e.to_string(),
+        },
+    }
+}
@@ -0,0 +1,93 @@
+//! `${VAR}` placeholders in configuration files, so secrets can stay out of them.
+
+use serde_json::Value;
+
+use crate::{ConfigError, Error, EsparrierConfig};
+
+impl EsparrierConfig {
+    /// Parse a configuration from JSON like `serde_json::from_str`, after
+    /// replacing the placeholders in string values with environment variables.
+    ///
+    /// `${VAR}` is replaced with the value of `VAR` and fails with
+    /// [`ConfigError::MissingEnvVar`] if it's not set, `${VAR:-default}` falls
+    /// back to `default`. `$${` stands for a literal `${`, other `$` are kept
+    /// as is. The result is not validated.
+    pub fn from_json_with_env(s: &str) -> Result<Self, Error> {
+        let mut value: Value =
+            serde_json::from_str(s).map_err(|e| Error::FormatError(e.to_string()))?;
+        expand_value(&mut value, &|name| std::env::var(name).ok())?;
+        serde_json::from_value(value).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Replace the placeholders in all string fields with environment
+    /// variables, see [`from_json_with_env`](Self::from_json_with_env).
+    ///
+    /// `self` is only modified if all the variables are set.
+    pub fn expand_env(&mut self) -> Result<(), Error> {
+        self.expand_env_with(|name| std::env::var(name).ok())
+    }
+
+    pub(crate) fn expand_env_with<F>(&mut self, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut value =
+            serde_json::to_value(&*self).map_err(|e| Error::FormatError(e.to_string()))?;
+        expand_value(&mut value, &lookup)?;
+        *self = serde_json::from_value(value).map_err(|e| Error::FormatError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+fn expand_value(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), Error> {
+    match value {
+        Value::String(s) => *s = expand_str(s, lookup)?,
+        Value::Array(values) => {
+            for value in values {
+                expand_value(value, lookup)?;
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                expand_value(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_str(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, Error> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            // The value may be a secret, so it's not part of the error
+            let end = after
+                .find('}')
+                .ok_or_else(|| Error::FormatError("Unterminated '${' placeholder".to_string()))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match lookup(name) {
+                Some(value) => expanded.push_str(&value),
+                None => match default {
+                    Some(default) => expanded.push_str(default),
+                    None => return Err(ConfigError::MissingEnvVar(name.to_string()).into()),
+                },
+            }
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
@@ -0,0 +1,207 @@
+//! Tests against a real device, enabled with the `hardware-tests` feature.
+//!
+//! The device is selected with `ESPARRIER_TEST_BUS` and `ESPARRIER_TEST_ADDRESS`,
+//! or `ESPARRIER_TEST_SERIAL`, the tests are skipped if neither is set.
+//! Tests that change the configuration restore it afterwards, also when they
+//! fail, the device never returns the WiFi password so they also need
+//! `ESPARRIER_TEST_PASSWORD` and are skipped without it.
+
+use std::{future::Future, panic::AssertUnwindSafe, time::Duration};
+
+use futures::FutureExt;
+
+use crate::{DeviceFilter, Esparrier, EsparrierConfig, OpenOptions};
+
+/// How long to wait for the device to come back after a restart.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The device selected by the environment, `None` if the tests should be skipped.
+fn test_filter() -> Option<DeviceFilter> {
+    let env = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let filter = DeviceFilter {
+        bus: env("ESPARRIER_TEST_BUS"),
+        address: env("ESPARRIER_TEST_ADDRESS").map(|a| {
+            a.parse()
+                .expect("ESPARRIER_TEST_ADDRESS must be a device address")
+        }),
+        serial: env("ESPARRIER_TEST_SERIAL"),
+        ..Default::default()
+    };
+    if filter.serial.is_none() && (filter.bus.is_none() || filter.address.is_none()) {
+        eprintln!(
+            "Skipped, set ESPARRIER_TEST_BUS and ESPARRIER_TEST_ADDRESS, or ESPARRIER_TEST_SERIAL"
+        );
+        return None;
+    }
+    Some(filter)
+}
+
+/// Open exactly the device selected by the environment.
+async fn open_test_device() -> Option<Esparrier> {
+    let filter = test_filter()?;
+    Some(
+        Esparrier::open(&filter, &OpenOptions::default())
+            .await
+            .expect("Failed to open the test device"),
+    )
+}
+
+/// The configuration of the device with the password from the environment,
+/// to restore it later.
+async fn snapshot_config(esparrier: &Esparrier) -> Option<EsparrierConfig> {
+    let Ok(password) = std::env::var("ESPARRIER_TEST_PASSWORD") else {
+        eprintln!("Skipped, set ESPARRIER_TEST_PASSWORD to restore the configuration");
+        return None;
+    };
+    let mut config = esparrier.get_config().await.unwrap();
+    config.password = password;
+    Some(config)
+}
+
+/// Run a test that changes the configuration, then restore the snapshot, and
+/// commit it if `commit` is set, also when the test panics.
+///
+/// The test gets the device and the snapshot. It may consume the handle, e.g.
+/// to restart the device, the device is opened again for the restore.
+async fn with_config_restored<F, Fut>(commit: bool, test: F)
+where
+    F: FnOnce(Esparrier, EsparrierConfig) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let Some(esparrier) = open_test_device().await else {
+        return;
+    };
+    let Some(snapshot) = snapshot_config(&esparrier).await else {
+        return;
+    };
+    let result = AssertUnwindSafe(test(esparrier, snapshot.clone()))
+        .catch_unwind()
+        .await;
+
+    let options = OpenOptions {
+        wait: true,
+        timeout: Some(RESTART_TIMEOUT),
+        ..Default::default()
+    };
+    let esparrier = Esparrier::open(&test_filter().unwrap(), &options)
+        .await
+        .expect("Failed to open the test device to restore its configuration");
+    esparrier.set_config(snapshot).await.unwrap();
+    if commit {
+        esparrier
+            .commit_config_and_wait(RESTART_TIMEOUT)
+            .await
+            .unwrap();
+    }
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[tokio::test]
+async fn test_get_state() {
+    let Some(esparrier) = open_test_device().await else {
+        return;
+    };
+    let state = esparrier.get_state().await.unwrap();
+    println!("{state:?}");
+}
+
+#[tokio::test]
+async fn test_get_config() {
+    let Some(esparrier) = open_test_device().await else {
+        return;
+    };
+    let config = esparrier.get_config().await.unwrap();
+    println!("{config:?}");
+}
+
+#[tokio::test]
+async fn test_set_config() {
+    // Nothing is committed, but don't leave the test config pending
+    with_config_restored(false, |esparrier, _| async move {
+        let config = serde_json::from_str(
+            r#"{
+                "ssid": "some-wifi",
+                "password": "magic-word",
+                "server": "192.168.2.59:24800",
+                "screen_name": "SAW",
+                "screen_width": 5120,
+                "screen_height": 2880,
+                "flip_wheel": true,
+                "brightness": 10,
+                "serial_number": "88888888",
+                "pid": 4
+            }"#,
+        )
+        .unwrap();
+        esparrier.set_config(config).await.unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_set_config_1() {
+    with_config_restored(false, |esparrier, snapshot| async move {
+        let mut config = snapshot;
+        config.ssid = "test".to_string();
+        esparrier.set_config(config).await.unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_commit_config() {
+    with_config_restored(true, |esparrier, snapshot| async move {
+        let mut config = snapshot;
+        config.ssid = "test".to_string();
+        esparrier.set_config(config).await.unwrap();
+        let esparrier = esparrier
+            .commit_config_and_wait(RESTART_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(esparrier.get_config().await.unwrap().ssid, "test");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_reboot() {
+    let Some(esparrier) = open_test_device().await else {
+        return;
+    };
+    let (_, state) = esparrier.reboot_and_wait(RESTART_TIMEOUT).await.unwrap();
+    println!("{state:?}");
+}
+
+#[cfg(feature = "blocking")]
+mod blocking {
+    use super::test_filter;
+    use crate::{blocking::Esparrier, OpenOptions};
+
+    fn open_test_device() -> Option<Esparrier> {
+        let filter = test_filter()?;
+        Some(
+            Esparrier::open(&filter, &OpenOptions::default())
+                .expect("Failed to open the test device"),
+        )
+    }
+
+    #[test]
+    fn test_blocking_get_state() {
+        let Some(esparrier) = open_test_device() else {
+            return;
+        };
+        let state = esparrier.get_state().unwrap();
+        println!("{state:?}");
+    }
+
+    #[test]
+    fn test_blocking_get_config() {
+        let Some(esparrier) = open_test_device() else {
+            return;
+        };
+        let config = esparrier.get_config().unwrap();
+        println!("{config:?}");
+    }
+}
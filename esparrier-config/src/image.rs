@@ -0,0 +1,149 @@
+//! Sanity checks for ESP32 application images before they are sent over OTA.
+//!
+//! The OTA partition only takes the application image (`esparrier-*.bin`),
+//! flashing anything else leaves the device with an unbootable OTA slot.
+
+use crate::Error;
+
+/// First byte of every ESP image.
+const IMAGE_MAGIC: u8 = 0xE9;
+/// Size of `esp_image_header_t`.
+const IMAGE_HEADER_LEN: usize = 24;
+/// Size of `esp_image_segment_header_t`.
+const SEGMENT_HEADER_LEN: usize = 8;
+/// The bootloader refuses images with more segments than this.
+const MAX_SEGMENTS: u8 = 16;
+/// `ESP_CHIP_ID_ESP32S3`, every supported board uses an ESP32-S3.
+const CHIP_ID_ESP32S3: u16 = 0x0009;
+/// Offset of the partition table in a merged full-flash image.
+const PARTITION_TABLE_OFFSET: usize = 0x8000;
+/// First two bytes of a partition table entry.
+const PARTITION_TABLE_MAGIC: [u8; 2] = [0xAA, 0x50];
+/// How much of a streamed image is read before the upload starts, enough to
+/// see the partition table of a merged image.
+pub(crate) const CHECK_PREFIX_LEN: usize = PARTITION_TABLE_OFFSET + PARTITION_TABLE_MAGIC.len();
+
+/// Whether a segment load address lies in the flash-mapped instruction or
+/// data bus. Applications always have such segments, the bootloader never
+/// does as it runs before the flash cache is set up.
+fn is_flash_mapped(addr: u32) -> bool {
+    (0x3C00_0000..0x3E00_0000).contains(&addr) || (0x4200_0000..0x4400_0000).contains(&addr)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::OtaError(format!(
+        "{msg}, refusing to flash it (use force to override)"
+    ))
+}
+
+/// Check that `data` looks like an ESP32-S3 application image.
+///
+/// `data` may be only a prefix of the image; `complete` tells whether it is
+/// the whole image, which is needed to tell a bootloader from an application.
+pub(crate) fn validate_app_image(data: &[u8], complete: bool) -> Result<(), Error> {
+    if data.len() < IMAGE_HEADER_LEN {
+        return Err(invalid(format!(
+            "Firmware is too short to be an ESP32 image ({} bytes)",
+            data.len()
+        )));
+    }
+    if data[0] != IMAGE_MAGIC {
+        return Err(invalid(format!(
+            "Firmware is not an ESP32 image (magic 0x{:02x}, expected 0x{:02x})",
+            data[0], IMAGE_MAGIC
+        )));
+    }
+    let segments = data[1];
+    if segments == 0 || segments > MAX_SEGMENTS {
+        return Err(invalid(format!(
+            "Firmware has an invalid segment count {segments}"
+        )));
+    }
+    let chip_id = u16::from_le_bytes([data[12], data[13]]);
+    if chip_id != CHIP_ID_ESP32S3 {
+        return Err(invalid(format!(
+            "Firmware is built for chip id {chip_id}, expected ESP32-S3 ({CHIP_ID_ESP32S3})"
+        )));
+    }
+    if data.len() >= PARTITION_TABLE_OFFSET + 2
+        && data[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + 2] == PARTITION_TABLE_MAGIC
+    {
+        return Err(invalid(
+            "Firmware looks like a merged full-flash image, use the esparrier-*.bin application image instead"
+                .to_string(),
+        ));
+    }
+
+    // Walk the segment headers as far as the data goes
+    let mut walk = SegmentWalk::new(segments);
+    walk.update(data);
+    if complete {
+        walk.finish()?;
+    }
+    Ok(())
+}
+
+/// Follows the segment headers of an image fed to it piece by piece, so a
+/// streamed image can be told from a bootloader once all of it has been seen.
+pub(crate) struct SegmentWalk {
+    /// How many bytes were fed so far.
+    position: usize,
+    /// Where the next segment header starts.
+    next_header: usize,
+    segments_left: u8,
+    /// The bytes of the next segment header seen so far.
+    header: Vec<u8>,
+    flash_mapped: bool,
+}
+
+impl SegmentWalk {
+    /// Start at the beginning of an image with `segments` segments.
+    pub(crate) fn new(segments: u8) -> Self {
+        Self {
+            position: 0,
+            next_header: IMAGE_HEADER_LEN,
+            segments_left: segments,
+            header: Vec::with_capacity(SEGMENT_HEADER_LEN),
+            flash_mapped: false,
+        }
+    }
+
+    /// Feed the next bytes of the image.
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while self.segments_left > 0 {
+            let wanted = self.next_header + self.header.len();
+            let skip = wanted - self.position;
+            if skip >= data.len() {
+                break;
+            }
+            data = &data[skip..];
+            let take = (SEGMENT_HEADER_LEN - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            self.position = wanted + take;
+            if self.header.len() == SEGMENT_HEADER_LEN {
+                let addr = u32::from_le_bytes(self.header[0..4].try_into().unwrap());
+                let len = u32::from_le_bytes(self.header[4..8].try_into().unwrap()) as usize;
+                self.flash_mapped |= is_flash_mapped(addr);
+                self.next_header = self.next_header.saturating_add(SEGMENT_HEADER_LEN + len);
+                self.header.clear();
+                self.segments_left -= 1;
+            }
+        }
+        self.position += data.len();
+    }
+
+    /// Check the segments once the whole image was fed.
+    pub(crate) fn finish(&self) -> Result<(), Error> {
+        if self.segments_left > 0 {
+            return Err(invalid("Firmware image is truncated".to_string()));
+        }
+        if !self.flash_mapped {
+            return Err(invalid(
+                "Firmware looks like a bootloader image, use the esparrier-*.bin application image instead"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Bootstrap a configuration from a software Barrier, InputLeap or Deskflow client.
+
+use crate::{Error, EsparrierConfig};
+
+/// The default port of Barrier and its forks.
+pub const DEFAULT_SERVER_PORT: u16 = 24800;
+
+/// The fields a software client configuration can provide.
+const IMPORTABLE_FIELDS: [&str; 4] = ["server", "screen_name", "screen_width", "screen_height"];
+
+/// Client options that take a value, so it's not mistaken for the server address.
+const OPTIONS_WITH_VALUE: [&str; 10] = [
+    "-d",
+    "--debug",
+    "-l",
+    "--log",
+    "--display",
+    "--yscroll",
+    "--profile-dir",
+    "--plugin-dir",
+    "--drop-dir",
+    "--tls-cert",
+];
+
+/// A configuration imported from a software client, see
+/// [`EsparrierConfig::from_client_settings`].
+#[derive(Clone, Debug)]
+pub struct ImportedConfig {
+    /// The imported fields, the others have their defaults. The WiFi
+    /// credentials are never part of a client configuration.
+    pub config: EsparrierConfig,
+    /// The names of the fields that were found.
+    pub extracted: Vec<&'static str>,
+}
+
+impl ImportedConfig {
+    /// The names of the fields a client configuration can provide but this one didn't.
+    pub fn missing(&self) -> Vec<&'static str> {
+        IMPORTABLE_FIELDS
+            .into_iter()
+            .filter(|f| !self.extracted.contains(f))
+            .collect()
+    }
+
+    fn from_parts(
+        host: Option<&str>,
+        port: Option<&str>,
+        screen_name: Option<&str>,
+        width: Option<&str>,
+        height: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut imported = ImportedConfig {
+            config: EsparrierConfig::default(),
+            extracted: Vec::new(),
+        };
+        if let Some(host) = host.filter(|h| !h.is_empty()) {
+            let has_port = host
+                .rsplit_once(':')
+                .is_some_and(|(_, p)| p.parse::<u16>().is_ok());
+            imported.config.server = match port {
+                _ if has_port => host.to_string(),
+                Some(port) => format!("{host}:{port}"),
+                None => format!("{host}:{DEFAULT_SERVER_PORT}"),
+            };
+            imported.extracted.push("server");
+        }
+        if let Some(screen_name) = screen_name.filter(|n| !n.is_empty()) {
+            imported.config.screen_name = screen_name.to_string();
+            imported.extracted.push("screen_name");
+        }
+        if let Some(width) = width {
+            imported.config.screen_width = parse_number("screen width", width)?;
+            imported.extracted.push("screen_width");
+        }
+        if let Some(height) = height {
+            imported.config.screen_height = parse_number("screen height", height)?;
+            imported.extracted.push("screen_height");
+        }
+        if imported.extracted.is_empty() {
+            return Err(Error::FormatError(
+                "no Barrier, InputLeap or Deskflow client settings found".to_string(),
+            ));
+        }
+        Ok(imported)
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u16, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::FormatError(format!("invalid {name} '{value}'")))
+}
+
+impl EsparrierConfig {
+    /// Import the server address and screen name from the settings file of a
+    /// software client, e.g. `~/.config/InputLeap/InputLeap.conf`,
+    /// `~/.config/Debauchee/Barrier.conf` or `~/.config/Deskflow/Deskflow.conf`.
+    ///
+    /// The clients don't store the screen size, it's only taken from
+    /// `screenWidth` and `screenHeight` keys if present.
+    pub fn from_client_settings(ini: &str) -> Result<ImportedConfig, Error> {
+        let mut host = None;
+        let mut port = None;
+        let mut screen_name = None;
+        let mut width = None;
+        let mut height = None;
+        for line in ini.lines().map(str::trim) {
+            if line.starts_with(['[', ';', '#']) {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            // Qt stores keys of nested groups as `group\key`
+            let key = key.trim().rsplit(['\\', '/']).next().unwrap_or_default();
+            match key.to_ascii_lowercase().as_str() {
+                // Barrier and InputLeap call it `serverHostname`, Deskflow `remoteHost`
+                "serverhostname" | "remotehost" => host = Some(value),
+                "port" => port = Some(value),
+                "screenname" => screen_name = Some(value),
+                "screenwidth" => width = Some(value),
+                "screenheight" => height = Some(value),
+                _ => {}
+            }
+        }
+        ImportedConfig::from_parts(host, port, screen_name, width, height)
+    }
+
+    /// Import the server address and screen name from the command line of a
+    /// software client, starting with the program name, e.g.
+    /// `barrierc -f --name laptop 192.168.1.2:24800`.
+    pub fn from_client_command_line(command_line: &str) -> Result<ImportedConfig, Error> {
+        let mut args = command_line
+            .split_whitespace()
+            .map(|a| a.trim_matches(['"', '\'']))
+            .skip(1);
+        let mut screen_name = None;
+        let mut server = None;
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--name=") {
+                screen_name = Some(name);
+            } else if arg == "-n" || arg == "--name" {
+                screen_name = args.next();
+            } else if OPTIONS_WITH_VALUE.contains(&arg) {
+                args.next();
+            } else if !arg.starts_with('-') {
+                server = Some(arg);
+            }
+        }
+        ImportedConfig::from_parts(server, None, screen_name, None, None)
+    }
+}
@@ -1,17 +1,59 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    fmt::Display,
+    future::Future,
+    net::Ipv4Addr,
+    ops::BitOr,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use futures::StreamExt;
-use log::debug;
-use nusb::{
-    hotplug::HotplugEvent,
-    transfer::{Bulk, Buffer, Direction, In, Out},
-    DeviceInfo, Endpoint, ErrorKind,
+use futures::{
+    future::{self, Either},
+    io::{AsyncRead, AsyncReadExt},
+    Stream, StreamExt,
 };
+use futures_timer::Delay;
+use log::debug;
+use nusb::{hotplug::HotplugEvent, transfer::TransferError, DeviceInfo};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+
+mod backend;
+mod backup;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod capability;
+mod display;
+mod doctor;
+mod env;
+#[cfg(all(test, feature = "hardware-tests"))]
+mod hardware_tests;
+mod image;
+mod import;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+mod model;
+mod monitor;
+mod stats;
+mod transport;
+mod watch;
+
+pub use backend::{NusbBackend, UsbBackend};
+pub use backup::ConfigBackup;
+pub use capability::{
+    Command, Requirement, FULL_CONFIG_MIN_VERSION, KEEP_AWAKE_MIN_VERSION, LOGS_MIN_VERSION,
+};
+pub use display::{model_display_size, rgb888_to_rgb565, ImageFormat};
+pub use doctor::{DeviceDiagnosis, DeviceHealth};
+pub use import::{ImportedConfig, DEFAULT_SERVER_PORT};
+pub use model::ModelId;
+pub use monitor::{StateChange, StateField};
+pub use stats::{CommandEvent, TransferStats};
+pub use transport::{NusbTransport, Transport};
+pub use watch::EsparrierEvent;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
@@ -21,6 +63,12 @@ pub enum ConfigError {
     #[error("Config field '{0}' is too long")]
     FieldTooLong(String),
 
+    #[error("WiFi password is too short, WPA requires at least 8 characters")]
+    PasswordTooShort,
+
+    #[error("WiFi password of 64 characters must be a hex-encoded PSK")]
+    InvalidPsk,
+
     #[error("Config field '{0}' is out of range [{1}..{2}]")]
     FieldOutOfRange(String, usize, usize),
 
@@ -30,10 +78,52 @@ pub enum ConfigError {
     #[error("Config field '{0}' is invalid IP address")]
     InvalidIpAddress(String),
 
-    #[error("Config field '{0}' has invalid IPv4 CIDR prefix")]
-    InvalidIpCidrPrefix(String),
+    #[error("Config field '{0}' has invalid IPv4 CIDR prefix '{1}', expected 1..32")]
+    InvalidIpCidrPrefix(String, String),
+
+    #[error("Config field '{0}' requires a CIDR prefix length, e.g. '192.168.1.10/24'")]
+    MissingIpCidrPrefix(String),
+
+    #[error("Unknown config field '{0}'")]
+    UnknownField(String),
+
+    /// Every unknown field with the closest known field name, if any is close.
+    #[error("Unknown config fields: {}", describe_unknown_fields(.0))]
+    UnknownFields(Vec<(String, Option<&'static str>)>),
+
+    #[error("The device never returns the WiFi password, it must be set explicitly")]
+    PasswordRequired,
+
+    #[error("Environment variable '{0}' is not set")]
+    MissingEnvVar(String),
+
+    #[error("Too many DNS servers, {0} are set but the firmware keeps at most {MAX_DNS_SERVERS}")]
+    TooManyDnsServers(usize),
+
+    #[error("DNS server '{0}' is listed more than once")]
+    DuplicateDnsServer(String),
+
+    #[error(
+        "A static ip_addr needs a dns_server or a gateway, otherwise host names can't be resolved"
+    )]
+    MissingResolver,
+
+    /// `position` counts characters from 0.
+    #[error(
+        "Screen name has invalid character {character:?} at position {position}, only letters, \
+         digits, '.', '_' and '-' are allowed, and '.' and '-' not at either end"
+    )]
+    InvalidScreenName { character: char, position: usize },
+
+    /// Several problems found by [`EsparrierConfig::validate_all`].
+    #[error("{} config errors: {}", .0.len(), describe_config_errors(.0))]
+    Multiple(Vec<ConfigError>),
 }
 
+/// Errors of the device operations.
+///
+/// [`is_retryable`](Error::is_retryable) tells which ones may go away by
+/// simply running the operation again.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Device not found")]
@@ -45,6 +135,15 @@ pub enum Error {
     #[error("Device busy")]
     DeviceBusy,
 
+    #[error("{} devices match, specify which one to use", .0.len())]
+    AmbiguousDevice(Vec<DeviceSummary>),
+
+    #[error("Failed to open device ({device}): {source}")]
+    OpenFailed {
+        device: Box<DeviceSummary>,
+        source: Box<Error>,
+    },
+
     #[error("Permission denied")]
     PermissionDenied,
 
@@ -55,10 +154,33 @@ pub enum Error {
     ActiveConfiguration(#[from] nusb::ActiveConfigurationError),
 
     #[error("USB error: {0}")]
-    Usb(#[from] nusb::Error),
+    Usb(nusb::Error),
 
     #[error("Transfer failed {0}")]
-    TransferFailed(#[from] nusb::transfer::TransferError),
+    TransferFailed(nusb::transfer::TransferError),
+
+    /// The device went away, e.g. it was unplugged or rebooted.
+    #[error("Device disconnected{}", command_context(*.command))]
+    Disconnected { command: Option<u8> },
+
+    /// The device answered with something that doesn't belong to the command,
+    /// the host and the device are out of step.
+    #[error(
+        "Protocol out of sync, expected '{}' but got {:#04x}{}",
+        *.expected as char,
+        .got,
+        command_context(Some(*.command))
+    )]
+    ProtocolDesync { command: u8, expected: u8, got: u8 },
+
+    #[error("Transfer failed after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        source: nusb::transfer::TransferError,
+    },
+
+    #[error("Operation timed out")]
+    Timeout,
 
     #[error("Invalid response")]
     InvalidResponse,
@@ -66,6 +188,9 @@ pub enum Error {
     #[error("Format error, {0}")]
     FormatError(String),
 
+    #[error("Configuration verification failed, field '{field}' does not match")]
+    VerificationFailed { field: String },
+
     #[error(transparent)]
     ConfigError(#[from] ConfigError),
 
@@ -74,20 +199,34 @@ pub enum Error {
 
     #[error("OTA error: {0}")]
     OtaError(String),
+
+    #[error("{command} is not supported by the device, it needs {required}")]
+    NotSupported {
+        command: Command,
+        required: Requirement,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct EsparrierState {
     pub version_major: u8,
     pub version_minor: u8,
     pub version_patch: u8,
-    pub feature_flags: u8,
+    pub feature_flags: FeatureFlags,
     pub ip_address: Ipv4Addr,
     pub ip_prefix: u8,
     pub server_connected: bool,
     pub active: bool,
     pub keep_awake: bool,
     pub model_id: u8,
+    /// Bytes after the fields known to this library, sent by newer firmware.
+    /// See [`mac_address`](Self::mac_address) and [`uptime_seconds`](Self::uptime_seconds).
+    #[serde(default)]
+    pub extra: Vec<u8>,
+    /// The 32-bit feature flags, only set after
+    /// [`Esparrier::get_extended_features`] on firmware that reports them.
+    #[serde(default)]
+    pub extended_flags: Option<FeatureFlags32>,
 }
 
 /// Feature flags indicating device capabilities.
@@ -102,25 +241,341 @@ pub enum FeatureFlag {
     Clipboard = 0b_1000_0000,
 }
 
+impl FeatureFlag {
+    /// All known feature flags, in bit order.
+    pub const ALL: [FeatureFlag; 5] = [
+        FeatureFlag::Led,
+        FeatureFlag::SmartLed,
+        FeatureFlag::Graphics,
+        FeatureFlag::Ota,
+        FeatureFlag::Clipboard,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeatureFlag::Led => "Led",
+            FeatureFlag::SmartLed => "SmartLed",
+            FeatureFlag::Graphics => "Graphics",
+            FeatureFlag::Ota => "Ota",
+            FeatureFlag::Clipboard => "Clipboard",
+        }
+    }
+}
+
+impl Display for FeatureFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl BitOr for FeatureFlag {
+    type Output = FeatureFlags;
+
+    fn bitor(self, rhs: Self) -> FeatureFlags {
+        FeatureFlags::from(self) | rhs
+    }
+}
+
+/// A set of feature flags as reported by the firmware.
+/// Bits unknown to this library are kept as-is so they survive a round trip.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Hash, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct FeatureFlags(u8);
+
+impl FeatureFlags {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create the set from the raw bits, unknown bits are retained.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Get the raw bits.
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Get the bits that don't correspond to any known [`FeatureFlag`].
+    pub fn unknown_bits(&self) -> u8 {
+        FeatureFlag::ALL
+            .iter()
+            .fold(self.0, |bits, flag| bits & !(*flag as u8))
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check if all flags in `flags` are set.
+    pub fn contains<F: Into<FeatureFlags>>(&self, flags: F) -> bool {
+        let flags = flags.into();
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Check if any flag in `flags` is set.
+    pub fn intersects<F: Into<FeatureFlags>>(&self, flags: F) -> bool {
+        self.0 & flags.into().0 != 0
+    }
+
+    pub fn insert<F: Into<FeatureFlags>>(&mut self, flags: F) {
+        self.0 |= flags.into().0;
+    }
+
+    pub fn remove<F: Into<FeatureFlags>>(&mut self, flags: F) {
+        self.0 &= !flags.into().0;
+    }
+
+    /// Iterate over the known flags that are set.
+    pub fn iter(&self) -> impl Iterator<Item = FeatureFlag> + '_ {
+        FeatureFlag::ALL
+            .into_iter()
+            .filter(|flag| self.contains(*flag))
+    }
+}
+
+impl From<FeatureFlag> for FeatureFlags {
+    fn from(flag: FeatureFlag) -> Self {
+        Self(flag as u8)
+    }
+}
+
+impl From<u8> for FeatureFlags {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<FeatureFlags> for u8 {
+    fn from(flags: FeatureFlags) -> Self {
+        flags.0
+    }
+}
+
+impl<F: Into<FeatureFlags>> BitOr<F> for FeatureFlags {
+    type Output = FeatureFlags;
+
+    fn bitor(self, rhs: F) -> FeatureFlags {
+        Self(self.0 | rhs.into().0)
+    }
+}
+
+impl FromIterator<FeatureFlag> for FeatureFlags {
+    fn from_iter<I: IntoIterator<Item = FeatureFlag>>(iter: I) -> Self {
+        iter.into_iter()
+            .fold(Self::empty(), |flags, flag| flags | flag)
+    }
+}
+
+/// Formats as `SmartLed | Ota`, unknown bits are appended in hex.
+impl Display for FeatureFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let mut names = self
+            .iter()
+            .map(|flag| flag.name().to_string())
+            .collect::<Vec<_>>();
+        if self.unknown_bits() != 0 {
+            names.push(format!("{:#04x}", self.unknown_bits()));
+        }
+        f.write_str(&names.join(" | "))
+    }
+}
+
+/// The 32-bit feature flags reported by newer firmware.
+///
+/// The low byte has the same layout as the legacy [`FeatureFlags`], the
+/// other bits are for capabilities that didn't fit in it.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Hash, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct FeatureFlags32(u32);
+
+impl FeatureFlags32 {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create the set from the raw bits, unknown bits are retained.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Get the raw bits.
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Get the low byte as the legacy 8-bit flags.
+    pub const fn legacy(&self) -> FeatureFlags {
+        FeatureFlags::from_bits(self.0 as u8)
+    }
+
+    /// Get the bits that don't correspond to any known [`FeatureFlag`].
+    pub fn unknown_bits(&self) -> u32 {
+        FeatureFlag::ALL
+            .iter()
+            .fold(self.0, |bits, flag| bits & !(*flag as u32))
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check if all flags in `flags` are set.
+    pub fn contains<F: Into<FeatureFlags32>>(&self, flags: F) -> bool {
+        let flags = flags.into();
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Check if any flag in `flags` is set.
+    pub fn intersects<F: Into<FeatureFlags32>>(&self, flags: F) -> bool {
+        self.0 & flags.into().0 != 0
+    }
+
+    /// Iterate over the known flags that are set.
+    pub fn iter(&self) -> impl Iterator<Item = FeatureFlag> + '_ {
+        FeatureFlag::ALL
+            .into_iter()
+            .filter(|flag| self.contains(*flag))
+    }
+}
+
+impl From<FeatureFlag> for FeatureFlags32 {
+    fn from(flag: FeatureFlag) -> Self {
+        Self(flag as u32)
+    }
+}
+
+impl From<FeatureFlags> for FeatureFlags32 {
+    fn from(flags: FeatureFlags) -> Self {
+        Self(flags.bits() as u32)
+    }
+}
+
+impl From<u32> for FeatureFlags32 {
+    fn from(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<FeatureFlags32> for u32 {
+    fn from(flags: FeatureFlags32) -> Self {
+        flags.0
+    }
+}
+
+/// Formats as `SmartLed | Ota`, unknown bits are appended in hex.
+impl Display for FeatureFlags32 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let mut names = self
+            .iter()
+            .map(|flag| flag.name().to_string())
+            .collect::<Vec<_>>();
+        if self.unknown_bits() != 0 {
+            names.push(format!("{:#010x}", self.unknown_bits()));
+        }
+        f.write_str(&names.join(" | "))
+    }
+}
+
 impl EsparrierState {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        EsparrierState {
+    /// The shortest GetState response understood, sent by firmware that
+    /// predates the `keep_awake` and `model_id` fields.
+    pub const MIN_RESPONSE_LEN: usize = 12;
+
+    /// The length of the GetState response with all the fixed fields,
+    /// anything after it goes to `extra`.
+    pub const FIXED_RESPONSE_LEN: usize = 14;
+
+    /// Parse the GetState response, `bytes` includes the leading `'s'`.
+    ///
+    /// Trailing fields missing from older firmware take default values,
+    /// `keep_awake` is false and `model_id` is 0 (unknown).
+    /// Bytes appended by newer firmware are kept in `extra`.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.first() != Some(&b's') {
+            return Err(Error::InvalidResponse);
+        }
+        if bytes.len() < Self::MIN_RESPONSE_LEN {
+            return Err(Error::FormatError(format!(
+                "GetState response too short: got {} bytes, expected at least {}",
+                bytes.len(),
+                Self::MIN_RESPONSE_LEN
+            )));
+        }
+        Ok(EsparrierState {
             version_major: bytes[1],
             version_minor: bytes[2],
             version_patch: bytes[3],
-            feature_flags: bytes[4],
+            feature_flags: FeatureFlags::from_bits(bytes[4]),
             ip_address: Ipv4Addr::new(bytes[5], bytes[6], bytes[7], bytes[8]),
             ip_prefix: bytes[9],
             server_connected: bytes[10] != 0,
             active: bytes[11] != 0,
-            keep_awake: bytes[12] != 0,
-            model_id: bytes[13],
-        }
+            keep_awake: bytes.get(12).is_some_and(|b| *b != 0),
+            model_id: bytes.get(13).copied().unwrap_or(0),
+            extra: bytes
+                .get(Self::FIXED_RESPONSE_LEN..)
+                .unwrap_or_default()
+                .to_vec(),
+            extended_flags: None,
+        })
+    }
+
+    /// Get the WiFi station MAC address, reported by firmware 0.7.2 and
+    /// later in the 6 bytes following `model_id`.
+    pub fn mac_address(&self) -> Option<[u8; 6]> {
+        self.extra.get(0..6)?.try_into().ok()
+    }
+
+    /// Get the time since the device booted, if reported by the firmware.
+    pub fn uptime_seconds(&self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.extra.get(6..10)?.try_into().ok()?))
+    }
+
+    /// Get the MAC address formatted as `aa:bb:cc:dd:ee:ff`.
+    pub fn mac_string(&self) -> Option<String> {
+        self.mac_address().map(|mac| {
+            mac.iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+    }
+
+    /// Same as [`mac_string`](Self::mac_string).
+    #[deprecated(note = "use `mac_string` instead")]
+    pub fn mac_address_string(&self) -> Option<String> {
+        self.mac_string()
+    }
+
+    /// Get the 32-bit feature flags, `None` unless they were read with
+    /// [`Esparrier::get_extended_features`].
+    pub fn extended_features(&self) -> Option<FeatureFlags32> {
+        self.extended_flags
+    }
+
+    /// Get the effective feature flags, the extended ones when known,
+    /// otherwise the legacy byte.
+    pub fn feature_set(&self) -> FeatureFlags32 {
+        self.extended_flags
+            .unwrap_or_else(|| self.feature_flags.into())
     }
 
     /// Check if a specific feature flag is set.
     pub fn has_feature(&self, flag: FeatureFlag) -> bool {
-        self.feature_flags & (flag as u8) != 0
+        self.feature_set().contains(flag)
+    }
+
+    /// Get the list of features supported by the firmware.
+    pub fn features(&self) -> Vec<FeatureFlag> {
+        self.feature_set().iter().collect()
     }
 
     /// Check if OTA updates are supported by the firmware.
@@ -141,66 +596,228 @@ impl EsparrierState {
         )
     }
 
+    /// Get the model of this device.
+    pub fn model(&self) -> ModelId {
+        self.model_id.into()
+    }
+
     /// Get the model name for this device based on model_id.
     /// Returns None if the model_id is unknown.
     pub fn model_name(&self) -> Option<&'static str> {
-        model_id_to_name(self.model_id)
+        self.model().asset_prefix()
+    }
+}
+
+/// Serialized with the derived `version` string and decoded `features` list,
+/// so consumers of the JSON don't need to decode the bitmask themselves.
+impl Serialize for EsparrierState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mac_address = self.mac_string();
+        let uptime_seconds = self.uptime_seconds();
+        // The fields always there, and the optional ones that are set
+        let len = 12
+            + [
+                self.extended_flags.is_some(),
+                mac_address.is_some(),
+                uptime_seconds.is_some(),
+                !self.extra.is_empty(),
+            ]
+            .into_iter()
+            .filter(|&set| set)
+            .count();
+        let mut state = serializer.serialize_struct("EsparrierState", len)?;
+        state.serialize_field("version_major", &self.version_major)?;
+        state.serialize_field("version_minor", &self.version_minor)?;
+        state.serialize_field("version_patch", &self.version_patch)?;
+        state.serialize_field("version", &self.version_string())?;
+        state.serialize_field("feature_flags", &self.feature_flags)?;
+        if let Some(flags) = self.extended_flags {
+            state.serialize_field("extended_flags", &flags)?;
+        }
+        state.serialize_field("features", &self.features())?;
+        state.serialize_field("ip_address", &self.ip_address)?;
+        state.serialize_field("ip_prefix", &self.ip_prefix)?;
+        state.serialize_field("server_connected", &self.server_connected)?;
+        state.serialize_field("active", &self.active)?;
+        state.serialize_field("keep_awake", &self.keep_awake)?;
+        state.serialize_field("model_id", &self.model_id)?;
+        if let Some(mac) = mac_address {
+            state.serialize_field("mac_address", &mac)?;
+        }
+        if let Some(uptime) = uptime_seconds {
+            state.serialize_field("uptime_seconds", &uptime)?;
+        }
+        if !self.extra.is_empty() {
+            state.serialize_field("extra", &self.extra)?;
+        }
+        state.end()
+    }
+}
+
+impl Display for EsparrierState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn yes_no(b: bool) -> &'static str {
+            if b {
+                "yes"
+            } else {
+                "no"
+            }
+        }
+
+        writeln!(f, "Firmware version: {}", self.version_string())?;
+        writeln!(
+            f,
+            "Model: {} (model_id={})",
+            self.model_name().unwrap_or("unknown"),
+            self.model_id
+        )?;
+        writeln!(f, "IP address: {}/{}", self.ip_address, self.ip_prefix)?;
+        writeln!(f, "Server connected: {}", yes_no(self.server_connected))?;
+        writeln!(f, "Active: {}", yes_no(self.active))?;
+        writeln!(f, "Keep awake: {}", yes_no(self.keep_awake))?;
+        if let Some(mac) = self.mac_string() {
+            writeln!(f, "MAC address: {mac}")?;
+        }
+        if let Some(uptime) = self.uptime_seconds() {
+            writeln!(f, "Uptime: {uptime}s")?;
+        }
+        let features = self
+            .features()
+            .iter()
+            .map(|flag| flag.name())
+            .collect::<Vec<_>>();
+        if features.is_empty() {
+            write!(f, "Features: none")
+        } else {
+            write!(f, "Features: {}", features.join(", "))
+        }
+    }
+}
+
+impl Error {
+    /// Whether running the operation again may succeed.
+    ///
+    /// These are [`Timeout`](Error::Timeout), [`DeviceBusy`](Error::DeviceBusy) and
+    /// transient [`TransferFailed`](Error::TransferFailed) errors. A
+    /// [`Disconnected`](Error::Disconnected) device needs to be opened again once
+    /// it is back, and an [`InvalidResponse`](Error::InvalidResponse) or
+    /// [`ProtocolDesync`](Error::ProtocolDesync) points to a protocol bug that
+    /// a retry won't fix.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Timeout | Error::DeviceBusy => true,
+            Error::TransferFailed(e) => is_recoverable(*e),
+            _ => false,
+        }
+    }
+}
+
+impl From<nusb::Error> for Error {
+    fn from(e: nusb::Error) -> Self {
+        if e.kind() == nusb::ErrorKind::Disconnected {
+            Error::Disconnected { command: None }
+        } else {
+            Error::Usb(e)
+        }
+    }
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        if e == TransferError::Disconnected {
+            Error::Disconnected { command: None }
+        } else {
+            Error::TransferFailed(e)
+        }
     }
 }
 
+/// Describe the command in flight for error messages.
+fn command_context(command: Option<u8>) -> String {
+    match command {
+        Some(command) => format!(" during '{}' command", command as char),
+        None => String::new(),
+    }
+}
+
+/// A random number from the std hasher seed, good enough for ping payloads.
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
+}
+
 /// Map model_id to firmware asset name prefix.
 /// These correspond to the asset names in GitHub releases.
+#[deprecated(note = "use `ModelId::asset_prefix` instead")]
 pub fn model_id_to_name(model_id: u8) -> Option<&'static str> {
-    match model_id {
-        1 => Some("m5atoms3-lite"),
-        2 => Some("m5atoms3"),
-        3 => Some("m5atoms3r"),
-        4 => Some("devkitc-1_0"),
-        5 => Some("devkitc-1_1"),
-        6 => Some("xiao-esp32s3"),
-        7 => Some("esp32-s3-eth"),
-        255 => Some("generic"),
-        _ => None,
-    }
+    ModelId::from(model_id).asset_prefix()
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(default)]
 pub struct EsparrierConfig {
     // These fields must be set
+    #[cfg_attr(feature = "schema", schemars(required, length(min = 1, max = 32)))]
     pub ssid: String,
     #[serde(skip_serializing_if = "String::is_empty")]
+    #[cfg_attr(feature = "schema", schemars(pattern(r"^(|.{8,63}|[0-9a-fA-F]{64})$")))]
     pub password: String,
+    #[cfg_attr(feature = "schema", schemars(required, length(min = 1, max = 64)))]
     pub server: String,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(required, length(min = 1, max = 64), pattern(SCREEN_NAME_PATTERN))
+    )]
     pub screen_name: String,
 
     // Screen configuration
     #[serde(default = "get_default_screen_width")]
+    #[cfg_attr(feature = "schema", schemars(range(min = 1, max = 32767)))]
     pub screen_width: u16,
     #[serde(default = "get_default_screen_height")]
+    #[cfg_attr(feature = "schema", schemars(range(min = 1, max = 32767)))]
     pub screen_height: u16,
     #[serde(default)]
     pub flip_wheel: bool,
+    /// HID report rate in Hz.
     #[serde(
         skip_serializing_if = "is_default_polling_rate",
         default = "get_default_polling_rate"
     )]
+    #[cfg_attr(feature = "schema", schemars(range(min = 10, max = 1000)))]
     pub polling_rate: u16,
+    /// Seconds between mouse jiggles while keep awake is on, 0 disables it.
     #[serde(
         skip_serializing_if = "is_default_jiggle_interval",
         default = "get_default_jiggle_interval"
     )]
+    #[cfg_attr(feature = "schema", schemars(range(max = 3600)))]
     pub jiggle_interval: u16,
 
     // LED configuration
     #[serde(default = "get_default_brightness")]
+    #[cfg_attr(feature = "schema", schemars(range(min = 1, max = 100)))]
     pub brightness: u8,
 
     #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[cfg_attr(feature = "schema", schemars(pattern(IP_CIDR_PATTERN)))]
     pub ip_addr: Option<String>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    #[cfg_attr(feature = "schema", schemars(length(max = 3), inner(ipv4)))]
     pub dns_server: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[cfg_attr(feature = "schema", schemars(ipv4))]
     pub gateway: Option<String>,
 
     // USB HID configuration
@@ -212,117 +829,766 @@ pub struct EsparrierConfig {
         default = "get_default_manufacturer",
         skip_serializing_if = "is_default_manufacturer"
     )]
+    #[cfg_attr(feature = "schema", schemars(length(min = 1, max = 64)))]
     pub manufacturer: String,
     #[serde(
         default = "get_default_product",
         skip_serializing_if = "is_default_product"
     )]
+    #[cfg_attr(feature = "schema", schemars(length(min = 1, max = 64)))]
     pub product: String,
     #[serde(
         default = "get_default_serial_number",
         skip_serializing_if = "is_default_serial_number"
     )]
+    #[cfg_attr(feature = "schema", schemars(length(min = 1, max = 64)))]
     pub serial_number: String,
     #[serde(
         default = "get_default_landing_url",
         skip_serializing_if = "is_default_landing_url"
     )]
+    #[cfg_attr(feature = "schema", schemars(length(max = 255)))]
     pub landing_url: String,
 
     // Misc internal fields
+    /// Seconds.
     #[serde(
         default = "get_default_watchdog_timeout",
         skip_serializing_if = "is_default_watchdog_timeout"
     )]
+    #[cfg_attr(feature = "schema", schemars(range(min = 5, max = 300)))]
     pub watchdog_timeout: u32,
+
+    /// Fields unknown to this library, e.g. added by newer firmware.
+    /// They are kept so a read-modify-write cycle doesn't wipe them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-impl EsparrierConfig {
-    pub fn validate(&self) -> Result<(), Error> {
-        fn validate_string(s: &str, name: &str, max_len: usize) -> Result<(), Error> {
-            if s.is_empty() {
-                Err(ConfigError::FieldEmpty(name.to_string()).into())
-            } else if s.len() > max_len {
-                Err(ConfigError::FieldTooLong(name.to_string()).into())
-            } else {
-                Ok(())
-            }
+/// The firmware defaults, only the fields that must be set are left empty:
+/// `ssid`, `password`, `server` and `screen_name`.
+impl Default for EsparrierConfig {
+    fn default() -> Self {
+        Self {
+            ssid: String::new(),
+            password: String::new(),
+            server: String::new(),
+            screen_name: String::new(),
+            screen_width: SCREEN_WIDTH,
+            screen_height: SCREEN_HEIGHT,
+            flip_wheel: REVERSED_WHEEL,
+            polling_rate: POLLING_RATE,
+            jiggle_interval: JIGGLE_INTERVAL,
+            brightness: BRIGHTNESS,
+            ip_addr: None,
+            dns_server: Vec::new(),
+            gateway: None,
+            vid: USB_VID,
+            pid: USB_PID,
+            manufacturer: USB_MANUFACTURER.to_string(),
+            product: USB_PRODUCT.to_string(),
+            serial_number: USB_SERIAL_NUMBER.to_string(),
+            landing_url: LANDING_URL.to_string(),
+            watchdog_timeout: WATCHDOG_TIMEOUT,
+            extra: serde_json::Map::new(),
         }
+    }
+}
 
-        macro_rules! validate_string {
-            ($s:ident, $max_len:literal) => {
-                validate_string(&self.$s, stringify!($s), $max_len)?;
-            };
-            () => {};
-        }
+/// A single field that differs between two configurations, see [`EsparrierConfig::diff`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConfigFieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
 
-        macro_rules! validate_num {
-            ($s:ident, $min:literal, $max:literal) => {
-                if self.$s < $min || self.$s > $max {
-                    return Err(ConfigError::FieldOutOfRange(
-                        stringify!($s).to_string(),
-                        $min,
-                        $max,
-                    )
-                    .into());
-                }
-            };
-            () => {};
-        }
+impl Display for ConfigFieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
 
-        validate_string!(ssid, 32);
-        validate_string!(password, 64);
-        validate_string!(server, 64);
-        if self.server.parse::<SocketAddrV4>().is_err() {
-            return Err(ConfigError::InvalidEndpoint("server".to_string()).into());
-        }
-        validate_string!(screen_name, 64);
-        validate_num!(screen_width, 1, 32767);
-        validate_num!(screen_height, 1, 32767);
-        validate_num!(brightness, 1, 100);
+fn describe_config_errors(errors: &[ConfigError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
 
-        if let Some(ip) = &self.ip_addr {
-            let (ip, prefix) =
-                ip.split_once('/')
-                    .ok_or(Into::<Error>::into(ConfigError::InvalidIpAddress(
-                        "ip_addr".to_string(),
-                    )))?;
-            let _ip = Ipv4Addr::from_str(ip).map_err(|_| {
-                Into::<Error>::into(ConfigError::InvalidIpAddress("ip_addr".to_string()))
-            })?;
-            if prefix.parse::<u8>().is_err() {
-                return Err(ConfigError::InvalidIpCidrPrefix("ip_addr".to_string()).into());
-            }
-        }
-        for d in &self.dns_server {
-            let _ip = Ipv4Addr::from_str(d).map_err(|_| {
-                Into::<Error>::into(ConfigError::InvalidIpAddress("dns_server".to_string()))
-            })?;
-        }
-        if let Some(gateway) = &self.gateway {
-            let _ip = Ipv4Addr::from_str(gateway).map_err(|_| {
-                Into::<Error>::into(ConfigError::InvalidIpAddress("gateway".to_string()))
-            })?;
-        }
-        validate_string!(manufacturer, 64);
-        validate_string!(product, 64);
-        validate_string!(serial_number, 64);
-        // The landing URL can be empty
-        if self.landing_url.len() > 255 {
-            return Err(ConfigError::FieldTooLong("landing_url".to_string()).into());
+fn describe_unknown_fields(fields: &[(String, Option<&'static str>)]) -> String {
+    fields
+        .iter()
+        .map(|(field, suggestion)| match suggestion {
+            Some(suggestion) => format!("'{field}' (did you mean '{suggestion}'?)"),
+            None => format!("'{field}'"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The known field `key` is probably a typo of, if any is close.
+fn suggest_field(known: &[(&'static str, serde_json::Value)], key: &str) -> Option<&'static str> {
+    known
+        .iter()
+        .map(|(name, _)| (*name, edit_distance(key, name)))
+        .filter(|(_, distance)| *distance <= 2.max(key.len() / 3))
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
         }
-        Ok(())
     }
+    row[b.len()]
 }
 
-pub const SCREEN_WIDTH: u16 = 1920;
-pub const SCREEN_HEIGHT: u16 = 1080;
-pub const REVERSED_WHEEL: bool = false;
-pub const BRIGHTNESS: u8 = 30;
-pub const POLLING_RATE: u16 = 200;
-pub const JIGGLE_INTERVAL: u16 = 60;
-pub const USB_VID: u16 = 0x0d0a;
-pub const USB_PID: u16 = 0xc0de;
+/// The placeholder shown instead of secrets.
+pub const REDACTED: &str = "********";
+
+/// Render a field value for humans, strings are not quoted.
+fn display_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "(unset)".to_string(),
+        serde_json::Value::String(s) if s.is_empty() => "(empty)".to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(display_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        v => v.to_string(),
+    }
+}
+
+/// IPv4 address with a CIDR prefix of 1..32, as accepted in `ip_addr`.
+pub const IP_CIDR_PATTERN: &str = r"^((25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])/([1-9]|[12][0-9]|3[0-2])$";
+
+/// A screen name as accepted by [`validate_screen_name`], the length is checked separately.
+pub const SCREEN_NAME_PATTERN: &str = r"^[A-Za-z0-9_]([A-Za-z0-9._-]*[A-Za-z0-9_])?$";
+
+/// How many entries of `dns_server` the firmware keeps.
+pub const MAX_DNS_SERVERS: usize = 3;
+
+/// The first firmware version able to resolve a host name in the `server` field,
+/// older versions only accept an IPv4 address.
+pub const HOSTNAME_SERVER_MIN_VERSION: (u8, u8, u8) = (0, 9, 0);
+
+/// Check the screen name against what Barrier servers accept, ASCII letters,
+/// digits, `.`, `_` and `-`, with no `.` or `-` at either end, and up to 64 characters.
+pub fn validate_screen_name(name: &str) -> Result<(), ConfigError> {
+    if name.is_empty() {
+        return Err(ConfigError::FieldEmpty("screen_name".to_string()));
+    }
+    let last = name.chars().count() - 1;
+    for (position, character) in name.chars().enumerate() {
+        let valid = match character {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => true,
+            '.' | '-' => position != 0 && position != last,
+            _ => false,
+        };
+        if !valid {
+            return Err(ConfigError::InvalidScreenName {
+                character,
+                position,
+            });
+        }
+    }
+    if name.len() > 64 {
+        return Err(ConfigError::FieldTooLong("screen_name".to_string()));
+    }
+    Ok(())
+}
+
+/// Check a WiFi password, empty for an open network, otherwise a WPA passphrase
+/// of 8 to 63 characters or a raw 64-digit hex PSK.
+pub fn validate_wifi_password(password: &str) -> Result<(), ConfigError> {
+    match password.len() {
+        0 | 8..=63 => Ok(()),
+        1..=7 => Err(ConfigError::PasswordTooShort),
+        64 if password.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+        64 => Err(ConfigError::InvalidPsk),
+        _ => Err(ConfigError::FieldTooLong("password".to_string())),
+    }
+}
+
+/// Check the `server` address, `host:port` with an IPv4 address or a host
+/// name, firmware older than [`HOSTNAME_SERVER_MIN_VERSION`] only accepts an
+/// IPv4 address.
+pub fn validate_server_address(server: &str, version: (u8, u8, u8)) -> Result<(), ConfigError> {
+    validate_server(server, version >= HOSTNAME_SERVER_MIN_VERSION)
+}
+
+fn validate_server(server: &str, allow_hostname: bool) -> Result<(), ConfigError> {
+    if server.is_empty() {
+        Err(ConfigError::FieldEmpty("server".to_string()))
+    } else if server.len() > 64 {
+        Err(ConfigError::FieldTooLong("server".to_string()))
+    } else if !is_valid_endpoint(server, allow_hostname) {
+        Err(ConfigError::InvalidEndpoint("server".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Turn a screen name into one [`validate_screen_name`] accepts, e.g.
+/// `my screen (left)` into `my-screen-left`, to suggest to the user.
+pub fn sanitize_screen_name(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+    let mut sanitized = sanitized
+        .trim_matches(['.', '-'])
+        .chars()
+        .take(64)
+        .collect::<String>();
+    while sanitized.ends_with(['.', '-']) {
+        sanitized.pop();
+    }
+    if sanitized.is_empty() {
+        sanitized.push_str("screen");
+    }
+    sanitized
+}
+
+/// Check `host:port`, the host is an IPv4 address or, if allowed, an RFC 1123 host name.
+fn is_valid_endpoint(endpoint: &str, allow_hostname: bool) -> bool {
+    let Some((host, port)) = endpoint.rsplit_once(':') else {
+        return false;
+    };
+    if !port.parse::<u16>().is_ok_and(|p| p != 0) {
+        return false;
+    }
+    host.parse::<Ipv4Addr>().is_ok() || (allow_hostname && is_valid_hostname(host))
+}
+
+fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() || host.len() > 253 {
+        return false;
+    }
+    let labels_ok = host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    // An all-numeric top label is a malformed IPv4 address, not a host name
+    labels_ok
+        && !host
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| tld.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Never prints secrets, see [`EsparrierConfig::redacted`].
+impl std::fmt::Debug for EsparrierConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let config = self.redacted();
+        f.debug_struct("EsparrierConfig")
+            .field("ssid", &config.ssid)
+            .field("password", &config.password)
+            .field("server", &config.server)
+            .field("screen_name", &config.screen_name)
+            .field("screen_width", &config.screen_width)
+            .field("screen_height", &config.screen_height)
+            .field("flip_wheel", &config.flip_wheel)
+            .field("polling_rate", &config.polling_rate)
+            .field("jiggle_interval", &config.jiggle_interval)
+            .field("brightness", &config.brightness)
+            .field("ip_addr", &config.ip_addr)
+            .field("dns_server", &config.dns_server)
+            .field("gateway", &config.gateway)
+            .field("vid", &config.vid)
+            .field("pid", &config.pid)
+            .field("manufacturer", &config.manufacturer)
+            .field("product", &config.product)
+            .field("serial_number", &config.serial_number)
+            .field("landing_url", &config.landing_url)
+            .field("watchdog_timeout", &config.watchdog_timeout)
+            .field("extra", &config.extra)
+            .finish()
+    }
+}
+
+impl EsparrierConfig {
+    /// Get a copy safe for display and logging, secrets that are set are
+    /// replaced with [`REDACTED`], empty ones stay empty.
+    pub fn redacted(&self) -> EsparrierConfig {
+        let mut config = self.clone();
+        if !config.password.is_empty() {
+            config.password = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// All fields with their values, including the ones equal to the default,
+    /// followed by the unknown ones from `extra`.
+    fn fields(&self) -> Vec<(String, serde_json::Value)> {
+        self.known_fields()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .chain(self.extra.iter().map(|(k, v)| (k.clone(), v.clone())))
+            .collect()
+    }
+
+    /// All fields as a JSON object, including the ones serialization skips when
+    /// they have the default value, e.g. `polling_rate` and `vid`. Unset
+    /// optional fields are `null`.
+    pub fn to_json_full(&self) -> serde_json::Value {
+        serde_json::Value::Object(self.fields().into_iter().collect())
+    }
+
+    /// The names of the known fields, in the order they're serialized, e.g.
+    /// for completing them on a command line.
+    pub fn field_names() -> Vec<&'static str> {
+        EsparrierConfig::default()
+            .known_fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// The names of the known fields that have the default value.
+    pub fn default_fields(&self) -> Vec<&'static str> {
+        self.known_fields()
+            .into_iter()
+            .zip(EsparrierConfig::default().known_fields())
+            .filter(|((_, value), (_, default))| value == default)
+            .map(|((name, _), _)| name)
+            .collect()
+    }
+
+    fn known_fields(&self) -> Vec<(&'static str, serde_json::Value)> {
+        use serde_json::json;
+        vec![
+            ("ssid", json!(self.ssid)),
+            ("password", json!(self.password)),
+            ("server", json!(self.server)),
+            ("screen_name", json!(self.screen_name)),
+            ("screen_width", json!(self.screen_width)),
+            ("screen_height", json!(self.screen_height)),
+            ("flip_wheel", json!(self.flip_wheel)),
+            ("polling_rate", json!(self.polling_rate)),
+            ("jiggle_interval", json!(self.jiggle_interval)),
+            ("brightness", json!(self.brightness)),
+            ("ip_addr", json!(self.ip_addr)),
+            ("dns_server", json!(self.dns_server)),
+            ("gateway", json!(self.gateway)),
+            ("vid", json!(self.vid)),
+            ("pid", json!(self.pid)),
+            ("manufacturer", json!(self.manufacturer)),
+            ("product", json!(self.product)),
+            ("serial_number", json!(self.serial_number)),
+            ("landing_url", json!(self.landing_url)),
+            ("watchdog_timeout", json!(self.watchdog_timeout)),
+        ]
+    }
+
+    /// Parse a configuration from JSON like `serde_json::from_str`, but reject
+    /// unknown fields instead of keeping them in [`extra`](Self::extra), so typos
+    /// don't go unnoticed. The result is not validated.
+    pub fn from_json_strict(s: &str) -> Result<Self, Error> {
+        let config: EsparrierConfig =
+            serde_json::from_str(s).map_err(|e| Error::FormatError(e.to_string()))?;
+        config.ensure_no_unknown_fields()?;
+        Ok(config)
+    }
+
+    /// Fail with [`ConfigError::UnknownFields`] if [`extra`](Self::extra) is not
+    /// empty, suggesting the known field each unknown one is probably a typo of.
+    pub fn ensure_no_unknown_fields(&self) -> Result<(), Error> {
+        if self.extra.is_empty() {
+            return Ok(());
+        }
+        let known = self.known_fields();
+        let unknown = self
+            .extra
+            .keys()
+            .map(|key| (key.clone(), suggest_field(&known, key)))
+            .collect();
+        Err(ConfigError::UnknownFields(unknown).into())
+    }
+
+    /// List the fields changed from `self` to `other`, secrets are shown
+    /// [`redacted`](Self::redacted).
+    pub fn diff(&self, other: &Self) -> Vec<ConfigFieldChange> {
+        self.diff_impl(other, true)
+    }
+
+    /// Same as [`diff`](Self::diff) but shows the password in clear text.
+    pub fn diff_unredacted(&self, other: &Self) -> Vec<ConfigFieldChange> {
+        self.diff_impl(other, false)
+    }
+
+    fn diff_impl(&self, other: &Self, redact: bool) -> Vec<ConfigFieldChange> {
+        let old = self.fields();
+        let new = other.fields();
+        // Compared in clear text so a changed secret shows up, but shown redacted
+        let (shown_old, shown_new) = if redact {
+            (self.redacted().fields(), other.redacted().fields())
+        } else {
+            (old.clone(), new.clone())
+        };
+        let lookup = |fields: &[(String, serde_json::Value)], name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+        // Known fields first, then unknown ones from either side
+        let mut names: Vec<&String> = Vec::new();
+        for (name, _) in old.iter().chain(new.iter()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+            .into_iter()
+            .filter_map(|field| {
+                let old = lookup(&old, field);
+                let new = lookup(&new, field);
+                (old != new).then(|| ConfigFieldChange {
+                    field: field.clone(),
+                    old: display_value(&lookup(&shown_old, field)),
+                    new: display_value(&lookup(&shown_new, field)),
+                })
+            })
+            .collect()
+    }
+
+    /// Apply an RFC 7396 style merge patch, then validate the result.
+    ///
+    /// Keys present in `patch` overwrite the field, `null` resets the field to its
+    /// default, absent keys are left untouched. Unknown keys are rejected.
+    /// `self` is only modified if the patched configuration is valid.
+    pub fn merge_json(&mut self, patch: &serde_json::Value) -> Result<(), Error> {
+        self.merge_json_impl(patch, false)
+    }
+
+    /// Same as [`merge_json`](Self::merge_json) but unknown keys are kept in
+    /// [`extra`](Self::extra), e.g. for fields only known to newer firmware.
+    pub fn merge_json_lenient(&mut self, patch: &serde_json::Value) -> Result<(), Error> {
+        self.merge_json_impl(patch, true)
+    }
+
+    fn merge_json_impl(&mut self, patch: &serde_json::Value, lenient: bool) -> Result<(), Error> {
+        let patch = patch
+            .as_object()
+            .ok_or_else(|| Error::FormatError("Config patch must be a JSON object".to_string()))?;
+        // Unknown fields already present in `extra` can be patched too
+        let mut merged = self.fields().into_iter().collect::<serde_json::Map<_, _>>();
+        for (key, value) in patch {
+            if !lenient && !merged.contains_key(key) {
+                return Err(ConfigError::UnknownField(key.clone()).into());
+            }
+            if value.is_null() {
+                merged.remove(key);
+            } else {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+        let config: EsparrierConfig = serde_json::from_value(serde_json::Value::Object(merged))
+            .map_err(|e| Error::FormatError(e.to_string()))?;
+        config.validate()?;
+        *self = config;
+        Ok(())
+    }
+
+    /// Build a merge patch for [`merge_json`](Self::merge_json) from `field=value`
+    /// assignments, e.g. `brightness=50`.
+    ///
+    /// Values are parsed according to the type of the field: `true` or `false`,
+    /// integers, also in hex with `0x`, strings as is and `dns_server` as a
+    /// comma-separated list. An empty value unsets `ip_addr` and `gateway`.
+    /// Unknown fields are rejected with [`ConfigError::UnknownFields`].
+    pub fn patch_from_assignments<'a, I>(assignments: I) -> Result<serde_json::Value, Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        use serde_json::Value;
+
+        let known = EsparrierConfig::default().known_fields();
+        let mut patch = serde_json::Map::new();
+        for assignment in assignments {
+            let (field, value) = assignment.split_once('=').ok_or_else(|| {
+                Error::FormatError(format!("Expected field=value, got '{assignment}'"))
+            })?;
+            let field = field.trim();
+            let Some((_, default)) = known.iter().find(|(name, _)| *name == field) else {
+                let unknown = vec![(field.to_string(), suggest_field(&known, field))];
+                return Err(ConfigError::UnknownFields(unknown).into());
+            };
+            let invalid = |expected: &str| {
+                Error::FormatError(format!(
+                    "Invalid value '{value}' for {field}, expected {expected}"
+                ))
+            };
+            let value = match default {
+                Value::Bool(_) => Value::Bool(value.parse().map_err(|_| invalid("true or false"))?),
+                Value::Number(_) => {
+                    let number = match value.strip_prefix("0x") {
+                        Some(hex) => u64::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    };
+                    Value::from(number.map_err(|_| invalid("an integer"))?)
+                }
+                Value::Array(_) => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(Value::from)
+                    .collect(),
+                // `ip_addr` and `gateway`, unset by default
+                Value::Null if value.is_empty() => Value::Null,
+                _ => Value::from(value),
+            };
+            patch.insert(field.to_string(), value);
+        }
+        Ok(Value::Object(patch))
+    }
+
+    /// Parse a configuration from TOML, the result is not validated.
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Serialize the configuration to TOML.
+    #[cfg(feature = "toml")]
+    pub fn to_toml_string(&self) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Serialize all fields to TOML, see [`to_json_full`](Self::to_json_full).
+    /// Unset optional fields are left out, TOML has no `null`.
+    #[cfg(feature = "toml")]
+    pub fn to_toml_string_full(&self) -> Result<String, Error> {
+        let mut fields = self.to_json_full();
+        if let Some(fields) = fields.as_object_mut() {
+            fields.retain(|_, value| !value.is_null());
+        }
+        toml::to_string(&fields).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Parse a configuration from YAML, the result is not validated.
+    ///
+    /// Integer fields also accept YAML hex notation, e.g. `vid: 0x0d0a`.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(s: &str) -> Result<Self, Error> {
+        serde_yaml::from_str(s).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Serialize the configuration to YAML.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml_string(&self) -> Result<String, Error> {
+        serde_yaml::to_string(self).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// Serialize all fields to YAML, see [`to_json_full`](Self::to_json_full).
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml_string_full(&self) -> Result<String, Error> {
+        serde_yaml::to_string(&self.to_json_full()).map_err(|e| Error::FormatError(e.to_string()))
+    }
+
+    /// The JSON Schema of the configuration file, including the constraints
+    /// checked by [`EsparrierConfig::validate`] where they can be expressed.
+    #[cfg(feature = "schema")]
+    pub fn json_schema() -> serde_json::Value {
+        const REQUIRED: [&str; 3] = ["ssid", "server", "screen_name"];
+        let mut schema = schemars::schema_for!(EsparrierConfig).to_value();
+        schema["required"] = serde_json::json!(REQUIRED);
+        // schemars can't see the container level `serde(default)` from the fields,
+        // nor the defaults of the fields that are skipped when set to them
+        let defaults = serde_json::json!({
+            "polling_rate": POLLING_RATE,
+            "jiggle_interval": JIGGLE_INTERVAL,
+            "vid": USB_VID,
+            "pid": USB_PID,
+            "manufacturer": USB_MANUFACTURER,
+            "product": USB_PRODUCT,
+            "serial_number": USB_SERIAL_NUMBER,
+            "landing_url": LANDING_URL,
+            "watchdog_timeout": WATCHDOG_TIMEOUT,
+        });
+        if let Some(properties) = schema["properties"].as_object_mut() {
+            for field in REQUIRED {
+                if let Some(property) = properties.get_mut(field).and_then(|p| p.as_object_mut()) {
+                    property.remove("default");
+                }
+            }
+            for (field, default) in defaults.as_object().into_iter().flatten() {
+                if let Some(property) = properties.get_mut(field) {
+                    property["default"] = default.clone();
+                }
+            }
+        }
+        schema
+    }
+
+    /// Validate the configuration for the latest firmware.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_impl(true)
+    }
+
+    /// Validate the configuration for the firmware with the given version,
+    /// e.g. the one reported by [`EsparrierState::version`].
+    /// Firmware before [`HOSTNAME_SERVER_MIN_VERSION`] only accepts an IPv4 server address.
+    pub fn validate_for_version(&self, version: (u8, u8, u8)) -> Result<(), Error> {
+        self.validate_impl(version >= HOSTNAME_SERVER_MIN_VERSION)
+    }
+
+    /// Validate the configuration for the latest firmware like
+    /// [`validate`](Self::validate), but report all the problems instead of
+    /// only the first one, as [`ConfigError::Multiple`] if there are several.
+    pub fn validate_all(&self) -> Result<(), Error> {
+        let mut errors = self.errors(true);
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0).into()),
+            _ => Err(ConfigError::Multiple(errors).into()),
+        }
+    }
+
+    fn validate_impl(&self, allow_hostname: bool) -> Result<(), Error> {
+        match self.errors(allow_hostname).into_iter().next() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// All the problems of the configuration, in field order.
+    fn errors(&self, allow_hostname: bool) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+
+        fn validate_string(s: &str, name: &str, max_len: usize) -> Option<ConfigError> {
+            if s.is_empty() {
+                Some(ConfigError::FieldEmpty(name.to_string()))
+            } else if s.len() > max_len {
+                Some(ConfigError::FieldTooLong(name.to_string()))
+            } else {
+                None
+            }
+        }
+
+        macro_rules! validate_string {
+            ($s:ident, $max_len:literal) => {
+                errors.extend(validate_string(&self.$s, stringify!($s), $max_len));
+            };
+            () => {};
+        }
+
+        macro_rules! validate_num {
+            ($s:ident, $min:literal, $max:literal) => {
+                if !($min..=$max).contains(&self.$s) {
+                    errors.push(ConfigError::FieldOutOfRange(
+                        stringify!($s).to_string(),
+                        $min,
+                        $max,
+                    ));
+                }
+            };
+            () => {};
+        }
+
+        validate_string!(ssid, 32);
+        errors.extend(validate_wifi_password(&self.password).err());
+        errors.extend(validate_server(&self.server, allow_hostname).err());
+        errors.extend(validate_screen_name(&self.screen_name).err());
+        validate_num!(screen_width, 1, 32767);
+        validate_num!(screen_height, 1, 32767);
+        validate_num!(brightness, 1, 100);
+        validate_num!(polling_rate, 10, 1000);
+        validate_num!(jiggle_interval, 0, 3600);
+        validate_num!(watchdog_timeout, 5, 300);
+
+        if let Some(ip) = &self.ip_addr {
+            match ip.split_once('/') {
+                Some((ip, _)) if Ipv4Addr::from_str(ip).is_err() => {
+                    errors.push(ConfigError::InvalidIpAddress("ip_addr".to_string()));
+                }
+                Some((_, prefix)) => match prefix.parse::<u8>() {
+                    Ok(p @ 1..=32) => {
+                        if p < 8 {
+                            log::warn!("ip_addr has an unusually short CIDR prefix /{p}");
+                        }
+                    }
+                    _ => errors.push(ConfigError::InvalidIpCidrPrefix(
+                        "ip_addr".to_string(),
+                        prefix.to_string(),
+                    )),
+                },
+                None if Ipv4Addr::from_str(ip).is_ok() => {
+                    errors.push(ConfigError::MissingIpCidrPrefix("ip_addr".to_string()));
+                }
+                None => errors.push(ConfigError::InvalidIpAddress("ip_addr".to_string())),
+            }
+        }
+        if self.dns_server.len() > MAX_DNS_SERVERS {
+            errors.push(ConfigError::TooManyDnsServers(self.dns_server.len()));
+        }
+        for (i, d) in self.dns_server.iter().enumerate() {
+            let Ok(ip) = Ipv4Addr::from_str(d) else {
+                errors.push(ConfigError::InvalidIpAddress("dns_server".to_string()));
+                continue;
+            };
+            // Ipv4Addr only parses the canonical form, e.g. it rejects "1.1.1.01", so
+            // comparing the parsed addresses catches the same duplicates as the strings
+            if self.dns_server[..i]
+                .iter()
+                .any(|other| Ipv4Addr::from_str(other) == Ok(ip))
+            {
+                errors.push(ConfigError::DuplicateDnsServer(d.clone()));
+            }
+        }
+        if let Some(gateway) = &self.gateway {
+            if Ipv4Addr::from_str(gateway).is_err() {
+                errors.push(ConfigError::InvalidIpAddress("gateway".to_string()));
+            }
+        }
+        if self.ip_addr.is_some() && self.dns_server.is_empty() && self.gateway.is_none() {
+            errors.push(ConfigError::MissingResolver);
+        }
+        validate_string!(manufacturer, 64);
+        validate_string!(product, 64);
+        validate_string!(serial_number, 64);
+        // The landing URL can be empty
+        if self.landing_url.len() > 255 {
+            errors.push(ConfigError::FieldTooLong("landing_url".to_string()));
+        }
+        errors
+    }
+}
+
+pub const SCREEN_WIDTH: u16 = 1920;
+pub const SCREEN_HEIGHT: u16 = 1080;
+pub const REVERSED_WHEEL: bool = false;
+pub const BRIGHTNESS: u8 = 30;
+pub const POLLING_RATE: u16 = 200;
+pub const JIGGLE_INTERVAL: u16 = 60;
+pub const USB_VID: u16 = 0x0d0a;
+pub const USB_PID: u16 = 0xc0de;
 pub const USB_MANUFACTURER: &str = "0d0a.com";
 pub const LANDING_URL: &str = "https://0d0a.com";
 pub const USB_PRODUCT: &str = "Esparrier KVM";
@@ -414,610 +1680,5069 @@ fn is_default_watchdog_timeout(timeout: &u32) -> bool {
     *timeout == WATCHDOG_TIMEOUT
 }
 
-pub struct Esparrier {
-    pub device_info: DeviceInfo,
+/// Retry policy for transient USB transfer errors.
+///
+/// Only the failing packet is retried, never the whole command, so commands with
+/// side effects are not replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts per packet, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after each further failure up to
+    /// [`MAX_RETRY_BACKOFF`].
+    pub backoff: Duration,
+}
+
+/// Longest delay the backoff of [`RetryPolicy`] doubles to, a longer initial
+/// backoff is kept as is.
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+impl RetryPolicy {
+    /// Never retry, every transfer error is returned immediately.
+    pub const fn none() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    pub const fn new(attempts: u32, backoff: Duration) -> Self {
+        Self { attempts, backoff }
+    }
 
-    ep_in: Mutex<Endpoint<Bulk, In>>,
-    ep_out: Mutex<Endpoint<Bulk, Out>>,
+    /// The delay before the retry after the one that waited `delay`.
+    fn next_backoff(&self, delay: Duration) -> Duration {
+        delay
+            .saturating_mul(2)
+            .min(MAX_RETRY_BACKOFF)
+            .max(self.backoff)
+    }
 }
 
-/// Compare bus IDs, normalizing numeric values (e.g., "3" matches "03")
-fn bus_id_matches(device_bus_id: &str, filter_bus_id: &str) -> bool {
-    // First try exact match
-    if device_bus_id == filter_bus_id {
-        return true;
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
     }
-    // Try numeric comparison (handles "3" == "03" case)
-    if let (Ok(a), Ok(b)) = (
-        device_bus_id.parse::<u32>(),
-        filter_bus_id.parse::<u32>(),
-    ) {
-        return a == b;
+}
+
+/// Most data a block transfer carries, the block count is a single byte.
+const MAX_BLOCKS_SIZE: usize = 255 * 64;
+
+/// How long [`Esparrier::read_blocks`] waits for each announced block, even
+/// without a timeout set, the firmware sends them right after the count.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size of the configuration buffer in the firmware, the most the serialized
+/// JSON of [`Esparrier::set_config`] can take.
+pub const MAX_CONFIG_SIZE: usize = 4096;
+
+/// Size of the clipboard buffer in the firmware.
+pub const CLIPBOARD_MAX_SIZE: usize = 4096;
+
+/// Default for [`OtaOptions::chunk_timeout`].
+///
+/// Erasing a new 4 KiB flash sector takes tens of milliseconds on a healthy
+/// chip, this leaves plenty of room for slow ones while still catching a
+/// device that stopped responding.
+pub const OTA_CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options for [`Esparrier::upload_ota`] and [`Esparrier::upload_ota_stream`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OtaOptions {
+    /// Skip the pre-flight check that the firmware is an ESP32-S3
+    /// application image rather than e.g. a merged full-flash image.
+    pub force: bool,
+    /// How many times a data chunk is sent again after a transfer error or
+    /// a flash write failure reported by the device, 0 (the default) to fail
+    /// right away.
+    pub chunk_retries: u32,
+    /// How long to wait for the device to acknowledge a data chunk, which
+    /// includes writing it to flash. On timeout the OTA session is aborted.
+    /// Defaults to [`OTA_CHUNK_TIMEOUT`], `None` waits forever.
+    pub chunk_timeout: Option<Duration>,
+}
+
+impl Default for OtaOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            chunk_retries: 0,
+            chunk_timeout: Some(OTA_CHUNK_TIMEOUT),
+        }
     }
-    false
 }
 
-impl Esparrier {
-    pub async fn list_devices(vid: Option<u16>, pid: Option<u16>) -> Vec<(String, u8)> {
-        let devices = match nusb::list_devices().await {
-            Ok(d) => d,
-            Err(e) => {
-                debug!("Failed to list devices: {e}");
-                return Vec::new();
+/// Phase of an OTA upload, see [`OtaProgress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtaPhase {
+    /// The device is preparing the OTA partition.
+    Starting,
+    /// Firmware chunks are being sent.
+    Uploading,
+    /// All data has been sent, the device is writing the last chunk to flash.
+    Flashing,
+    /// The device has all data and is checking the image.
+    Verifying,
+    /// The device accepted the image and is rebooting into it.
+    Complete,
+}
+
+/// Progress report passed to the OTA progress callback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtaProgress {
+    pub phase: OtaPhase,
+    /// Bytes sent to the device so far.
+    pub sent: usize,
+    /// Bytes the device reported as received and written.
+    pub acknowledged: usize,
+    /// Size of the whole image.
+    pub total: usize,
+    /// Time since the upload started.
+    pub elapsed: Duration,
+    /// Throughput of the last chunk in bytes per second.
+    pub throughput: f64,
+    /// Estimated time until all data is sent, based on the average throughput.
+    pub eta: Option<Duration>,
+}
+
+impl OtaProgress {
+    fn new(total: usize) -> Self {
+        Self {
+            phase: OtaPhase::Starting,
+            sent: 0,
+            acknowledged: 0,
+            total,
+            elapsed: Duration::ZERO,
+            throughput: 0.0,
+            eta: None,
+        }
+    }
+
+    /// Record the device-acknowledged byte count from a chunk response.
+    fn record_ack(&mut self, result: &[u8]) {
+        match result[0] {
+            // Progress response: 'P' + received(4B LE) + total(4B LE)
+            b'P' if result.len() >= 9 => {
+                let received = u32::from_le_bytes([result[1], result[2], result[3], result[4]]);
+                let total = u32::from_le_bytes([result[5], result[6], result[7], result[8]]);
+                debug!("OTA progress: {}/{} bytes", received, total);
+                self.acknowledged = received as usize;
             }
-        };
-        let mut ret = Vec::new();
-        for di in devices {
-            if di.vendor_id() == vid.unwrap_or(USB_VID) && di.product_id() == pid.unwrap_or(USB_PID)
-            {
-                ret.push((di.bus_id().to_string(), di.device_address()));
+            // Ok response (alternative to Progress)
+            b'o' => {
+                debug!("OTA chunk acknowledged");
+                self.acknowledged = self.sent;
             }
+            _ => {}
         }
-        ret
     }
 
-    /**
-     * Auto detect the device with the specified VID, PID, bus ID, and device address.
-     * If `wait` is true, the method will wait for the device to be connected.
-     */
-    pub async fn auto_detect<A, B, C, D>(
-        wait: bool,
-        vid: A,
-        pid: B,
-        bus: C,
-        address: D,
-    ) -> Option<Self>
+    /// Percentage of the image sent so far.
+    pub fn percent(&self) -> usize {
+        self.sent * 100 / self.total.max(1)
+    }
+
+    /// Adapt a `(sent_bytes, total_bytes)` callback, as taken by earlier
+    /// versions of [`Esparrier::upload_ota`], to the new progress reports.
+    pub fn sent_total<F>(mut callback: F) -> impl FnMut(&OtaProgress)
     where
-        A: Into<Option<u16>> + Clone,
-        B: Into<Option<u16>> + Clone,
-        C: Into<Option<String>> + Clone,
-        D: Into<Option<u8>> + Clone,
+        F: FnMut(usize, usize),
     {
-        if wait {
-            return Self::wait_for_device(vid, pid, bus, address).await.ok();
+        move |progress| {
+            if progress.phase == OtaPhase::Uploading {
+                callback(progress.sent, progress.total)
+            }
         }
-        let devices = match nusb::list_devices().await {
-            Ok(d) => d,
-            Err(_) => return None,
-        };
-        for di in devices {
-            if vid.clone().into().is_none_or(|v| di.vendor_id() == v)
-                && pid.clone().into().is_none_or(|p| di.product_id() == p)
-                && bus
-                    .clone()
-                    .into()
-                    .is_none_or(|b| bus_id_matches(di.bus_id(), &b))
-                && address
-                    .clone()
-                    .into()
-                    .is_none_or(|a| di.device_address() == a)
-            {
-                if let Ok(dev) = Self::try_open_device(di).await {
-                    return Some(dev);
-                }
-            }
-        }
-        None
     }
+}
 
-    /// Get the current state from the device.
-    pub async fn get_state(&self) -> Result<EsparrierState, Error> {
-        // Send the 's'(GetState) command to the device
-        self.write(b"s").await?;
-        let result = self.read().await?;
-        if result.len() < 13 || result[0] != b's' {
-            return Err(Error::InvalidResponse);
+/// Event emitted by [`Esparrier::upload_ota_with_events`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtaEvent {
+    /// The upload is starting with an image of this size and checksum.
+    Started { size: usize, crc: u32 },
+    /// A data chunk has been sent to the device.
+    ChunkSent(OtaProgress),
+    /// The device reported how much of the image it has received.
+    DeviceProgress { received: usize, total: usize },
+    /// The device accepted the image and is rebooting into it.
+    Completed,
+    /// The upload failed, the error itself is returned by the upload future.
+    Error(String),
+}
+
+/// Transfer errors that may go away if the packet is simply sent again.
+fn is_recoverable(e: TransferError) -> bool {
+    matches!(
+        e,
+        TransferError::Stall | TransferError::Fault | TransferError::Unknown(_)
+    )
+}
+
+/// A handle to an Esparrier device.
+///
+/// The handle can be shared between tasks, commands issued concurrently are
+/// sent one after another and never see each other's responses.
+pub struct Esparrier<T = NusbTransport> {
+    transport: T,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    ota_options: OtaOptions,
+    /// Set when an upload was abandoned without aborting the OTA session.
+    ota_abort_pending: AtomicBool,
+    /// The command byte of the last command sent, for error reports.
+    last_command: AtomicU8,
+    /// Held for a whole request/response exchange, so the packets of commands
+    /// issued concurrently on the same handle don't interleave.
+    exchange: async_lock::Mutex<()>,
+    /// The last state read from the device, used to check command support.
+    state: Mutex<Option<EsparrierState>>,
+    /// The result of the last [`get_extended_features`](Esparrier::get_extended_features),
+    /// added to every state read after it.
+    extended_flags: Mutex<Option<FeatureFlags32>>,
+    /// Parent of the command spans, tagged with the device.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    stats: stats::TransferCounters,
+    command_hook: Option<stats::CommandHook>,
+}
+
+/// Flags the OTA session for a deferred abort if the upload is abandoned,
+/// e.g. because its future was dropped.
+struct OtaGuard<'a> {
+    abort_pending: &'a AtomicBool,
+    armed: bool,
+}
+
+impl<'a> OtaGuard<'a> {
+    fn new(abort_pending: &'a AtomicBool) -> Self {
+        Self {
+            abort_pending,
+            armed: true,
         }
-        Ok(EsparrierState::from_bytes(&result))
     }
 
-    /// Get the current configuration from the device.
-    pub async fn get_config(&self) -> Result<EsparrierConfig, Error> {
-        // Send the 'r'(ReadConfig) command to the device
-        self.write(b"r").await?;
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
 
-        // Response format: ['r', <num_blocks>], <block1>, <block2>, ...
-        let result = self.read().await?;
-        if result.len() != 2 || result[0] != b'r' {
-            return Err(Error::InvalidResponse);
-        }
-        let size = result[1] as usize;
-        debug!("Blocks: {size}");
-        let mut data = Vec::new();
-        for _ in 0..size {
-            let result = self.read().await?;
-            debug!("Block len: {}", result.len());
-            let s = result.strip_suffix(&[0]).unwrap_or(&result);
-            data.extend_from_slice(s);
+impl Drop for OtaGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.abort_pending.store(true, Ordering::Relaxed);
         }
-        data.retain(|&b| (b != 0) && (b <= 0xF4));
-        let config: EsparrierConfig = serde_json::from_slice(&data)
-            .map_err(|_| Error::FormatError("Invalid JSON format".to_string()))?;
-        Ok(config)
     }
+}
 
-    /// Upload the new configuration to the device.
-    pub async fn set_config(&self, config: EsparrierConfig) -> Result<(), Error> {
-        config.validate()?;
-        let data = serde_json::to_vec(&config)
-            .map_err(|_| Error::FormatError("Invalid JSON format".to_string()))?;
-        let blocks = data.chunks(64).collect::<Vec<_>>();
-        // Send the 'w'(WriteConfig) command to the device
-        self.write(&[b'w', blocks.len() as u8]).await?;
-        // Send the blocks
-        for block in blocks {
-            self.write(block).await?;
-        }
-        // Receive the 'o'(Ok) response
-        let result = self.read().await?;
-        if result.len() != 1 || result[0] != b'o' {
-            return Err(Error::InvalidResponse);
+/// Reads the firmware of an OTA upload, checking the segments of the image as
+/// they go by unless forced.
+struct FirmwareReader<R> {
+    reader: R,
+    check: Option<image::SegmentWalk>,
+    offset: usize,
+    total_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FirmwareReader<R> {
+    /// Fill `buf` with the next bytes, once the last one is read the image is
+    /// checked, so a bad image fails before the last chunk is sent.
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.reader.read_exact(buf).await.map_err(|e| {
+            Error::OtaError(format!(
+                "Failed to read firmware at offset {}: {}",
+                self.offset, e
+            ))
+        })?;
+        self.offset += buf.len();
+        if let Some(check) = &mut self.check {
+            check.update(buf);
+            if self.offset == self.total_size {
+                check.finish()?;
+            }
         }
         Ok(())
     }
+}
 
-    /// Commit will flash the new config and restart the device.
-    /// The current connection will be lost, so this method consumes the instance.
-    /// The caller should wait for few seconds before trying to connect again,
-    /// or setup a watcher to detect when the device is back online.
-    pub async fn commit_config(self) -> Result<(), Error> {
-        // Send the 'c'(CommitConfig) command to the device
-        self.write(b"c").await?;
-        // Receive the 'o'(Ok) response
-        let result = self.read().await?;
-        if result.len() != 1 || result[0] != b'o' {
-            return Err(Error::InvalidResponse);
+/// Compare bus IDs, normalizing numeric values (e.g., "3" matches "03")
+fn bus_id_matches(device_bus_id: &str, filter_bus_id: &str) -> bool {
+    // First try exact match
+    if device_bus_id == filter_bus_id {
+        return true;
+    }
+    // Try numeric comparison (handles "3" == "03" case)
+    if let (Ok(a), Ok(b)) = (
+        device_bus_id.parse::<u32>(),
+        filter_bus_id.parse::<u32>(),
+    ) {
+        return a == b;
+    }
+    false
+}
+
+/// Order bus IDs numerically if they're numbers, e.g. "3" before "10", other
+/// IDs come after them in string order.
+fn compare_bus_ids(a: &str, b: &str) -> std::cmp::Ordering {
+    match (a.parse::<u32>(), b.parse::<u32>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// The USB identity of a device, see [`Esparrier::identity`], for logging and
+/// to recognize the device across a reboot.
+///
+/// The device address is reassigned on every enumeration, and the strings are
+/// only informational, so [`is_same_device`](Self::is_same_device) ignores them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceIdentity {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub bus_id: String,
+    pub address: u8,
+    /// Empty if the platform doesn't report it.
+    pub port_chain: Vec<u8>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl DeviceIdentity {
+    pub fn from_device_info(di: &DeviceInfo) -> Self {
+        Self {
+            vendor_id: di.vendor_id(),
+            product_id: di.product_id(),
+            serial_number: di.serial_number().map(|s| s.to_string()),
+            bus_id: di.bus_id().to_string(),
+            address: di.device_address(),
+            port_chain: di.port_chain().to_vec(),
+            manufacturer: di.manufacturer_string().map(|s| s.to_string()),
+            product: di.product_string().map(|s| s.to_string()),
         }
-        Ok(())
     }
 
-    /// Reboot the device.
-    /// The current connection will be lost, so this method consumes the instance.
-    /// The caller should wait for few seconds before trying to connect again,
-    /// or setup a watcher to detect when the device is back online.
-    pub async fn reboot_device(self) -> Result<(), Error> {
-        // Send the 'b'(Reboot) command to the device
-        self.write(b"b").await?;
-        // Receive the 'o'(Ok) response
-        let result = self.read().await?;
-        if result.len() != 1 || result[0] != b'o' {
-            return Err(Error::InvalidResponse);
+    /// Check if `other` is the same physical device.
+    ///
+    /// If both know the port they're plugged into, that decides, as the serial
+    /// number is often left at the default and may change with a committed config.
+    /// Otherwise the VID, PID and serial number must match.
+    pub fn is_same_device(&self, other: &DeviceIdentity) -> bool {
+        if !self.port_chain.is_empty() && !other.port_chain.is_empty() {
+            return bus_id_matches(&self.bus_id, &other.bus_id)
+                && self.port_chain == other.port_chain;
         }
-        Ok(())
+        self.vendor_id == other.vendor_id
+            && self.product_id == other.product_id
+            && self.serial_number.is_some()
+            && self.serial_number == other.serial_number
     }
+}
 
-    pub async fn keep_awake(&self, enable: bool) -> Result<(), Error> {
-        // Send the 'k'(KeepAwake) command to the device
-        self.write(&[b'k', enable as u8]).await?;
-        // Receive the 'o'(Ok) response
-        let result = self.read().await?;
-        if result.len() != 1 || result[0] != b'o' {
-            return Err(Error::InvalidResponse);
+impl Display for DeviceIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x} @ bus {} addr {}",
+            self.vendor_id, self.product_id, self.bus_id, self.address
+        )?;
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, " (SN {serial_number})")?;
         }
         Ok(())
     }
+}
 
-    /// Upload firmware via OTA.
-    ///
-    /// This method uploads the firmware binary to the device in chunks.
-    /// The device will verify the CRC32 checksum and reboot automatically on success.
-    ///
-    /// # Arguments
-    /// * `firmware` - The firmware binary data
-    /// * `progress_callback` - Optional callback for progress updates (received_bytes, total_bytes)
-    ///
-    /// # Returns
-    /// * `Ok(())` - OTA completed successfully, device will reboot
-    /// * `Err(Error)` - OTA failed
-    ///
-    /// # Protocol
-    /// 1. Send OtaStart command: 'O' + size(4B LE) + crc32(4B LE)
-    /// 2. Send OtaData chunks: 'D' + packets(1B) + length(2B LE) followed by packets × 64 bytes
-    /// 3. Receive OtaProgress or OtaComplete responses
-    pub async fn upload_ota<F>(
-        &self,
-        firmware: &[u8],
-        mut progress_callback: Option<F>,
-    ) -> Result<(), Error>
-    where
-        F: FnMut(usize, usize),
-    {
-        let total_size = firmware.len();
-        if total_size == 0 || total_size > 0x100000 {
-            return Err(Error::OtaError(format!(
-                "Invalid firmware size: {} (max 1048576 bytes)",
-                total_size
-            )));
+/// A device found on the bus, see [`Esparrier::list_devices_detailed`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceSummary {
+    pub bus_id: String,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Only set if the device was probed.
+    pub model_id: Option<u8>,
+    /// Only set if the device was probed.
+    pub version: Option<(u8, u8, u8)>,
+    /// Only set if the device was probed and is connected to the network.
+    pub ip_address: Option<Ipv4Addr>,
+    /// Why probing the device failed, e.g. `busy` or `permission denied`.
+    pub probe_error: Option<String>,
+}
+
+impl DeviceSummary {
+    pub fn from_device_info(di: &DeviceInfo) -> Self {
+        Self {
+            bus_id: di.bus_id().to_string(),
+            address: di.device_address(),
+            vendor_id: di.vendor_id(),
+            product_id: di.product_id(),
+            manufacturer: di.manufacturer_string().map(|s| s.to_string()),
+            product: di.product_string().map(|s| s.to_string()),
+            serial_number: di.serial_number().map(|s| s.to_string()),
+            model_id: None,
+            version: None,
+            ip_address: None,
+            probe_error: None,
         }
+    }
 
-        // Calculate CRC32 (IEEE 802.3 polynomial, same as firmware)
-        let crc = crc32(firmware);
-        debug!("Firmware size: {}, CRC32: 0x{:08x}", total_size, crc);
+    /// Fill in what probing the device found out.
+    fn set_probe_result(&mut self, result: Result<EsparrierState, Error>) {
+        match result {
+            Ok(state) => {
+                self.model_id = Some(state.model_id);
+                self.version = Some(state.version());
+                self.ip_address = Some(state.ip_address).filter(|ip| !ip.is_unspecified());
+            }
+            Err(e) => {
+                debug!("Failed to probe device {self}: {e}");
+                self.probe_error = Some(match e {
+                    Error::DeviceBusy => "busy".to_string(),
+                    Error::PermissionDenied => "permission denied".to_string(),
+                    e => e.to_string(),
+                });
+            }
+        }
+    }
 
-        // Send OtaStart command: 'O' + size(4B LE) + crc(4B LE)
-        let mut start_cmd = [0u8; 9];
-        start_cmd[0] = b'O';
-        start_cmd[1..5].copy_from_slice(&(total_size as u32).to_le_bytes());
-        start_cmd[5..9].copy_from_slice(&crc.to_le_bytes());
-        self.write(&start_cmd).await?;
+    /// Get the model name, if the device was probed and the model is known.
+    pub fn model_name(&self) -> Option<&'static str> {
+        self.model_id
+            .and_then(|model_id| ModelId::from(model_id).asset_prefix())
+    }
+}
 
-        // Receive response
-        let result = self.read().await?;
-        if result.is_empty() {
-            return Err(Error::InvalidResponse);
+impl Display for DeviceSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bus: {}, Address: {}", self.bus_id, self.address)?;
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, ", Serial: {serial_number}")?;
         }
-        if result[0] == b'e' {
-            return Err(self.parse_ota_error(&result));
+        if let Some(model_id) = self.model_id {
+            write!(
+                f,
+                ", Model: {}",
+                ModelId::from(model_id).asset_prefix().unwrap_or("unknown")
+            )?;
         }
-        if result[0] != b'o' {
-            return Err(Error::InvalidResponse);
+        if let Some((major, minor, patch)) = self.version {
+            write!(f, ", Firmware: {major}.{minor}.{patch}")?;
+        }
+        if let Some(ip_address) = self.ip_address {
+            write!(f, ", IP: {ip_address}")?;
+        }
+        if let Some(probe_error) = &self.probe_error {
+            write!(f, ", Status: {probe_error}")?;
         }
+        Ok(())
+    }
+}
 
-        // Send firmware in chunks (up to 4096 bytes per chunk = 64 packets × 64 bytes)
-        const CHUNK_SIZE: usize = 4096;
-        let mut sent = 0usize;
+/// Criteria to select devices, unset fields match anything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub bus: Option<String>,
+    pub address: Option<u8>,
+    /// Matched case-insensitively.
+    pub serial: Option<String>,
+}
 
-        for chunk in firmware.chunks(CHUNK_SIZE) {
-            let chunk_len = chunk.len();
-            // Calculate number of 64-byte USB packets needed (round up)
-            let packets = chunk_len.div_ceil(64) as u8;
+impl DeviceFilter {
+    pub fn matches(&self, device: &DeviceSummary) -> bool {
+        self.vid.is_none_or(|v| device.vendor_id == v)
+            && self.pid.is_none_or(|p| device.product_id == p)
+            && self
+                .bus
+                .as_ref()
+                .is_none_or(|b| bus_id_matches(&device.bus_id, b))
+            && self.address.is_none_or(|a| device.address == a)
+            && self.serial.as_ref().is_none_or(|s| {
+                device
+                    .serial_number
+                    .as_ref()
+                    .is_some_and(|serial| serial.eq_ignore_ascii_case(s))
+            })
+    }
 
-            // Send OtaData command: 'D' + packets(1B) + length(2B LE)
-            let length_bytes = (chunk_len as u16).to_le_bytes();
-            self.write(&[b'D', packets, length_bytes[0], length_bytes[1]])
-                .await?;
+    fn matches_device_info(&self, di: &DeviceInfo) -> bool {
+        self.matches(&DeviceSummary::from_device_info(di))
+    }
 
-            // Send the data packets
-            for packet_data in chunk.chunks(64) {
-                // Pad to 64 bytes if needed (USB bulk transfer)
-                let mut packet = [0u8; 64];
-                packet[..packet_data.len()].copy_from_slice(packet_data);
-                self.write(&packet).await?;
-            }
+    /// Set the VID and PID to the Esparrier defaults if unset, so only
+    /// Esparrier devices match.
+    pub fn with_default_ids(self) -> Self {
+        Self {
+            vid: Some(self.vid.unwrap_or(USB_VID)),
+            pid: Some(self.pid.unwrap_or(USB_PID)),
+            ..self
+        }
+    }
+}
 
-            sent += chunk_len;
+/// How to open a device, see [`Esparrier::open`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Wait for the device to be connected.
+    pub wait: bool,
+    /// Use the first device if more than one matches, instead of failing.
+    pub allow_ambiguous: bool,
+    /// How long to wait for the device, `None` waits forever.
+    /// Only applies if `wait` is set.
+    pub timeout: Option<Duration>,
+}
 
-            // Call progress callback
-            if let Some(ref mut cb) = progress_callback {
-                cb(sent, total_size);
+/// Wait until the device with `identity` shows up in `candidates` and open it,
+/// fails with `Error::Timeout` if that doesn't happen in time.
+///
+/// Candidates that fail to open are skipped.
+async fn reconnect<D, U, S, F, Fut>(
+    identity: &DeviceIdentity,
+    candidates: S,
+    timeout: Duration,
+    open: F,
+) -> Result<Esparrier<U>, Error>
+where
+    U: Transport,
+    S: Stream<Item = (DeviceIdentity, D)>,
+    F: Fn(D) -> Fut,
+    Fut: Future<Output = Result<U, Error>>,
+{
+    let wait = async {
+        let mut candidates = std::pin::pin!(candidates);
+        while let Some((candidate, device)) = candidates.next().await {
+            if !identity.is_same_device(&candidate) {
+                debug!("Ignoring other device {candidate:?}");
+                continue;
             }
-
-            // Receive response (Progress or Complete or Error)
-            let result = self.read().await?;
-            if result.is_empty() {
-                return Err(Error::InvalidResponse);
+            match open(device).await {
+                Ok(transport) => return Ok(Esparrier::new(transport)),
+                Err(e) => debug!("Failed to reopen the device: {e}"),
             }
+        }
+        Err(Error::DeviceNotFound)
+    };
+    with_timeout(Some(timeout), wait).await?
+}
 
-            match result[0] {
-                b'P' => {
-                    // Progress response: 'P' + received(4B LE) + total(4B LE)
-                    if result.len() >= 9 {
-                        let received =
-                            u32::from_le_bytes([result[1], result[2], result[3], result[4]]);
-                        let total =
-                            u32::from_le_bytes([result[5], result[6], result[7], result[8]]);
-                        debug!("OTA progress: {}/{} bytes", received, total);
-                    }
-                }
-                b'C' => {
-                    // Complete response
-                    debug!("OTA complete, device will reboot");
-                    return Ok(());
-                }
-                b'o' => {
-                    // Ok response (alternative to Progress)
-                    debug!("OTA chunk acknowledged");
-                }
-                b'e' => {
-                    return Err(self.parse_ota_error(&result));
-                }
-                _ => {
-                    return Err(Error::InvalidResponse);
-                }
-            }
+impl<T: Transport> Esparrier<T> {
+    /// Create a handle that talks to the device over the given transport.
+    pub fn new(transport: T) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: device_span(transport.device_summary().as_ref()),
+            transport,
+            timeout: None,
+            retry_policy: RetryPolicy::default(),
+            ota_options: OtaOptions::default(),
+            ota_abort_pending: AtomicBool::new(false),
+            last_command: AtomicU8::new(0),
+            exchange: async_lock::Mutex::new(()),
+            state: Mutex::new(None),
+            extended_flags: Mutex::new(None),
+            stats: stats::TransferCounters::default(),
+            command_hook: None,
         }
+    }
 
-        // All data sent - the device should have sent OtaComplete
-        // If we're here, something went wrong
-        Err(Error::OtaError(
-            "OTA did not complete as expected".to_string(),
-        ))
+    /// Get the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
     }
 
-    /// Abort an in-progress OTA update.
-    pub async fn abort_ota(&self) -> Result<(), Error> {
-        self.write(b"A").await?;
-        let result = self.read().await?;
-        if result.len() != 1 || result[0] != b'o' {
-            return Err(Error::InvalidResponse);
-        }
-        Ok(())
+    /// Set the timeout applied to every single USB read or write.
+    /// `None` (the default) waits forever.
+    pub fn set_timeout<D>(&mut self, timeout: D)
+    where
+        D: Into<Option<Duration>>,
+    {
+        self.timeout = timeout.into();
     }
 
-    /// Query OTA progress.
-    /// Returns (received_bytes, total_bytes) if OTA is in progress, None otherwise.
-    pub async fn get_ota_progress(&self) -> Result<Option<(u32, u32)>, Error> {
-        self.write(b"P").await?;
-        let result = self.read().await?;
-        if result.is_empty() {
-            return Err(Error::InvalidResponse);
-        }
-        match result[0] {
-            b'P' if result.len() >= 9 => {
-                let received = u32::from_le_bytes([result[1], result[2], result[3], result[4]]);
-                let total = u32::from_le_bytes([result[5], result[6], result[7], result[8]]);
-                Ok(Some((received, total)))
-            }
-            b'o' => Ok(None), // Not in OTA mode
-            b'e' => Err(self.parse_ota_error(&result)),
-            _ => Err(Error::InvalidResponse),
-        }
+    /// Get the timeout applied to every single USB read or write.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
     }
 
-    /// Parse OTA error response.
-    fn parse_ota_error(&self, result: &[u8]) -> Error {
-        if result.len() >= 3 && result[1] == b'O' {
-            let error_msg = match result[2] {
-                b'a' => "OTA already in progress",
-                b'n' => "OTA not started",
-                b'i' => "OTA initialization failed",
-                b'w' => "OTA write failed",
-                b'c' => "CRC mismatch",
-                b'f' => "OTA flush failed",
-                b's' => "Invalid firmware size",
-                b'p' => "OTA partition not found",
-                _ => "Unknown OTA error",
-            };
-            Error::OtaError(error_msg.to_string())
-        } else {
-            Error::InvalidResponse
-        }
+    /// Retry transient transfer errors according to the policy.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
     }
 
-    async fn try_open_device(di: DeviceInfo) -> Result<Self, Error> {
-        let device = di.open().await?;
-        let cfg = device.active_configuration()?;
-
-        // Find the interface with class 0xFF, subclass 0x0D, and protocol 0x0A
-        let iface_alt = cfg
-            .interface_alt_settings()
-            .find(|i| i.class() == 0xFF && i.subclass() == 0x0D && i.protocol() == 0x0A)
-            .ok_or(Error::UnknownDevice)?;
-
-        // Claim this interface
-        let interface = device
-            .claim_interface(iface_alt.interface_number())
-            .await
-            .map_err(|e| {
-                if e.kind() == ErrorKind::PermissionDenied {
-                    Error::PermissionDenied
-                } else {
-                    Error::DeviceBusy
-                }
-            })?;
+    /// Get the current retry policy.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
 
-        // Find the bulk IN and OUT endpoints
-        let alt = interface.descriptors().next().ok_or(Error::UnknownDevice)?;
-        let ep_in_addr = alt
-            .endpoints()
-            .find(|ep| ep.direction() == Direction::In)
-            .ok_or(Error::UnknownDevice)?
-            .address();
-        let ep_out_addr = alt
-            .endpoints()
-            .find(|ep| ep.direction() == Direction::Out)
-            .ok_or(Error::UnknownDevice)?
-            .address();
-
-        // Open the bulk endpoints
-        let ep_in = interface.endpoint::<Bulk, In>(ep_in_addr)?;
-        let ep_out = interface.endpoint::<Bulk, Out>(ep_out_addr)?;
-
-        Ok(Self {
-            device_info: di,
-            ep_in: Mutex::new(ep_in),
-            ep_out: Mutex::new(ep_out),
-        })
+    /// Set the options used by OTA uploads.
+    pub fn with_ota_options(mut self, options: OtaOptions) -> Self {
+        self.ota_options = options;
+        self
+    }
+
+    /// Get the options used by OTA uploads.
+    pub fn ota_options(&self) -> OtaOptions {
+        self.ota_options
     }
 
-    async fn wait_for_device<A, B, C, D>(vid: A, pid: B, bus: C, address: D) -> Result<Self, Error>
+    /// Call `hook` after each command completes, with its name, duration and result.
+    ///
+    /// The hook runs on the task that issued the command, after the device is
+    /// free for the next one, so it should return quickly.
+    pub fn with_command_hook<F>(mut self, hook: F) -> Self
     where
-        A: Into<Option<u16>> + Clone,
-        B: Into<Option<u16>> + Clone,
-        C: Into<Option<String>> + Clone,
-        D: Into<Option<u8>> + Clone,
+        F: Fn(&CommandEvent<'_>) + Send + Sync + 'static,
     {
-        // Create a watcher for hotplug events
-        let mut watch = nusb::watch_devices().unwrap();
-
-        // Check if the device is already connected
-        let devices: Vec<DeviceInfo> = nusb::list_devices().await?.collect();
-        for d in devices {
-            if vid.clone().into().is_none_or(|v| d.vendor_id() == v)
-                && pid.clone().into().is_none_or(|p| d.product_id() == p)
-                && bus
-                    .clone()
-                    .into()
-                    .is_none_or(|b| bus_id_matches(d.bus_id(), &b))
-                && address
-                    .clone()
-                    .into()
-                    .is_none_or(|a| d.device_address() == a)
-            {
-                loop {
-                    match Self::try_open_device(d.clone()).await {
-                        Ok(dev) => return Ok(dev),
-                        Err(Error::DeviceBusy) => {
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                            continue;
-                        }
-                        Err(_) => break,
-                    }
-                }
-            }
-        }
-
-        // Wait for the device to be connected
-        while let Some(event) = watch.next().await {
-            if let HotplugEvent::Connected(di) = event {
-                if vid.clone().into().is_none_or(|v| di.vendor_id() == v)
-                    && pid.clone().into().is_none_or(|p| di.product_id() == p)
-                    && bus
-                        .clone()
-                        .into()
-                        .is_none_or(|b| bus_id_matches(di.bus_id(), &b))
-                    && address
-                        .clone()
-                        .into()
-                        .is_none_or(|a| di.device_address() == a)
-                {
-                    match Self::try_open_device(di).await {
-                        Ok(dev) => return Ok(dev),
-                        Err(_) => continue,
-                    }
-                }
-            }
-        }
-        Err(Error::DeviceNotFound)
+        self.command_hook = Some(Arc::new(hook));
+        self
     }
 
-    /// Write single packet to the device.
-    /// The packet must be less than or equal to 64 bytes.
-    async fn write(&self, data: &[u8]) -> Result<(), Error> {
-        assert!(data.len() <= 64, "Buffer size must be less than or equal to 64 bytes");
-        let mut buf = Buffer::new(64);
-        buf.extend_from_slice(data);
-
-        let mut ep_out = self.ep_out.lock().await;
-        ep_out.submit(buf);
-        let completion = ep_out.next_complete().await;
-        completion.status.map_err(|e| e.into())
+    /// Get what this handle has transferred so far.
+    pub fn stats(&self) -> TransferStats {
+        self.stats.snapshot()
     }
 
-    /// Read single packet from the device.
-    async fn read(&self) -> Result<Vec<u8>, Error> {
-        let buf = Buffer::new(64);
+    /// Open the device matching the filter through another USB stack.
+    ///
+    /// Devices are selected like with [`open`](Esparrier::open), with
+    /// `options.wait` the backend is polled until the device shows up.
+    pub async fn with_backend<B>(
+        backend: &B,
+        filter: &DeviceFilter,
+        options: &OpenOptions,
+    ) -> Result<Self, Error>
+    where
+        B: UsbBackend<Transport = T>,
+    {
+        Ok(Self::new(
+            backend::open_matching(backend, filter, options).await?,
+        ))
+    }
 
-        let mut ep_in = self.ep_in.lock().await;
-        ep_in.submit(buf);
-        let completion = ep_in.next_complete().await;
-        completion.status?;
-        Ok(completion.buffer[..completion.actual_len].to_vec())
+    /// Get the current state from the device.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_state(&self) -> Result<EsparrierState, Error> {
+        self.command("get_state", async {
+            // Send the 's'(GetState) command to the device
+            self.send_command(b"s").await?;
+            let result = self.read().await?;
+            self.check_response(&result, b's')?;
+            let mut state = EsparrierState::try_from_bytes(&result)?;
+            state.extended_flags = *self.extended_flags.lock().unwrap();
+            *self.state.lock().unwrap() = Some(state.clone());
+            Ok(state)
+        })
+        .await
     }
-}
 
-/// Calculate CRC32 checksum (IEEE 802.3 polynomial).
-/// This matches the CRC32 implementation in the firmware.
-fn crc32(data: &[u8]) -> u32 {
-    let mut crc = 0xFFFFFFFFu32;
-    for &byte in data {
-        crc ^= byte as u32;
-        for _ in 0..8 {
-            if crc & 1 != 0 {
-                crc = (crc >> 1) ^ 0xEDB88320;
-            } else {
-                crc >>= 1;
+    /// Get the 32-bit feature flags, `None` if the firmware only reports
+    /// the legacy 8-bit ones in [`EsparrierState::feature_flags`].
+    ///
+    /// The result is kept with the cached state, so [`supports`](Self::supports)
+    /// and the state returned by later [`get_state`](Self::get_state) calls use it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_extended_features(&self) -> Result<Option<FeatureFlags32>, Error> {
+        self.command("get_extended_features", async {
+            // Send the 'F'(GetFeatures) command to the device
+            self.send_command(b"F").await?;
+            // Receive 'F' followed by the flags in little-endian,
+            // firmware without the command answers with an error
+            let result = self.read().await?;
+            if result.first() == Some(&b'e') {
+                return Ok(None);
             }
-        }
+            self.check_response(&result, b'F')?;
+            let bits: [u8; 4] = result
+                .get(1..5)
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(|| {
+                    Error::FormatError(format!(
+                        "GetFeatures response too short: got {} bytes, expected 5",
+                        result.len()
+                    ))
+                })?;
+            let flags = FeatureFlags32::from_bits(u32::from_le_bytes(bits));
+            *self.extended_flags.lock().unwrap() = Some(flags);
+            if let Some(state) = self.state.lock().unwrap().as_mut() {
+                state.extended_flags = Some(flags);
+            }
+            Ok(Some(flags))
+        })
+        .await
     }
-    !crc
-}
+
+    /// Check whether the firmware implements `command`.
+    ///
+    /// Uses the state from the last [`get_state`](Self::get_state), reading
+    /// it from the device first if there is none yet.
+    pub async fn supports(&self, command: Command) -> Result<bool, Error> {
+        let cached = self.state.lock().unwrap().clone();
+        let state = match cached {
+            Some(state) => state,
+            None => self.get_state().await?,
+        };
+        Ok(command.requirement().is_met_by(&state))
+    }
+
+    /// Fail with [`Error::NotSupported`] unless the firmware implements `command`.
+    async fn require(&self, command: Command) -> Result<(), Error> {
+        if self.supports(command).await? {
+            Ok(())
+        } else {
+            Err(Error::NotSupported {
+                command,
+                required: command.requirement(),
+            })
+        }
+    }
+
+    /// Check that the device answers, returning the round trip time.
+    ///
+    /// An Echo command with a random payload is sent, it has no effect on
+    /// the device. Firmware without Echo answers with an error, which is
+    /// reported as [`Error::InvalidResponse`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let payload = random_u64().to_le_bytes();
+        let mut command = vec![b'E'];
+        command.extend_from_slice(&payload);
+        self.command("ping", async {
+            let start = Instant::now();
+            // Send the 'E'(Echo) command to the device
+            self.send_command(&command).await?;
+            // Receive the same packet back
+            let result = self.read().await?;
+            let elapsed = start.elapsed();
+            self.check_response(&result, b'E')?;
+            if result != command {
+                return Err(Error::InvalidResponse);
+            }
+            Ok(elapsed)
+        })
+        .await
+    }
+
+    /// Get the current configuration from the device.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_config(&self) -> Result<EsparrierConfig, Error> {
+        // Send the 'r'(ReadConfig) command to the device
+        self.command("get_config", self.read_config(b'r')).await
+    }
+
+    /// Get the current configuration including the WiFi password, e.g. for
+    /// a backup that can be restored with [`set_config`](Self::set_config).
+    ///
+    /// Needs firmware [`FULL_CONFIG_MIN_VERSION`] or newer, fails with
+    /// `Error::NotSupported` otherwise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_config_full(&self) -> Result<EsparrierConfig, Error> {
+        self.require(Command::FullConfig).await?;
+        // Send the 'R'(ReadFullConfig) command to the device
+        self.command("get_config_full", self.read_config(b'R'))
+            .await
+    }
+
+    async fn read_config(&self, cmd: u8) -> Result<EsparrierConfig, Error> {
+        self.send_command(&[cmd]).await?;
+
+        let data = self.read_blocks(cmd).await?;
+        let json = std::str::from_utf8(&data).map_err(|e| {
+            Error::FormatError(format!(
+                "Config is not valid UTF-8 at offset {}",
+                e.valid_up_to()
+            ))
+        })?;
+        let config: EsparrierConfig = serde_json::from_str(json)
+            .map_err(|_| Error::FormatError("Invalid JSON format".to_string()))?;
+        Ok(config)
+    }
+
+    /// Get the log the firmware keeps in memory, oldest line first.
+    ///
+    /// The firmware only keeps the most recent lines, older ones are dropped.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_logs(&self) -> Result<String, Error> {
+        self.require(Command::Logs).await?;
+        self.command("get_logs", async {
+            // Send the 'L'(GetLogs) command to the device
+            self.send_command(b"L").await?;
+
+            let data = self.read_blocks(b'L').await?;
+            Ok(String::from_utf8_lossy(&data).into_owned())
+        })
+        .await
+    }
+
+    /// Upload the new configuration to the device.
+    ///
+    /// Fails with `Error::FormatError` before sending anything if the JSON is
+    /// larger than [`MAX_CONFIG_SIZE`], e.g. because of big unknown fields.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn set_config(&self, config: EsparrierConfig) -> Result<(), Error> {
+        config.validate()?;
+        debug!("Uploading config: {config:?}");
+        let data = serde_json::to_vec(&config)
+            .map_err(|_| Error::FormatError("Invalid JSON format".to_string()))?;
+        if data.len() > MAX_CONFIG_SIZE {
+            return Err(Error::FormatError(format!(
+                "config too large: {} bytes > {MAX_CONFIG_SIZE}",
+                data.len()
+            )));
+        }
+        self.command("set_config", async {
+            // Send the 'w'(WriteConfig) command to the device
+            self.write_blocks(b'w', &data).await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Upload the new configuration like [`set_config`](Self::set_config), then
+    /// read it back and compare, so a corrupted upload is detected before commit.
+    ///
+    /// The password is not compared as the device never returns it. Firmware
+    /// that reads back the committed configuration until the commit can't
+    /// verify the upload, it's accepted if the configuration read back is the
+    /// one from before the upload.
+    pub async fn set_config_verified(&self, config: EsparrierConfig) -> Result<(), Error> {
+        let mut before = self.get_config().await?;
+        self.set_config(config.clone()).await?;
+        let mut read_back = self.get_config().await?;
+        read_back.password = config.password.clone();
+        before.password = config.password.clone();
+        let Some(change) = config.diff_unredacted(&read_back).into_iter().next() else {
+            return Ok(());
+        };
+        if read_back == before {
+            debug!("The device returns the committed configuration, the upload can't be verified");
+            return Ok(());
+        }
+        Err(Error::VerificationFailed {
+            field: change.field,
+        })
+    }
+
+    /// Upload the configuration to all devices like [`set_config_verified`](Self::set_config_verified),
+    /// and commit it if `commit` is true, at most [`APPLY_CONCURRENCY`] devices at a time.
+    ///
+    /// Returns the result for each device, in order.
+    pub async fn apply_config_to_all(
+        devices: Vec<Self>,
+        config: &EsparrierConfig,
+        commit: bool,
+    ) -> Vec<Result<(), Error>> {
+        futures::stream::iter(devices)
+            .map(|esparrier| async move {
+                esparrier.set_config_verified(config.clone()).await?;
+                if commit {
+                    esparrier.commit_config().await?;
+                }
+                Ok(())
+            })
+            .buffered(APPLY_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// Read the configuration from the device, apply the merge patch (see
+    /// [`EsparrierConfig::merge_json`]) and upload the result, which is returned.
+    ///
+    /// The device never returns the WiFi password, so unless the patch contains
+    /// `password` (possibly empty for an open network) this fails with
+    /// [`ConfigError::PasswordRequired`] rather than wiping it.
+    /// Like [`set_config`](Self::set_config) the change needs to be committed.
+    pub async fn patch_config(&self, patch: &serde_json::Value) -> Result<EsparrierConfig, Error> {
+        if patch.get("password").is_none() {
+            return Err(ConfigError::PasswordRequired.into());
+        }
+        let mut config = self.get_config().await?;
+        config.merge_json(patch)?;
+        self.set_config(config.clone()).await?;
+        Ok(config)
+    }
+
+    /// Commit will flash the new config and restart the device.
+    /// The current connection will be lost, so this method consumes the instance.
+    /// The caller should wait for few seconds before trying to connect again,
+    /// or setup a watcher to detect when the device is back online.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn commit_config(self) -> Result<(), Error> {
+        self.command("commit_config", async {
+            // Send the 'c'(CommitConfig) command to the device
+            self.send_command(b"c").await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Reboot the device.
+    /// The current connection will be lost, so this method consumes the instance.
+    /// The caller should wait for few seconds before trying to connect again,
+    /// or setup a watcher to detect when the device is back online.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn reboot_device(self) -> Result<(), Error> {
+        self.command("reboot_device", async {
+            // Send the 'b'(Reboot) command to the device
+            self.send_command(b"b").await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn keep_awake(&self, enable: bool) -> Result<(), Error> {
+        self.require(Command::KeepAwake).await?;
+        self.command("keep_awake", async {
+            // Send the 'k'(KeepAwake) command to the device
+            self.send_command(&[b'k', enable as u8]).await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Check if keep awake is enabled.
+    pub async fn keep_awake_status(&self) -> Result<bool, Error> {
+        Ok(self.get_state().await?.keep_awake)
+    }
+
+    /// Flip keep awake and return the new value.
+    pub async fn toggle_keep_awake(&self) -> Result<bool, Error> {
+        let enable = !self.keep_awake_status().await?;
+        self.keep_awake(enable).await?;
+        Ok(enable)
+    }
+
+    /// Change the LED brightness right away, `percent` must be in 1..=100.
+    ///
+    /// The stored configuration is left alone, so the device goes back to the
+    /// configured `brightness` on reboot unless that is changed and committed too.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn set_brightness(&self, percent: u8) -> Result<(), Error> {
+        if !(1..=100).contains(&percent) {
+            return Err(Error::FormatError(format!(
+                "Brightness must be between 1 and 100, got {percent}"
+            )));
+        }
+        self.require(Command::Brightness).await?;
+        self.command("set_brightness", async {
+            // Send the 'i'(SetBrightness) command to the device
+            self.send_command(&[b'i', percent]).await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Show a fixed RGB color on the smart LED instead of the connection status.
+    ///
+    /// Like [`set_brightness`](Self::set_brightness) this only lasts until the
+    /// device reboots.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn set_led_color(&self, r: u8, g: u8, b: u8) -> Result<(), Error> {
+        self.require(Command::LedColor).await?;
+        self.command("set_led_color", async {
+            // Send the 'l'(SetLedColor) command to the device
+            self.send_command(&[b'l', r, g, b]).await?;
+            // Receive the 'o'(Ok) response
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Turn the smart LED off, see [`set_led_color`](Self::set_led_color).
+    pub async fn set_led_off(&self) -> Result<(), Error> {
+        self.set_led_color(0, 0, 0).await
+    }
+
+    /// Upload the boot splash shown on the display, the image must match the
+    /// display size of the model, see [`model_display_size`].
+    ///
+    /// # Protocol
+    /// 1. Send UploadImage command: 'I' + format(1B) + width(2B LE) + height(2B LE)
+    /// 2. Send the image in blocks of up to 64 bytes
+    /// 3. Receive 'o'(Ok) or 'e'(Error)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn upload_image(&self, data: &[u8], format: ImageFormat) -> Result<(), Error> {
+        self.require(Command::Splash).await?;
+        let model_id = self.get_state().await?.model_id;
+        format.validate(data, model_id)?;
+        let (width, height) = format.dimensions();
+        let mut command = vec![b'I', format.id()];
+        command.extend_from_slice(&width.to_le_bytes());
+        command.extend_from_slice(&height.to_le_bytes());
+        self.command("upload_image", async {
+            self.send_command(&command).await?;
+            for block in data.chunks(64) {
+                self.write(block).await?;
+            }
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replace the clipboard content of the device, which it then offers to
+    /// the Barrier/Deskflow server like a local copy.
+    ///
+    /// At most [`CLIPBOARD_MAX_SIZE`] bytes fit in the firmware buffer.
+    ///
+    /// # Protocol
+    /// 1. Send SetClipboard command: 'p' + length(2B LE)
+    /// 2. Send the data in blocks of up to 64 bytes
+    /// 3. Receive 'o'(Ok) or 'e'(Error)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn set_clipboard(&self, data: &[u8]) -> Result<(), Error> {
+        self.require(Command::Clipboard).await?;
+        if data.len() > CLIPBOARD_MAX_SIZE {
+            return Err(Error::FormatError(format!(
+                "Clipboard data too large: {} bytes (max {})",
+                data.len(),
+                CLIPBOARD_MAX_SIZE
+            )));
+        }
+        let length_bytes = (data.len() as u16).to_le_bytes();
+        self.command("set_clipboard", async {
+            self.send_command(&[b'p', length_bytes[0], length_bytes[1]])
+                .await?;
+            for block in data.chunks(64) {
+                self.write(block).await?;
+            }
+            let result = self.read().await?;
+            self.check_ok(&result)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Get the clipboard content of the device, as last received from the
+    /// Barrier/Deskflow server or set with [`set_clipboard`](Self::set_clipboard).
+    ///
+    /// # Protocol
+    /// 1. Send GetClipboard command: 'g'
+    /// 2. Receive 'g' + length(2B LE)
+    /// 3. Receive the data in blocks of up to 64 bytes
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_clipboard(&self) -> Result<Vec<u8>, Error> {
+        self.require(Command::Clipboard).await?;
+        self.command("get_clipboard", async {
+            self.send_command(b"g").await?;
+            let result = self.read().await?;
+            self.check_response(&result, b'g')?;
+            if result.len() != 3 {
+                return Err(Error::InvalidResponse);
+            }
+            let length = u16::from_le_bytes([result[1], result[2]]) as usize;
+            if length > CLIPBOARD_MAX_SIZE {
+                return Err(Error::InvalidResponse);
+            }
+            let mut data = Vec::with_capacity(length);
+            while data.len() < length {
+                let block = self.read().await?;
+                if block.is_empty() || data.len() + block.len() > length {
+                    return Err(Error::InvalidResponse);
+                }
+                data.extend_from_slice(&block);
+            }
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Upload firmware via OTA.
+    ///
+    /// This method uploads the firmware binary to the device in chunks.
+    /// The device will verify the CRC32 checksum and reboot automatically on success.
+    ///
+    /// # Arguments
+    /// * `firmware` - The firmware binary data
+    /// * `progress_callback` - Optional callback for progress updates, see [`OtaProgress`]
+    ///
+    /// # Returns
+    /// * `Ok(())` - OTA completed successfully, device will reboot
+    /// * `Err(Error)` - OTA failed
+    ///
+    /// Unless [`OtaOptions::force`] is set, the firmware is checked to be an
+    /// ESP32-S3 application image first, merged full-flash and bootloader
+    /// images are rejected with [`Error::OtaError`].
+    ///
+    /// See [`upload_ota_stream`](Self::upload_ota_stream) for the protocol details.
+    pub async fn upload_ota<F>(
+        &self,
+        firmware: &[u8],
+        progress_callback: Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&OtaProgress),
+    {
+        if !self.ota_options.force {
+            image::validate_app_image(firmware, true)?;
+        }
+        // Calculate CRC32 (IEEE 802.3 polynomial, same as firmware)
+        let crc = crc32_ieee(firmware);
+        self.upload_ota_stream(firmware, firmware.len(), crc, progress_callback)
+            .await
+    }
+
+    /// Upload firmware via OTA, reporting progress as a stream of events
+    /// instead of through a callback.
+    ///
+    /// The returned future performs the upload and must be polled for
+    /// anything to happen, the stream ends when the future completes or is
+    /// dropped. Otherwise this behaves like [`upload_ota`](Self::upload_ota).
+    pub fn upload_ota_with_events<'a>(
+        &'a self,
+        firmware: &'a [u8],
+    ) -> (
+        impl Future<Output = Result<(), Error>> + 'a,
+        impl Stream<Item = OtaEvent>,
+    ) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let upload = async move {
+            let crc = crc32_ieee(firmware);
+            let mut acknowledged = 0;
+            let on_progress = |progress: &OtaProgress| {
+                // The device acknowledgement of a chunk comes with the next report
+                if progress.acknowledged != acknowledged && progress.phase != OtaPhase::Complete {
+                    acknowledged = progress.acknowledged;
+                    let _ = tx.unbounded_send(OtaEvent::DeviceProgress {
+                        received: acknowledged,
+                        total: progress.total,
+                    });
+                }
+                let event = match progress.phase {
+                    OtaPhase::Starting => OtaEvent::Started {
+                        size: progress.total,
+                        crc,
+                    },
+                    OtaPhase::Uploading => OtaEvent::ChunkSent(progress.clone()),
+                    OtaPhase::Complete => OtaEvent::Completed,
+                    OtaPhase::Flashing | OtaPhase::Verifying => return,
+                };
+                let _ = tx.unbounded_send(event);
+            };
+            let result = async {
+                if !self.ota_options.force {
+                    image::validate_app_image(firmware, true)?;
+                }
+                self.upload_ota_stream(firmware, firmware.len(), crc, Some(on_progress))
+                    .await
+            }
+            .await;
+            if let Err(e) = &result {
+                let _ = tx.unbounded_send(OtaEvent::Error(e.to_string()));
+            }
+            result
+        };
+        (upload, rx)
+    }
+
+    /// Upload firmware via OTA, reading it from `reader` as it is sent.
+    ///
+    /// The device needs the size and CRC32 of the image before the first
+    /// byte, so the caller has to supply them up front, e.g. computed with
+    /// [`Crc32`] while downloading or from a first pass over a file. Exactly
+    /// `total_size` bytes are read from `reader`, wrap Tokio readers in
+    /// [`TokioRead`].
+    ///
+    /// Unless [`OtaOptions::force`] is set, the start of the image is checked
+    /// before anything is sent to the device, and the rest as it's read,
+    /// before the last chunk is sent.
+    ///
+    /// If the upload fails after the device entered OTA mode, the session is
+    /// aborted before returning. If the returned future is dropped halfway,
+    /// the abort is sent at the start of the next upload on this handle.
+    ///
+    /// # Protocol
+    /// 1. Send OtaStart command: 'O' + size(4B LE) + crc32(4B LE)
+    /// 2. Send OtaData chunks: 'D' + packets(1B) + length(2B LE) followed by packets × 64 bytes
+    /// 3. Receive OtaProgress or OtaComplete responses
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn upload_ota_stream<R, F>(
+        &self,
+        reader: R,
+        total_size: usize,
+        crc: u32,
+        progress_callback: Option<F>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(&OtaProgress),
+    {
+        self.require(Command::Ota).await?;
+        debug!("Firmware size: {}, CRC32: 0x{:08x}", total_size, crc);
+        self.upload_ota_impl(reader, total_size, Some(crc), 0, progress_callback)
+            .await
+    }
+
+    /// Continue the OTA session left on the device by an interrupted upload,
+    /// e.g. when the host went to sleep, reading the same firmware from
+    /// `reader` as [`upload_ota_stream`](Self::upload_ota_stream).
+    ///
+    /// The bytes the device already received are skipped, the first progress
+    /// report has them as `sent`. The device only reports the size of the
+    /// session, it checks the CRC32 given when the session started once all
+    /// data is received, so a different image of the same size fails there.
+    ///
+    /// Fails with `Error::OtaError` if there's no session, or it's for an
+    /// image of another size.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn resume_ota_stream<R, F>(
+        &self,
+        reader: R,
+        total_size: usize,
+        progress_callback: Option<F>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(&OtaProgress),
+    {
+        let Some((received, total)) = self.get_ota_progress().await? else {
+            return Err(Error::OtaError("No OTA update to resume".to_string()));
+        };
+        if total as usize != total_size {
+            return Err(Error::OtaError(format!(
+                "The OTA update on the device is for {} bytes, the firmware has {}",
+                total, total_size
+            )));
+        }
+        if received >= total {
+            return Err(Error::OtaError(
+                "The OTA update on the device has all the data already".to_string(),
+            ));
+        }
+        debug!("Resuming OTA at {}/{} bytes", received, total);
+        let resume_at = received as usize;
+        self.upload_ota_impl(reader, total_size, None, resume_at, progress_callback)
+            .await
+    }
+
+    /// Upload the firmware, starting a new session with its CRC32 or, without
+    /// one, continuing the session on the device at `resume_at`.
+    async fn upload_ota_impl<R, F>(
+        &self,
+        mut reader: R,
+        total_size: usize,
+        crc: Option<u32>,
+        resume_at: usize,
+        mut progress_callback: Option<F>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(&OtaProgress),
+    {
+        if total_size == 0 || total_size > 0x100000 {
+            return Err(Error::OtaError(format!(
+                "Invalid firmware size: {} (max 1048576 bytes)",
+                total_size
+            )));
+        }
+
+        const CHUNK_SIZE: usize = 4096;
+        let mut buf = [0u8; CHUNK_SIZE];
+
+        // Read the start of the image up front so it can be checked before the
+        // device erases anything, the rest is checked as it's read, before the
+        // last chunk is sent
+        let mut prefix = vec![0; image::CHECK_PREFIX_LEN.min(total_size)];
+        reader
+            .read_exact(&mut prefix)
+            .await
+            .map_err(|e| Error::OtaError(format!("Failed to read firmware: {}", e)))?;
+        let mut check = None;
+        if !self.ota_options.force {
+            image::validate_app_image(&prefix, prefix.len() == total_size)?;
+            check = Some(image::SegmentWalk::new(prefix[1]));
+        }
+        let mut reader = FirmwareReader {
+            reader: futures::io::Cursor::new(prefix).chain(reader),
+            check,
+            offset: 0,
+            total_size,
+        };
+
+        // Skip what the device already has, it's read for the check
+        while reader.offset < resume_at {
+            let len = CHUNK_SIZE.min(resume_at - reader.offset);
+            reader.read_chunk(&mut buf[..len]).await?;
+        }
+        let mut chunk_len = CHUNK_SIZE.min(total_size - resume_at);
+        reader.read_chunk(&mut buf[..chunk_len]).await?;
+
+        // The whole upload is a single exchange
+        self.command("upload_ota", async {
+            // A session being resumed is not stale
+            if self.ota_abort_pending.swap(false, Ordering::Relaxed) && crc.is_some() {
+                debug!("Aborting the OTA session left behind by an interrupted upload");
+                let _ = with_timeout(self.ota_options.chunk_timeout, self.send_abort()).await;
+            }
+
+            let started = Instant::now();
+            let mut progress = OtaProgress::new(total_size);
+            progress.sent = resume_at;
+            progress.acknowledged = resume_at;
+            let mut report = |progress: &OtaProgress| {
+                if let Some(ref mut cb) = progress_callback {
+                    cb(progress);
+                }
+            };
+            report(&progress);
+
+            if let Some(crc) = crc {
+                // Send OtaStart command: 'O' + size(4B LE) + crc(4B LE)
+                let mut start_cmd = [0u8; 9];
+                start_cmd[0] = b'O';
+                start_cmd[1..5].copy_from_slice(&(total_size as u32).to_le_bytes());
+                start_cmd[5..9].copy_from_slice(&crc.to_le_bytes());
+                self.send_command(&start_cmd).await?;
+
+                // Receive response
+                let result = self.read().await?;
+                if result.is_empty() {
+                    return Err(Error::InvalidResponse);
+                }
+                if result[0] == b'e' {
+                    return Err(self.parse_ota_error(&result));
+                }
+                self.check_response(&result, b'o')?;
+            }
+
+            let guard = OtaGuard::new(&self.ota_abort_pending);
+            let result = async {
+                // Send firmware in chunks (up to 4096 bytes per chunk = 64 packets × 64 bytes)
+                let mut sent = resume_at;
+                let upload_started = Instant::now();
+                let mut chunk_started = upload_started;
+
+                loop {
+                    let chunk = &buf[..chunk_len];
+                    let offset = sent;
+                    sent += chunk_len;
+
+                    // Send the chunk and receive the response (Progress or Complete or Error),
+                    // sending it again on transient failures
+                    let mut retries = 0;
+                    let mut reported = false;
+                    let mut result = loop {
+                        let error = match self.send_ota_chunk(offset, chunk).await {
+                            Ok(()) => {
+                                // Call progress callback, only once per chunk
+                                if !reported {
+                                    reported = true;
+                                    let now = Instant::now();
+                                    let chunk_time =
+                                        now.duration_since(chunk_started).as_secs_f64();
+                                    let upload_time =
+                                        now.duration_since(upload_started).as_secs_f64();
+                                    chunk_started = now;
+                                    progress.phase = OtaPhase::Uploading;
+                                    progress.sent = sent;
+                                    progress.elapsed = now.duration_since(started);
+                                    if chunk_time > 0.0 {
+                                        progress.throughput = chunk_len as f64 / chunk_time;
+                                    }
+                                    if upload_time > 0.0 {
+                                        let remaining = (total_size - sent) as f64;
+                                        progress.eta = Some(Duration::from_secs_f64(
+                                            remaining * upload_time / (sent - resume_at) as f64,
+                                        ));
+                                    }
+                                    report(&progress);
+                                    if sent == total_size {
+                                        progress.phase = OtaPhase::Flashing;
+                                        report(&progress);
+                                    }
+                                }
+                                match with_timeout(self.ota_options.chunk_timeout, self.read())
+                                    .await
+                                {
+                                    Ok(Ok(result)) if result.starts_with(b"eOw") => {
+                                        self.parse_ota_error(&result)
+                                    }
+                                    Ok(Ok(result)) => break result,
+                                    Ok(Err(e)) => e,
+                                    Err(_) => {
+                                        return Err(Error::OtaError(format!(
+                                            "Timeout waiting for chunk ack at offset {}",
+                                            offset
+                                        )));
+                                    }
+                                }
+                            }
+                            Err((e, false)) => e,
+                            Err((e, true)) => {
+                                return Err(Error::OtaError(format!(
+                                    "Chunk at offset {} was interrupted: {}",
+                                    offset, e
+                                )));
+                            }
+                        };
+                        if !matches!(
+                            error,
+                            Error::TransferFailed(_)
+                                | Error::RetriesExhausted { .. }
+                                | Error::OtaError(_)
+                        ) {
+                            return Err(error);
+                        }
+                        if retries >= self.ota_options.chunk_retries {
+                            return Err(if retries > 0 {
+                                Error::OtaError(format!(
+                                    "Chunk at offset {} failed after {} retries: {}",
+                                    offset, retries, error
+                                ))
+                            } else {
+                                error
+                            });
+                        }
+                        retries += 1;
+                        debug!(
+                            "OTA chunk at offset {offset} failed ({error}), retrying ({retries})"
+                        );
+                    };
+                    if result.is_empty() {
+                        return Err(Error::InvalidResponse);
+                    }
+
+                    if sent == total_size && matches!(result[0], b'P' | b'o') {
+                        // Some firmware acknowledges the last chunk before checking
+                        // the image, the outcome follows in a separate response
+                        progress.record_ack(&result);
+                        progress.phase = OtaPhase::Verifying;
+                        progress.elapsed = started.elapsed();
+                        report(&progress);
+                        result = self.read().await?;
+                        if result.is_empty() {
+                            return Err(Error::InvalidResponse);
+                        }
+                    }
+
+                    match result[0] {
+                        b'P' | b'o' => {
+                            progress.record_ack(&result);
+                        }
+                        b'C' => {
+                            // Complete response
+                            debug!("OTA complete, device will reboot");
+                            progress.phase = OtaPhase::Complete;
+                            progress.acknowledged = total_size;
+                            progress.elapsed = started.elapsed();
+                            report(&progress);
+                            return Ok(());
+                        }
+                        b'e' => {
+                            return Err(self.parse_ota_error(&result));
+                        }
+                        _ => {
+                            return Err(self.desync(b'P', result[0]));
+                        }
+                    }
+
+                    if sent == total_size {
+                        break;
+                    }
+                    chunk_len = CHUNK_SIZE.min(total_size - sent);
+                    reader.read_chunk(&mut buf[..chunk_len]).await?;
+                }
+
+                // All data sent - the device should have sent OtaComplete
+                // If we're here, something went wrong
+                Err(Error::OtaError(
+                    "OTA did not complete as expected".to_string(),
+                ))
+            }
+            .await;
+
+            if result.is_err() {
+                // Don't leave the device stuck in OTA mode, it would refuse the next attempt
+                let _ = with_timeout(self.ota_options.chunk_timeout, self.send_abort()).await;
+            }
+            guard.disarm();
+            result
+        })
+        .await
+    }
+
+    /// Abort any OTA session still open on the device, e.g. one left behind
+    /// by a killed process, so a new upload can start.
+    ///
+    /// Returns whether a session was aborted.
+    pub async fn ensure_no_ota_in_progress(&self) -> Result<bool, Error> {
+        self.ota_abort_pending.store(false, Ordering::Relaxed);
+        if self.get_ota_progress().await?.is_none() {
+            return Ok(false);
+        }
+        debug!("Aborting stale OTA session");
+        self.abort_ota().await?;
+        Ok(true)
+    }
+
+    /// Send one OTA data chunk: the OtaData header followed by the data packets.
+    ///
+    /// The error tells if the header went out before the failure, the device
+    /// then takes whatever comes next as the data of the chunk, so the chunk
+    /// can't be sent again.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "ota_chunk", level = "debug", skip(self, chunk))
+    )]
+    async fn send_ota_chunk(&self, offset: usize, chunk: &[u8]) -> Result<(), (Error, bool)> {
+        log::trace!(
+            "Sending OTA chunk at offset {offset}, {} bytes",
+            chunk.len()
+        );
+        // Calculate number of 64-byte USB packets needed (round up)
+        let packets = chunk.len().div_ceil(64) as u8;
+
+        // Send OtaData command: 'D' + packets(1B) + length(2B LE)
+        let length_bytes = (chunk.len() as u16).to_le_bytes();
+        self.send_command(&[b'D', packets, length_bytes[0], length_bytes[1]])
+            .await
+            .map_err(|e| (e, false))?;
+
+        // Send the data packets
+        for (i, packet_data) in chunk.chunks(64).enumerate() {
+            // Pad to 64 bytes if needed (USB bulk transfer)
+            let mut packet = [0u8; 64];
+            packet[..packet_data.len()].copy_from_slice(packet_data);
+            if let Err(e) = self.write(&packet).await {
+                // Fill up the chunk so the device reads commands again and the
+                // session can be aborted, it gets the failed packet as padding too
+                for _ in i..packets as usize {
+                    if self.write(&[0; 64]).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = with_timeout(self.ota_options.chunk_timeout, self.read()).await;
+                return Err((e, true));
+            }
+        }
+        Ok(())
+    }
+
+    /// Abort an in-progress OTA update.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn abort_ota(&self) -> Result<(), Error> {
+        self.require(Command::Ota).await?;
+        self.command("abort_ota", async { self.send_abort().await })
+            .await
+    }
+
+    /// Send the OtaAbort command, the caller holds the exchange lock.
+    async fn send_abort(&self) -> Result<(), Error> {
+        self.send_command(b"A").await?;
+        let result = self.read().await?;
+        self.check_ok(&result)
+    }
+
+    /// Query OTA progress.
+    /// Returns (received_bytes, total_bytes) if OTA is in progress, None otherwise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, parent = &self.span)
+    )]
+    pub async fn get_ota_progress(&self) -> Result<Option<(u32, u32)>, Error> {
+        self.require(Command::Ota).await?;
+        self.command("get_ota_progress", async {
+            self.send_command(b"P").await?;
+            let result = self.read().await?;
+            if result.is_empty() {
+                return Err(Error::InvalidResponse);
+            }
+            match result[0] {
+                b'P' if result.len() >= 9 => {
+                    let received = u32::from_le_bytes([result[1], result[2], result[3], result[4]]);
+                    let total = u32::from_le_bytes([result[5], result[6], result[7], result[8]]);
+                    Ok(Some((received, total)))
+                }
+                b'o' => Ok(None), // Not in OTA mode
+                b'e' => Err(self.parse_ota_error(&result)),
+                got => Err(self.desync(b'P', got)),
+            }
+        })
+        .await
+    }
+
+    /// Check that `result` answers the last command with `expected`.
+    ///
+    /// An 'e'(Error) response means the device rejected the command and is
+    /// reported as [`Error::InvalidResponse`], anything else unexpected means the
+    /// host and the device are out of step.
+    fn check_response(&self, result: &[u8], expected: u8) -> Result<(), Error> {
+        match result.first() {
+            Some(&got) if got == expected => Ok(()),
+            None | Some(b'e') => Err(Error::InvalidResponse),
+            Some(&got) => Err(self.desync(expected, got)),
+        }
+    }
+
+    /// Check for the 'o'(Ok) response.
+    fn check_ok(&self, result: &[u8]) -> Result<(), Error> {
+        self.check_response(result, b'o')?;
+        if result.len() != 1 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    fn desync(&self, expected: u8, got: u8) -> Error {
+        Error::ProtocolDesync {
+            command: self.last_command.load(Ordering::Relaxed),
+            expected,
+            got,
+        }
+    }
+
+    /// Parse OTA error response.
+    fn parse_ota_error(&self, result: &[u8]) -> Error {
+        if result.len() >= 3 && result[1] == b'O' {
+            let error_msg = match result[2] {
+                b'a' => "OTA already in progress",
+                b'n' => "OTA not started",
+                b'i' => "OTA initialization failed",
+                b'w' => "OTA write failed",
+                b'c' => "CRC mismatch",
+                b'f' => "OTA flush failed",
+                b's' => "Invalid firmware size",
+                b'p' => "OTA partition not found",
+                _ => "Unknown OTA error",
+            };
+            Error::OtaError(error_msg.to_string())
+        } else {
+            Error::InvalidResponse
+        }
+    }
+
+    /// Read the blocks answering the command in flight.
+    ///
+    /// Response format: `[expected_cmd, <num_blocks>]`, then `num_blocks` packets
+    /// of 1 to 64 bytes, so at most [`MAX_BLOCKS_SIZE`] bytes. A block count of
+    /// zero is no data, and the NUL padding of the final block is removed.
+    /// Missing blocks are an [`Error::Timeout`] after [`BLOCK_TIMEOUT`], or
+    /// the timeout of the handle if it's shorter.
+    async fn read_blocks(&self, expected_cmd: u8) -> Result<Vec<u8>, Error> {
+        let result = self.read().await?;
+        self.check_response(&result, expected_cmd)?;
+        if result.len() != 2 {
+            return Err(Error::InvalidResponse);
+        }
+        let count = result[1] as usize;
+        debug!("Blocks: {count}");
+        let mut data = Vec::with_capacity(count * 64);
+        for _ in 0..count {
+            let block = with_timeout(Some(BLOCK_TIMEOUT), self.read()).await??;
+            debug!("Block len: {}", block.len());
+            if block.is_empty() || block.len() > 64 {
+                return Err(Error::InvalidResponse);
+            }
+            data.extend_from_slice(&block);
+        }
+        while data.last() == Some(&0) {
+            data.pop();
+        }
+        Ok(data)
+    }
+
+    /// Send `data` as a block transfer: `[cmd, <num_blocks>]`, then the data
+    /// in packets of 64 bytes, the last one may be shorter.
+    async fn write_blocks(&self, cmd: u8, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_BLOCKS_SIZE {
+            return Err(Error::FormatError(format!(
+                "Data too large: {} bytes (max {})",
+                data.len(),
+                MAX_BLOCKS_SIZE
+            )));
+        }
+        let blocks = data.chunks(64);
+        self.send_command(&[cmd, blocks.len() as u8]).await?;
+        for block in blocks {
+            self.write(block).await?;
+        }
+        Ok(())
+    }
+
+    /// Run a request/response exchange while holding the exchange lock,
+    /// accounting for it in the stats and reporting it to the command hook.
+    async fn command<R>(
+        &self,
+        name: &'static str,
+        exchange: impl Future<Output = Result<R, Error>>,
+    ) -> Result<R, Error> {
+        let (result, duration) = {
+            let _exchange = self.exchange.lock().await;
+            let started = Instant::now();
+            (exchange.await, started.elapsed())
+        };
+        self.stats.completed(result.is_err());
+        if let Some(hook) = &self.command_hook {
+            hook(&CommandEvent {
+                command: name,
+                duration,
+                result: result.as_ref().map(|_| ()),
+            });
+        }
+        result
+    }
+
+    /// Write the first packet of a command, remembering the command byte for
+    /// error reports.
+    async fn send_command(&self, packet: &[u8]) -> Result<(), Error> {
+        self.last_command.store(packet[0], Ordering::Relaxed);
+        self.write(packet).await
+    }
+
+    /// Write single packet to the device, retrying transient errors.
+    /// The packet must be less than or equal to 64 bytes.
+    async fn write(&self, data: &[u8]) -> Result<(), Error> {
+        self.with_retry(|| self.write_once(data))
+            .await
+            .map_err(|e| self.with_command(e))
+    }
+
+    /// Read single packet from the device, retrying transient errors.
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+        self.with_retry(|| self.read_once())
+            .await
+            .map_err(|e| self.with_command(e))
+    }
+
+    /// Add the command in flight to errors from the transport.
+    fn with_command(&self, e: Error) -> Error {
+        match e {
+            Error::Disconnected { command: None } => Error::Disconnected {
+                command: Some(self.last_command.load(Ordering::Relaxed)),
+            },
+            e => e,
+        }
+    }
+
+    async fn with_retry<R, F, Fut>(&self, mut op: F) -> Result<R, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        let mut attempt = 1;
+        let mut delay = self.retry_policy.backoff;
+        loop {
+            match op().await {
+                Err(Error::TransferFailed(e)) if is_recoverable(e) => {
+                    if attempt >= self.retry_policy.attempts {
+                        return Err(if attempt > 1 {
+                            Error::RetriesExhausted {
+                                attempts: attempt,
+                                source: e,
+                            }
+                        } else {
+                            Error::TransferFailed(e)
+                        });
+                    }
+                    debug!("Transfer failed ({e}), retrying (attempt {attempt})");
+                    self.stats.retried();
+                    Delay::new(delay).await;
+                    delay = self.retry_policy.next_backoff(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn write_once(&self, data: &[u8]) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(len = data.len(), "packet out");
+        with_timeout(self.timeout, self.transport.send_packet(data)).await??;
+        self.stats.sent(data.len());
+        Ok(())
+    }
+
+    async fn read_once(&self) -> Result<Vec<u8>, Error> {
+        let packet = with_timeout(self.timeout, self.transport.recv_packet()).await??;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(len = packet.len(), "packet in");
+        self.stats.received(packet.len());
+        Ok(packet)
+    }
+}
+
+impl Esparrier {
+    /// Commit the configuration like [`commit_config`](Self::commit_config), then
+    /// wait for the same device to come back after the restart and reopen it.
+    ///
+    /// Fails with `Error::Timeout` if the device isn't back within `timeout`.
+    /// The timeout, retry policy and command hook of this handle are carried over.
+    pub async fn commit_config_and_wait(self, timeout: Duration) -> Result<Esparrier, Error> {
+        self.restart_and_wait(timeout, Self::commit_config).await
+    }
+
+    /// Reboot the device like [`reboot_device`](Self::reboot_device), then wait for
+    /// it to come back, reopen it and get the fresh state.
+    ///
+    /// Fails with `Error::Timeout` if the device isn't back within `timeout`.
+    /// The timeout, retry policy and command hook of this handle are carried over.
+    pub async fn reboot_and_wait(
+        self,
+        timeout: Duration,
+    ) -> Result<(Esparrier, EsparrierState), Error> {
+        let esparrier = self.restart_and_wait(timeout, Self::reboot_device).await?;
+        let state = esparrier.get_state().await?;
+        Ok((esparrier, state))
+    }
+
+    async fn restart_and_wait<F, Fut>(
+        self,
+        timeout: Duration,
+        restart: F,
+    ) -> Result<Esparrier, Error>
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let identity = self.identity();
+        let (io_timeout, retry_policy, ota_options, command_hook) = (
+            self.timeout,
+            self.retry_policy,
+            self.ota_options,
+            self.command_hook.clone(),
+        );
+        // Watch before restarting so a quick restart isn't missed
+        let watch = nusb::watch_devices()?;
+        restart(self).await?;
+        let candidates = watch.filter_map(|event| async move {
+            match event {
+                HotplugEvent::Connected(di) => Some((DeviceIdentity::from_device_info(&di), di)),
+                _ => None,
+            }
+        });
+        let mut esparrier = reconnect(&identity, candidates, timeout, open_new_device).await?;
+        esparrier.set_timeout(io_timeout);
+        esparrier.command_hook = command_hook;
+        Ok(esparrier
+            .with_retry_policy(retry_policy)
+            .with_ota_options(ota_options))
+    }
+
+    #[deprecated(note = "use `list_devices_detailed` instead")]
+    pub async fn list_devices(vid: Option<u16>, pid: Option<u16>) -> Vec<(String, u8)> {
+        Self::list_devices_detailed(vid, pid, false)
+            .await
+            .into_iter()
+            .map(|d| (d.bus_id, d.address))
+            .collect()
+    }
+
+    /// List the devices with the specified VID and PID, the defaults if not set.
+    ///
+    /// If `open` is true, each device is also opened to get its model, firmware
+    /// version and IP address, devices that can't be opened, e.g. busy ones, are
+    /// listed without them and with the reason in `probe_error`.
+    ///
+    /// The devices are sorted by bus ID, then address, so the order is stable
+    /// as long as the devices stay connected.
+    pub async fn list_devices_detailed(
+        vid: Option<u16>,
+        pid: Option<u16>,
+        open: bool,
+    ) -> Vec<DeviceSummary> {
+        let devices = match nusb::list_devices().await {
+            Ok(d) => d,
+            Err(e) => {
+                debug!("Failed to list devices: {e}");
+                return Vec::new();
+            }
+        };
+        let mut ret = Vec::new();
+        for di in devices {
+            if di.vendor_id() == vid.unwrap_or(USB_VID) && di.product_id() == pid.unwrap_or(USB_PID)
+            {
+                let mut summary = DeviceSummary::from_device_info(&di);
+                if open {
+                    summary.set_probe_result(Self::probe(di).await);
+                }
+                ret.push(summary);
+            }
+        }
+        ret.sort_by(|a, b| compare_bus_ids(&a.bus_id, &b.bus_id).then(a.address.cmp(&b.address)));
+        ret
+    }
+
+    async fn probe(di: DeviceInfo) -> Result<EsparrierState, Error> {
+        let mut esparrier = Self::try_open_device(di).await?;
+        esparrier.set_timeout(PROBE_TIMEOUT);
+        esparrier.get_state().await
+    }
+
+    /**
+     * Auto detect the device with the specified VID, PID, bus ID, device address and serial number.
+     * If `wait` is true, the method will wait for the device to be connected.
+     *
+     * See [`open`](Self::open) for how it fails, it's strict about more than one device matching.
+     */
+    pub async fn auto_detect<A, B, C, D, E>(
+        wait: bool,
+        vid: A,
+        pid: B,
+        bus: C,
+        address: D,
+        serial: E,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Option<u16>>,
+        B: Into<Option<u16>>,
+        C: Into<Option<String>>,
+        D: Into<Option<u8>>,
+        E: Into<Option<String>>,
+    {
+        let filter = DeviceFilter {
+            vid: vid.into(),
+            pid: pid.into(),
+            bus: bus.into(),
+            address: address.into(),
+            serial: serial.into(),
+        };
+        let options = OpenOptions {
+            wait,
+            ..Default::default()
+        };
+        Self::open(&filter, &options).await
+    }
+
+    /// Open the device matching the filter.
+    ///
+    /// Fails with `Error::Timeout` if waiting for the device takes longer than `timeout`.
+    /// Fails with `Error::AmbiguousDevice` if more than one Esparrier matches, unless
+    /// `allow_ambiguous` is set, then the first one that can be opened is used.
+    /// Fails with `Error::DeviceNotFound` if none matches, otherwise with
+    /// `Error::OpenFailed` for the most relevant device that couldn't be opened,
+    /// e.g. the one the user lacks permission for rather than an unrelated one.
+    pub async fn open(filter: &DeviceFilter, options: &OpenOptions) -> Result<Self, Error> {
+        Ok(Self::new(
+            backend::open_matching(&NusbBackend, filter, options).await?,
+        ))
+    }
+
+    /// Open all devices matching the filter, unset VID and PID default to the Esparrier ones.
+    ///
+    /// Devices that can't be opened, e.g. busy ones, are returned with the error.
+    pub async fn open_all(
+        filter: DeviceFilter,
+    ) -> Result<Vec<Result<Self, (DeviceSummary, Error)>>, Error> {
+        let filter = filter.with_default_ids();
+        let mut ret = Vec::new();
+        for di in nusb::list_devices().await? {
+            if !filter.matches_device_info(&di) {
+                continue;
+            }
+            let summary = DeviceSummary::from_device_info(&di);
+            ret.push(Self::try_open_device(di).await.map_err(|e| (summary, e)));
+        }
+        Ok(ret)
+    }
+
+    /// Open the device with the USB serial number, matched case-insensitively.
+    ///
+    /// Fails with `Error::AmbiguousDevice` if more than one device has it,
+    /// e.g. they're all left with the default one.
+    pub async fn open_by_serial(serial: &str) -> Result<Self, Error> {
+        let filter = DeviceFilter {
+            serial: Some(serial.to_string()),
+            ..Default::default()
+        };
+        Self::open(&filter, &OpenOptions::default()).await
+    }
+
+    /// Get the USB device information of the opened device.
+    pub fn device_info(&self) -> &DeviceInfo {
+        self.transport.device_info()
+    }
+
+    /// The USB identity of the device, from the descriptors read when it was opened.
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity::from_device_info(self.device_info())
+    }
+
+    async fn try_open_device(di: DeviceInfo) -> Result<Self, Error> {
+        Ok(Self::new(NusbTransport::open(di).await?))
+    }
+}
+
+/// How many devices [`Esparrier::apply_config_to_all`] talks to at a time.
+pub const APPLY_CONCURRENCY: usize = 4;
+
+/// How long [`Esparrier::list_devices_detailed`] waits for a device to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Open a device that just showed up, on Windows its interfaces might not be
+/// ready right after the hotplug event so give it a few tries.
+async fn open_new_device(di: DeviceInfo) -> Result<NusbTransport, Error> {
+    let mut attempt = 1;
+    loop {
+        match NusbTransport::open(di.clone()).await {
+            Err(e) if attempt < 5 => {
+                debug!("Failed to open the device, attempt {attempt}: {e}");
+                Delay::new(Duration::from_millis(200)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The span of a device handle, tagged with where the device is if known.
+#[cfg(feature = "tracing")]
+fn device_span(device: Option<&DeviceSummary>) -> tracing::Span {
+    match device {
+        Some(device) => tracing::debug_span!(
+            "esparrier",
+            bus = %device.bus_id,
+            address = device.address,
+            serial = device.serial_number.as_deref().unwrap_or_default(),
+        ),
+        None => tracing::debug_span!("esparrier"),
+    }
+}
+
+/// Run the future to completion, or fail with `Error::Timeout` if it doesn't finish in time.
+async fn with_timeout<F: Future>(timeout: Option<Duration>, fut: F) -> Result<F::Output, Error> {
+    match timeout {
+        Some(timeout) => match future::select(std::pin::pin!(fut), Delay::new(timeout)).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Error::Timeout),
+        },
+        None => Ok(fut.await),
+    }
+}
+
+/// Lookup table for the reflected IEEE 802.3 polynomial 0xEDB88320.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Adapts a Tokio reader, e.g. a `tokio::fs::File`, to the [`AsyncRead`]
+/// [`Esparrier::upload_ota_stream`] reads from.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct TokioRead<R>(pub R);
+
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + Unpin> AsyncRead for TokioRead<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        futures::ready!(std::pin::Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        std::task::Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+/// Incremental CRC32 checksum (IEEE 802.3 polynomial).
+///
+/// This matches the CRC32 implementation in the firmware, and is what
+/// [`Esparrier::upload_ota_stream`] expects when the image is not in memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(0xFFFFFFFF)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &byte in data {
+            crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize];
+        }
+        self.0 = crc;
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Calculate CRC32 checksum (IEEE 802.3 polynomial).
+/// This matches the CRC32 implementation in the firmware, and is the checksum
+/// sent with the firmware image when starting an OTA update.
+pub fn crc32_ieee(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
     use super::*;
+
+    /// An in-memory transport replaying canned device responses.
+    #[derive(Default)]
+    struct FakeTransport {
+        sent: Mutex<Vec<Vec<u8>>>,
+        responses: Mutex<VecDeque<Vec<u8>>>,
+    }
+
+    impl FakeTransport {
+        fn with_responses<I, R>(responses: I) -> Self
+        where
+            I: IntoIterator<Item = R>,
+            R: AsRef<[u8]>,
+        {
+            Self {
+                sent: Mutex::new(Vec::new()),
+                responses: Mutex::new(responses.into_iter().map(|r| r.as_ref().to_vec()).collect()),
+            }
+        }
+
+        fn sent(&self) -> Vec<Vec<u8>> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for FakeTransport {
+        async fn send_packet(&self, data: &[u8]) -> Result<(), Error> {
+            self.sent.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+
+        async fn recv_packet(&self) -> Result<Vec<u8>, Error> {
+            self.responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(Error::Timeout)
+        }
+    }
+
+    fn test_config_json() -> &'static str {
+        r#"{
+            "ssid": "some-wifi",
+            "password": "magic-word",
+            "server": "192.168.2.59:24800",
+            "screen_name": "SAW",
+            "screen_width": 5120,
+            "screen_height": 2880,
+            "flip_wheel": true,
+            "brightness": 10,
+            "serial_number": "88888888",
+            "pid": 4
+        }"#
+    }
+
+    #[test]
+    fn test_config() {
+        let config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        println!("{config:?}");
+    }
+
+    /// GetState response of firmware 0.9.1 with OTA and clipboard support.
+    const STATE_RESPONSE: [u8; 14] = [b's', 0, 9, 1, 0b1100_0010, 192, 168, 1, 123, 24, 1, 0, 1, 2];
+
+    #[tokio::test]
+    async fn test_fake_get_state() {
+        let esparrier = Esparrier::new(FakeTransport::with_responses([STATE_RESPONSE]));
+        let state = esparrier.get_state().await.unwrap();
+        assert_eq!(esparrier.transport().sent(), vec![b"s".to_vec()]);
+        assert_eq!(state.version(), (0, 9, 1));
+        assert_eq!(state.ip_address, Ipv4Addr::new(192, 168, 1, 123));
+        assert_eq!(state.ip_prefix, 24);
+        assert!(state.server_connected);
+        assert!(!state.active);
+        assert!(state.keep_awake);
+        assert!(state.has_ota_support());
+        assert_eq!(state.model(), ModelId::M5AtomS3);
+        assert_eq!(state.model_name(), Some("m5atoms3"));
+        assert_eq!(
+            state.features(),
+            vec![
+                FeatureFlag::SmartLed,
+                FeatureFlag::Ota,
+                FeatureFlag::Clipboard
+            ]
+        );
+
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["version"], "0.9.1");
+        assert_eq!(json["feature_flags"], 194);
+        let state: EsparrierState = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(state.feature_flags.bits(), 194);
+        assert_eq!(
+            json["features"],
+            serde_json::json!(["SmartLed", "Ota", "Clipboard"])
+        );
+        let display = state.to_string();
+        assert!(display.contains("Firmware version: 0.9.1"));
+        assert!(display.contains("Model: m5atoms3 (model_id=2)"));
+        assert!(display.contains("IP address: 192.168.1.123/24"));
+        assert!(display.contains("Features: SmartLed, Ota, Clipboard"));
+    }
+
+    #[test]
+    fn test_state_from_bytes() {
+        let full = [b's', 0, 9, 1, 0b1100_0010, 192, 168, 1, 123, 24, 1, 1, 1, 3];
+
+        let state = EsparrierState::try_from_bytes(&full).unwrap();
+        assert!(state.keep_awake);
+        assert_eq!(state.model_id, 3);
+        assert!(state.extra.is_empty());
+        assert_eq!(state.mac_address(), None);
+        assert_eq!(state.uptime_seconds(), None);
+
+        // Newer firmware with MAC address and uptime
+        let mut newer = full.to_vec();
+        newer.extend([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]);
+        newer.extend(3600u32.to_le_bytes());
+        assert_eq!(newer.len(), 24);
+        let state = EsparrierState::try_from_bytes(&newer).unwrap();
+        assert_eq!(state.model_id, 3);
+        assert_eq!(
+            state.mac_address(),
+            Some([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56])
+        );
+        assert_eq!(state.mac_string().unwrap(), "24:0a:c4:12:34:56");
+        assert_eq!(state.uptime_seconds(), Some(3600));
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["uptime_seconds"], 3600);
+        assert!(state.to_string().contains("Uptime: 3600s"));
+
+        // Only the MAC address
+        let state = EsparrierState::try_from_bytes(&newer[..20]).unwrap();
+        assert!(state.mac_address().is_some());
+        assert_eq!(state.uptime_seconds(), None);
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["mac_address"], "24:0a:c4:12:34:56");
+        // The raw bytes are kept, so the MAC address survives a JSON round trip
+        let state: EsparrierState = serde_json::from_value(json).unwrap();
+        assert_eq!(state.mac_string().unwrap(), "24:0a:c4:12:34:56");
+
+        // A partial MAC address is ignored
+        let state = EsparrierState::try_from_bytes(&newer[..19]).unwrap();
+        assert_eq!(state.mac_address(), None);
+        assert_eq!(state.mac_string(), None);
+        let json = serde_json::to_value(&state).unwrap();
+        assert!(json.get("mac_address").is_none());
+
+        // Firmware without model_id
+        let state = EsparrierState::try_from_bytes(&full[..13]).unwrap();
+        assert!(state.keep_awake);
+        assert_eq!(state.model_id, 0);
+        assert_eq!(state.model_name(), None);
+
+        // Firmware without keep_awake and model_id
+        let state = EsparrierState::try_from_bytes(&full[..12]).unwrap();
+        assert_eq!(state.version(), (0, 9, 1));
+        assert!(state.server_connected);
+        assert!(state.active);
+        assert!(!state.keep_awake);
+        assert_eq!(state.model_id, 0);
+
+        assert!(matches!(
+            EsparrierState::try_from_bytes(&full[..11]),
+            Err(Error::FormatError(msg)) if msg.contains("got 11 bytes")
+        ));
+        assert!(matches!(
+            EsparrierState::try_from_bytes(&[]),
+            Err(Error::InvalidResponse)
+        ));
+        assert!(matches!(
+            EsparrierState::try_from_bytes(b"o"),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn test_feature_flags() {
+        let flags = FeatureFlags::from_bits(0b0010_0011);
+        assert!(flags.contains(FeatureFlag::Led));
+        assert!(flags.contains(FeatureFlag::Led | FeatureFlag::SmartLed));
+        assert!(!flags.contains(FeatureFlag::Ota));
+        assert!(flags.intersects(FeatureFlag::Ota | FeatureFlag::Led));
+        assert_eq!(
+            flags.iter().collect::<Vec<_>>(),
+            vec![FeatureFlag::Led, FeatureFlag::SmartLed]
+        );
+        assert_eq!(flags.unknown_bits(), 0b0010_0000);
+        assert_eq!(flags.to_string(), "Led | SmartLed | 0x20");
+        assert_eq!(FeatureFlags::empty().to_string(), "none");
+
+        // Unknown bits must survive a round trip
+        let json = serde_json::to_string(&flags).unwrap();
+        assert_eq!(json, "35");
+        assert_eq!(serde_json::from_str::<FeatureFlags>(&json).unwrap(), flags);
+        assert_eq!(
+            [FeatureFlag::Ota, FeatureFlag::Clipboard]
+                .into_iter()
+                .collect::<FeatureFlags>()
+                .bits(),
+            0b1100_0000
+        );
+    }
+
+    #[test]
+    fn test_feature_flags32() {
+        let flags = FeatureFlags32::from_bits(0x0001_0042);
+        assert!(flags.contains(FeatureFlag::SmartLed | FeatureFlag::Ota));
+        assert!(!flags.contains(FeatureFlag::Clipboard));
+        assert_eq!(flags.legacy(), FeatureFlags::from_bits(0b0100_0010));
+        assert_eq!(flags.unknown_bits(), 0x0001_0000);
+        assert_eq!(flags.to_string(), "SmartLed | Ota | 0x00010000");
+        assert_eq!(
+            FeatureFlags32::from(FeatureFlags::from_bits(0b1000_0001)).bits(),
+            0b1000_0001
+        );
+        let json = serde_json::to_string(&flags).unwrap();
+        assert_eq!(json, "65602");
+        assert_eq!(
+            serde_json::from_str::<FeatureFlags32>(&json).unwrap(),
+            flags
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fake_get_config() {
+        let json = serde_json::to_vec(
+            &serde_json::from_str::<EsparrierConfig>(test_config_json()).unwrap(),
+        )
+        .unwrap();
+        let mut responses = vec![vec![b'r', json.chunks(64).len() as u8]];
+        responses.extend(json.chunks(64).map(|c| c.to_vec()));
+        let esparrier = Esparrier::new(FakeTransport::with_responses(responses));
+        let config = esparrier.get_config().await.unwrap();
+        assert_eq!(esparrier.transport().sent(), vec![b"r".to_vec()]);
+        assert_eq!(config.ssid, "some-wifi");
+        assert_eq!(config.screen_width, 5120);
+        assert_eq!(config.pid, 4);
+    }
+
+    #[tokio::test]
+    async fn test_fake_set_config() {
+        let esparrier = Esparrier::new(FakeTransport::with_responses([b"o"]));
+        let config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        esparrier.set_config(config.clone()).await.unwrap();
+
+        let sent = esparrier.transport().sent();
+        let json = serde_json::to_vec(&config).unwrap();
+        assert_eq!(sent[0], vec![b'w', json.chunks(64).len() as u8]);
+        assert_eq!(sent[1..].concat(), json);
+    }
+
+    #[tokio::test]
+    async fn test_fake_set_config_too_large() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        config.extra.insert("padding".to_string(), "".into());
+        let padding = MAX_CONFIG_SIZE - serde_json::to_vec(&config).unwrap().len();
+        config
+            .extra
+            .insert("padding".to_string(), "x".repeat(padding).into());
+        let esparrier = Esparrier::new(FakeTransport::with_responses([b"o"]));
+        esparrier.set_config(config.clone()).await.unwrap();
+        assert_eq!(esparrier.transport().sent()[0], vec![b'w', 64]);
+
+        config
+            .extra
+            .insert("padding".to_string(), "x".repeat(padding + 1).into());
+        let esparrier = Esparrier::new(FakeTransport::with_responses([b"o"]));
+        let result = esparrier.set_config(config).await;
+        assert!(matches!(
+            result,
+            Err(Error::FormatError(msg)) if msg == "config too large: 4097 bytes > 4096"
+        ));
+        assert!(esparrier.transport().sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fake_set_config_verified() {
+        let config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        let read = |config: &EsparrierConfig| {
+            let mut config = config.clone();
+            config.password.clear();
+            let json = serde_json::to_vec(&config).unwrap();
+            let mut responses = vec![b"o".to_vec(), vec![b'r', json.chunks(64).len() as u8]];
+            responses.extend(json.chunks(64).map(|c| c.to_vec()));
+            responses
+        };
+
+        // The configuration is read before the upload, then read back
+        let mut before = config.clone();
+        before.screen_name = "BEFORE".to_string();
+        let responses = |read_back: &EsparrierConfig| {
+            let mut responses = read(&before)[1..].to_vec();
+            responses.extend(read(read_back));
+            responses
+        };
+
+        let esparrier = Esparrier::new(FakeTransport::with_responses(responses(&config)));
+        esparrier.set_config_verified(config.clone()).await.unwrap();
+        assert_eq!(esparrier.transport().sent().last().unwrap(), b"r");
+
+        // A block got corrupted on the way
+        let mut corrupted = config.clone();
+        corrupted.screen_name = "SA".to_string();
+        let esparrier = Esparrier::new(FakeTransport::with_responses(responses(&corrupted)));
+        match esparrier.set_config_verified(config).await {
+            Err(Error::VerificationFailed { field }) => assert_eq!(field, "screen_name"),
+            r => panic!("unexpected result: {r:?}"),
+        }
+    }
+
+    /// Header of an ESP32-S3 application image as produced by espflash.
+    const APP_IMAGE_HEADER: [u8; 24] = [
+        0xe9, 0x01, 0x02, 0x2f, 0x5c, 0x8d, 0x37, 0x40, 0xee, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00,
+        0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// A `len` bytes application image with a single flash-mapped segment
+    /// filled by `fill`.
+    fn app_image(len: usize, fill: impl Fn(u32) -> u8) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len as u32).map(fill).collect();
+        image[..24].copy_from_slice(&APP_IMAGE_HEADER);
+        image[24..28].copy_from_slice(&0x3c00_0020u32.to_le_bytes());
+        image[28..32].copy_from_slice(&(len as u32 - 32).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_validate_app_image() {
+        let app = app_image(10000, |i| (i * 3) as u8);
+        assert!(image::validate_app_image(&app, true).is_ok());
+        // Only the header is known while streaming
+        assert!(image::validate_app_image(&APP_IMAGE_HEADER, false).is_ok());
+
+        // Merged image: bootloader at 0, partition table at 0x8000
+        let mut merged = vec![0xff; 0x20000];
+        merged[..24].copy_from_slice(&APP_IMAGE_HEADER);
+        merged[1] = 3;
+        merged[0x8000..0x8002].copy_from_slice(&[0xaa, 0x50]);
+        merged[0x10000..0x10018].copy_from_slice(&APP_IMAGE_HEADER);
+        let err = image::validate_app_image(&merged, true).unwrap_err();
+        assert!(matches!(err, Error::OtaError(msg) if msg.contains("merged")));
+
+        // Bootloader: only IRAM/DRAM segments
+        let mut bootloader = app.clone();
+        bootloader[24..28].copy_from_slice(&0x3fce_3700u32.to_le_bytes());
+        let err = image::validate_app_image(&bootloader, true).unwrap_err();
+        assert!(matches!(err, Error::OtaError(msg) if msg.contains("bootloader")));
+
+        let mut esp32c3 = app.clone();
+        esp32c3[12] = 0x05;
+        let err = image::validate_app_image(&esp32c3, true).unwrap_err();
+        assert!(matches!(err, Error::OtaError(msg) if msg.contains("chip id 5")));
+
+        // Random garbage
+        let mut seed = 0x1234_5678u32;
+        let garbage: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let err = image::validate_app_image(&garbage, true).unwrap_err();
+        assert!(matches!(err, Error::OtaError(msg) if msg.contains("magic")));
+        let err = image::validate_app_image(&[0xe9, 0x01], false).unwrap_err();
+        assert!(matches!(err, Error::OtaError(msg) if msg.contains("too short")));
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_chunk_retry() {
+        let firmware = app_image(10000, |i| (i * 11) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_ota_options(OtaOptions {
+            chunk_retries: 2,
+            ..Default::default()
+        });
+
+        // Flash write failure on the second chunk
+        mock.fail_ota(Some(mock::MockOtaFailure::FlakyWrite(1)));
+        let mut updates = Vec::new();
+        esparrier
+            .upload_ota(
+                &firmware,
+                Some(OtaProgress::sent_total(|sent, _| updates.push(sent))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updates, vec![4096, 8192, 10000]);
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+
+        // Transfer errors on the header of the second chunk: 'O' + 65 packets pass
+        mock.fail_sends(66, 2);
+        esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await
+            .unwrap();
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+
+        mock.fail_sends(66, 3);
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::OtaError(msg)) if msg.starts_with("Chunk at offset 4096 failed after 2 retries")
+        ));
+
+        // A transfer error in the middle of the data of the second chunk can't
+        // be retried, the device waits for the rest of it
+        mock.fail_sends(66 + 10, 1);
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::OtaError(msg)) if msg.starts_with("Chunk at offset 4096 was interrupted")
+        ));
+        // The session was aborted rather than the chunk sent again
+        let packets = mock.received_packets();
+        let start = packets
+            .iter()
+            .rposition(|p| p.len() == 9 && p[0] == b'O')
+            .unwrap();
+        let headers = packets[start..]
+            .iter()
+            .filter(|p| p.len() == 4 && p[0] == b'D');
+        assert_eq!(headers.count(), 2);
+        assert_eq!(packets.last().unwrap(), b"A");
+        assert!(esparrier.get_ota_progress().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_chunk_timeout() {
+        let firmware = app_image(10000, |i| (i * 17) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_ota_options(OtaOptions {
+            chunk_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        mock.fail_ota(Some(mock::MockOtaFailure::Stall(1)));
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::OtaError(msg)) if msg == "Timeout waiting for chunk ack at offset 4096"
+        ));
+        // The stale session was aborted
+        assert!(mock.ota_progress().is_none());
+    }
+
+    #[test]
+    fn test_mock_block_on() {
+        // No Tokio runtime, timeouts and the exchange lock must still work
+        let firmware = app_image(10000, |i| (i * 23) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_ota_options(OtaOptions {
+            chunk_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        futures::executor::block_on(async {
+            assert_eq!(esparrier.get_state().await.unwrap().version(), (0, 9, 1));
+            esparrier
+                .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+                .await
+                .unwrap();
+            assert_eq!(mock.ota_image().unwrap(), firmware);
+
+            mock.fail_ota(Some(mock::MockOtaFailure::Stall(1)));
+            let result = esparrier
+                .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+                .await;
+            assert!(matches!(result, Err(Error::OtaError(_))));
+        });
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_abandoned() {
+        let firmware = app_image(10000, |i| (i * 19) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_ota_options(OtaOptions {
+            chunk_timeout: None,
+            ..Default::default()
+        });
+
+        // The upload future is dropped while waiting for the device
+        mock.fail_ota(Some(mock::MockOtaFailure::Stall(1)));
+        let upload = esparrier.upload_ota(&firmware, None::<fn(&OtaProgress)>);
+        assert!(tokio::time::timeout(Duration::from_millis(50), upload)
+            .await
+            .is_err());
+        assert!(mock.ota_progress().is_some());
+
+        // The next upload aborts the stale session first
+        mock.fail_ota(None);
+        esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await
+            .unwrap();
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+
+        // An error during the upload aborts right away
+        mock.fail_sends(66, 1);
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::TransferFailed(_))));
+        assert!(mock.ota_progress().is_none());
+
+        // A session left behind by another process
+        mock.fail_ota(Some(mock::MockOtaFailure::Stall(0)));
+        let upload = esparrier.upload_ota(&firmware, None::<fn(&OtaProgress)>);
+        assert!(tokio::time::timeout(Duration::from_millis(50), upload)
+            .await
+            .is_err());
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(esparrier.ensure_no_ota_in_progress().await.unwrap());
+        assert!(mock.ota_progress().is_none());
+        assert!(!esparrier.ensure_no_ota_in_progress().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_resume() {
+        let firmware = app_image(10000, |i| (i * 23) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_ota_options(OtaOptions {
+            chunk_timeout: None,
+            ..Default::default()
+        });
+        let result = esparrier
+            .resume_ota_stream(&firmware[..], firmware.len(), None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("No OTA update")));
+
+        // Interrupted after two chunks
+        mock.fail_ota(Some(mock::MockOtaFailure::Stall(2)));
+        let upload = esparrier.upload_ota(&firmware, None::<fn(&OtaProgress)>);
+        assert!(tokio::time::timeout(Duration::from_millis(50), upload)
+            .await
+            .is_err());
+        assert_eq!(mock.ota_progress(), Some((8192, 10000)));
+        mock.fail_ota(None);
+
+        // Firmware of another size can't continue the session
+        let result = esparrier
+            .resume_ota_stream(&firmware[..9000], 9000, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("10000 bytes")));
+
+        let mut reports = Vec::new();
+        esparrier
+            .resume_ota_stream(
+                &firmware[..],
+                firmware.len(),
+                Some(|p: &OtaProgress| reports.push((p.phase, p.sent))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reports[0], (OtaPhase::Starting, 8192));
+        assert_eq!(reports[1], (OtaPhase::Uploading, 10000));
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_force() {
+        let firmware = vec![0x5a; 10000];
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("magic 0x5a")));
+        // Nothing was sent to the device
+        assert!(esparrier.get_ota_progress().await.unwrap().is_none());
+
+        let esparrier = esparrier.with_ota_options(OtaOptions {
+            force: true,
+            ..Default::default()
+        });
+        esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await
+            .unwrap();
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+    }
+
+    #[tokio::test]
+    async fn test_fake_upload_ota() {
+        let firmware = app_image(5000, |i| i as u8);
+        let mut progress = b"P".to_vec();
+        progress.extend_from_slice(&4096u32.to_le_bytes());
+        progress.extend_from_slice(&5000u32.to_le_bytes());
+        let esparrier = Esparrier::new(FakeTransport::with_responses([
+            STATE_RESPONSE.to_vec(),
+            b"o".to_vec(),
+            progress,
+            b"C".to_vec(),
+        ]));
+        let mut updates = Vec::new();
+        esparrier
+            .upload_ota(
+                &firmware,
+                Some(OtaProgress::sent_total(|sent, total| {
+                    updates.push((sent, total))
+                })),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updates, vec![(4096, 5000), (5000, 5000)]);
+
+        let sent = esparrier.transport().sent();
+        // OTA support is checked first
+        assert_eq!(sent[0], b"s");
+        let sent = &sent[1..];
+        let mut start = vec![b'O'];
+        start.extend_from_slice(&5000u32.to_le_bytes());
+        start.extend_from_slice(&crc32_ieee(&firmware).to_le_bytes());
+        assert_eq!(sent[0], start);
+        // First chunk: header + 64 full packets
+        assert_eq!(sent[1], vec![b'D', 64, 0x00, 0x10]);
+        assert_eq!(sent[2..66].concat(), firmware[..4096]);
+        // Second chunk: header + 15 packets, the last one zero padded
+        assert_eq!(sent[66], vec![b'D', 15, 0x88, 0x03]);
+        assert_eq!(sent[67..81].concat(), firmware[4096..4992]);
+        assert_eq!(sent[81][..8], firmware[4992..]);
+        assert_eq!(sent[81].len(), 64);
+        assert_eq!(sent.len(), 82);
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_progress() {
+        let firmware = app_image(10000, |i| (i * 5) as u8);
+        let esparrier = Esparrier::new(mock::MockEsparrier::default());
+        let mut reports = Vec::new();
+        esparrier
+            .upload_ota(&firmware, Some(|p: &OtaProgress| reports.push(p.clone())))
+            .await
+            .unwrap();
+        let phases: Vec<_> = reports
+            .iter()
+            .map(|p| (p.phase, p.sent, p.acknowledged))
+            .collect();
+        assert_eq!(
+            phases,
+            vec![
+                (OtaPhase::Starting, 0, 0),
+                (OtaPhase::Uploading, 4096, 0),
+                (OtaPhase::Uploading, 8192, 4096),
+                (OtaPhase::Uploading, 10000, 8192),
+                (OtaPhase::Flashing, 10000, 8192),
+                (OtaPhase::Complete, 10000, 10000),
+            ]
+        );
+        assert!(reports.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+        assert_eq!(reports[3].eta, Some(Duration::ZERO));
+        assert_eq!(reports[2].percent(), 81);
+
+        // The last chunk acknowledged separately from the final verdict
+        let mut progress = b"P".to_vec();
+        progress.extend_from_slice(&100u32.to_le_bytes());
+        progress.extend_from_slice(&100u32.to_le_bytes());
+        let esparrier = Esparrier::new(FakeTransport::with_responses([
+            STATE_RESPONSE.to_vec(),
+            b"o".to_vec(),
+            progress,
+            b"C".to_vec(),
+        ]));
+        let mut phases = Vec::new();
+        esparrier
+            .upload_ota(
+                &app_image(100, |_| 0),
+                Some(|p: &OtaProgress| phases.push((p.phase, p.acknowledged))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            phases,
+            vec![
+                (OtaPhase::Starting, 0),
+                (OtaPhase::Uploading, 0),
+                (OtaPhase::Flashing, 0),
+                (OtaPhase::Verifying, 100),
+                (OtaPhase::Complete, 100),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_events() {
+        let firmware = app_image(10000, |i| (i * 23) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let (upload, events) = esparrier.upload_ota_with_events(&firmware);
+        let (result, events) = futures::join!(upload, events.collect::<Vec<_>>());
+        result.unwrap();
+        let events: Vec<_> = events
+            .into_iter()
+            .map(|event| match event {
+                OtaEvent::ChunkSent(progress) => format!("sent {}", progress.sent),
+                OtaEvent::DeviceProgress { received, total } => format!("ack {received}/{total}"),
+                event => format!("{event:?}"),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                format!("Started {{ size: 10000, crc: {} }}", crc32_ieee(&firmware)),
+                "sent 4096".to_string(),
+                "ack 4096/10000".to_string(),
+                "sent 8192".to_string(),
+                "ack 8192/10000".to_string(),
+                "sent 10000".to_string(),
+                "Completed".to_string(),
+            ]
+        );
+
+        mock.fail_ota(Some(mock::MockOtaFailure::CrcMismatch));
+        let (upload, events) = esparrier.upload_ota_with_events(&firmware);
+        let (result, events) = futures::join!(upload, events.collect::<Vec<_>>());
+        assert!(result.is_err());
+        assert_eq!(
+            events.last(),
+            Some(&OtaEvent::Error("OTA error: CRC mismatch".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_ping() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.ping().await.unwrap();
+        esparrier.ping().await.unwrap();
+        let received = mock.received_packets();
+        assert_eq!(received.len(), 2);
+        // Each ping has its own payload
+        assert_ne!(received[0], received[1]);
+    }
+
+    #[tokio::test]
+    async fn test_fake_ping_mismatch() {
+        let esparrier = Esparrier::new(FakeTransport::with_responses([&b"E12345678"[..], b"e"]));
+        assert!(matches!(
+            esparrier.ping().await,
+            Err(Error::InvalidResponse)
+        ));
+        // Old firmware without Echo
+        assert!(matches!(
+            esparrier.ping().await,
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fake_not_supported() {
+        let mut response = STATE_RESPONSE;
+        // Firmware 0.5.2 without OTA
+        response[1..5].copy_from_slice(&[0, 5, 2, 0b0000_0010]);
+        let esparrier = Esparrier::new(FakeTransport::with_responses([response]));
+        assert!(!esparrier.supports(Command::Ota).await.unwrap());
+        assert!(!esparrier.supports(Command::KeepAwake).await.unwrap());
+        let result = esparrier
+            .upload_ota(&app_image(128, |_| 0), None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::NotSupported {
+                command: Command::Ota,
+                required,
+            }) if required.feature == Some(FeatureFlag::Ota.into())
+        ));
+        let err = esparrier.keep_awake(true).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "KeepAwake is not supported by the device, it needs firmware 0.6.0 or newer"
+        );
+        // The state was read once, nothing else was sent
+        assert_eq!(esparrier.transport().sent(), vec![b"s".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_fake_ota_error() {
+        let esparrier =
+            Esparrier::new(FakeTransport::with_responses([&STATE_RESPONSE[..], b"eOp"]));
+        let result = esparrier
+            .upload_ota(&app_image(128, |_| 0), None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg == "OTA partition not found"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_config_round_trip() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let mut config = esparrier.get_config().await.unwrap();
+        // The password never comes back from the device
+        assert!(config.password.is_empty());
+        assert_eq!(config.ssid, "mock-wifi");
+
+        config.password = "new-password".to_string();
+        config.brightness = 77;
+        esparrier.set_config(config).await.unwrap();
+        assert_eq!(mock.pending_config().unwrap().brightness, 77);
+        esparrier.commit_config().await.unwrap();
+        assert_eq!(mock.commits(), 1);
+        assert_eq!(mock.config().brightness, 77);
+        assert_eq!(mock.config().password, "new-password");
+    }
+
+    #[tokio::test]
+    async fn test_mock_short_read() {
+        let mock = mock::MockEsparrier::default();
+        mock.truncate_responses(Some(8));
+        let esparrier = Esparrier::new(mock);
+        assert!(matches!(
+            esparrier.get_state().await,
+            Err(Error::FormatError(msg)) if msg.contains("got 8 bytes")
+        ));
+    }
+
+    /// A device that was unplugged, every transfer fails.
+    struct UnpluggedTransport;
+
+    impl Transport for UnpluggedTransport {
+        async fn send_packet(&self, _data: &[u8]) -> Result<(), Error> {
+            Err(TransferError::Disconnected.into())
+        }
+
+        async fn recv_packet(&self) -> Result<Vec<u8>, Error> {
+            Err(TransferError::Disconnected.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnected() {
+        let esparrier = Esparrier::new(UnpluggedTransport);
+        let err = esparrier.get_config().await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Disconnected {
+                command: Some(b'r')
+            }
+        ));
+        assert_eq!(err.to_string(), "Device disconnected during 'r' command");
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100));
+        let delays = std::iter::successors(Some(policy.backoff), |&d| Some(policy.next_backoff(d)))
+            .take(8)
+            .collect::<Vec<_>>();
+        assert_eq!(delays[1], Duration::from_millis(200));
+        assert_eq!(delays[5], Duration::from_millis(3200));
+        assert_eq!(delays[6], MAX_RETRY_BACKOFF);
+        assert_eq!(delays[7], MAX_RETRY_BACKOFF);
+        // A longer backoff is kept, and never overflows
+        let policy = RetryPolicy::new(10, Duration::MAX);
+        assert_eq!(policy.next_backoff(Duration::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn test_error_retryable() {
+        assert!(Error::Timeout.is_retryable());
+        assert!(Error::DeviceBusy.is_retryable());
+        assert!(Error::from(TransferError::Stall).is_retryable());
+        assert!(!Error::from(TransferError::Cancelled).is_retryable());
+        assert!(!Error::InvalidResponse.is_retryable());
+        let desync = Error::ProtocolDesync {
+            command: b'r',
+            expected: b'r',
+            got: b'o',
+        };
+        assert!(!desync.is_retryable());
+        assert_eq!(
+            desync.to_string(),
+            "Protocol out of sync, expected 'r' but got 0x6f during 'r' command"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_stats() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone()).with_command_hook({
+            let events = events.clone();
+            move |event: &CommandEvent| {
+                events
+                    .lock()
+                    .unwrap()
+                    .push((event.command, event.result.is_ok()));
+            }
+        });
+        assert_eq!(esparrier.stats(), TransferStats::default());
+
+        esparrier.get_state().await.unwrap();
+        let config = esparrier.get_config().await.unwrap();
+        let stats = esparrier.stats();
+        assert_eq!(stats.commands, 2);
+        assert_eq!(stats.failed_commands, 0);
+        assert_eq!(stats.packets_out, 2);
+        assert!(stats.packets_in >= 3);
+        assert_eq!(stats.bytes_out, 2);
+        assert!(stats.bytes_in as usize > serde_json::to_vec(&config).unwrap().len());
+        assert_eq!(stats.last_error, None);
+
+        // Failures are counted, and the OTA upload feeds the same counters
+        mock.fail_sends(0, 1);
+        esparrier.keep_awake(true).await.unwrap_err();
+        let firmware = app_image(10000, |i| i as u8);
+        esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await
+            .unwrap();
+        let after = esparrier.stats();
+        assert_eq!(after.commands, 4);
+        assert_eq!(after.failed_commands, 1);
+        assert!(after.last_error.is_some());
+        assert!(after.bytes_out - stats.bytes_out >= firmware.len() as u64);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("get_state", true),
+                ("get_config", true),
+                ("keep_awake", false),
+                ("upload_ota", true)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_mock_concurrent_commands() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = std::sync::Arc::new(Esparrier::new(mock.clone()));
+        let tasks = (0..100)
+            .map(|i| {
+                let esparrier = esparrier.clone();
+                tokio::spawn(async move {
+                    if i % 2 == 0 {
+                        esparrier.get_state().await.map(|_| ())
+                    } else {
+                        esparrier.get_config().await.map(|_| ())
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(mock.received_packets().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_mock_config_unicode() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let mut config = esparrier.get_config().await.unwrap();
+        config.ssid = "café ☕ naïve 🎉".to_string();
+        config.password = "pässwörd".to_string();
+        // Barrier only accepts ASCII screen names
+        config.screen_name = "Écran-gauche 🖥️ señor".to_string();
+        assert!(matches!(
+            esparrier.set_config(config.clone()).await,
+            Err(Error::ConfigError(ConfigError::InvalidScreenName {
+                character: 'É',
+                position: 0
+            }))
+        ));
+        config.screen_name = sanitize_screen_name(&config.screen_name);
+        assert_eq!(config.screen_name, "cran-gauche-se-or");
+        esparrier.set_config(config.clone()).await.unwrap();
+        let read_back = esparrier.get_config().await.unwrap();
+        assert_eq!(read_back.ssid, config.ssid);
+        assert_eq!(read_back.screen_name, config.screen_name);
+    }
+
+    #[tokio::test]
+    async fn test_mock_config_invalid_utf8() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        mock.inject_response(b"r\x01".to_vec());
+        mock.inject_response(b"{\"ssid\": \"caf\xc3\"}".to_vec());
+        assert!(matches!(
+            esparrier.get_config().await,
+            Err(Error::FormatError(msg)) if msg.contains("offset 13")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_read_blocks() {
+        let mock = mock::MockEsparrier::default();
+        let mut esparrier = Esparrier::new(mock.clone());
+        esparrier.set_timeout(Duration::from_millis(50));
+
+        // No blocks
+        mock.inject_response(b"r\x00".to_vec());
+        assert!(esparrier.read_blocks(b'r').await.unwrap().is_empty());
+
+        // A short final block, and a padded one
+        mock.inject_response(b"r\x02".to_vec());
+        mock.inject_response(vec![b'a'; 64]);
+        mock.inject_response(b"bc".to_vec());
+        mock.inject_response(b"r\x02".to_vec());
+        mock.inject_response(vec![b'a'; 64]);
+        mock.inject_response(b"bc\0\0\0\0".to_vec());
+        for _ in 0..2 {
+            let data = esparrier.read_blocks(b'r').await.unwrap();
+            assert_eq!(data.len(), 66);
+            assert!(data.ends_with(b"abc"));
+        }
+
+        // Oversized block
+        mock.inject_response(b"r\x01".to_vec());
+        mock.inject_response(vec![b'a'; 65]);
+        assert!(matches!(
+            esparrier.read_blocks(b'r').await,
+            Err(Error::InvalidResponse)
+        ));
+
+        // 3 blocks announced but only 2 sent
+        mock.inject_response(b"r\x03".to_vec());
+        mock.inject_response(vec![b'a'; 64]);
+        mock.inject_response(vec![b'a'; 64]);
+        assert!(matches!(
+            esparrier.read_blocks(b'r').await,
+            Err(Error::Timeout)
+        ));
+
+        // Also without a timeout on the handle
+        let esparrier = Esparrier::new(mock.clone());
+        mock.inject_response(b"r\x03".to_vec());
+        mock.inject_response(vec![b'a'; 64]);
+        mock.inject_response(vec![b'a'; 64]);
+        assert!(matches!(
+            esparrier.read_blocks(b'r').await,
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fake_write_blocks() {
+        let esparrier = Esparrier::new(FakeTransport::with_responses::<_, &[u8]>([]));
+        esparrier.write_blocks(b'w', &[7; 130]).await.unwrap();
+        let sent = esparrier.transport().sent();
+        assert_eq!(sent[0], [b'w', 3]);
+        assert_eq!(
+            sent[1..].iter().map(Vec::len).collect::<Vec<_>>(),
+            [64, 64, 2]
+        );
+
+        let esparrier = Esparrier::new(FakeTransport::with_responses::<_, &[u8]>([]));
+        esparrier
+            .write_blocks(b'w', &[7; MAX_BLOCKS_SIZE])
+            .await
+            .unwrap();
+        assert_eq!(esparrier.transport().sent()[0], [b'w', 255]);
+        let esparrier = Esparrier::new(FakeTransport::with_responses::<_, &[u8]>([]));
+        let result = esparrier
+            .write_blocks(b'w', &[7; MAX_BLOCKS_SIZE + 1])
+            .await;
+        assert!(matches!(result, Err(Error::FormatError(msg)) if msg.contains("too large")));
+        assert!(esparrier.transport().sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_out_of_order_response() {
+        let mock = mock::MockEsparrier::default();
+        mock.inject_response(b"o".to_vec());
+        let esparrier = Esparrier::new(mock);
+        assert!(matches!(
+            esparrier.get_state().await,
+            Err(Error::ProtocolDesync {
+                command: b's',
+                expected: b's',
+                got: b'o'
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_lost_response_times_out() {
+        let mock = mock::MockEsparrier::default();
+        mock.drop_responses(1);
+        let mut esparrier = Esparrier::new(mock);
+        esparrier.set_timeout(Duration::from_millis(50));
+        assert!(matches!(esparrier.get_state().await, Err(Error::Timeout)));
+        // The handle is still usable afterwards
+        assert!(esparrier.get_state().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mock_clipboard() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let text: Vec<u8> = (0..200u32).map(|i| b'a' + (i % 26) as u8).collect();
+        esparrier.set_clipboard(&text).await.unwrap();
+        assert_eq!(mock.clipboard(), text);
+        assert_eq!(esparrier.get_clipboard().await.unwrap(), text);
+
+        mock.set_clipboard("from the server");
+        assert_eq!(esparrier.get_clipboard().await.unwrap(), b"from the server");
+
+        esparrier.set_clipboard(b"").await.unwrap();
+        assert!(esparrier.get_clipboard().await.unwrap().is_empty());
+
+        let full = vec![b'x'; CLIPBOARD_MAX_SIZE];
+        esparrier.set_clipboard(&full).await.unwrap();
+        assert_eq!(esparrier.get_clipboard().await.unwrap(), full);
+        let result = esparrier.set_clipboard(&[0; CLIPBOARD_MAX_SIZE + 1]).await;
+        assert!(matches!(result, Err(Error::FormatError(msg)) if msg.contains("too large")));
+
+        // Firmware without the clipboard feature
+        let mut state = mock.state();
+        state.feature_flags.remove(FeatureFlag::Clipboard);
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(matches!(
+            esparrier.get_clipboard().await,
+            Err(Error::NotSupported {
+                command: Command::Clipboard,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_brightness() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.set_brightness(5).await.unwrap();
+        assert_eq!(mock.brightness(), Some(5));
+        esparrier.set_brightness(100).await.unwrap();
+        assert_eq!(mock.brightness(), Some(100));
+        for percent in [0, 101] {
+            let result = esparrier.set_brightness(percent).await;
+            assert!(matches!(result, Err(Error::FormatError(_))));
+        }
+        // The stored configuration is untouched
+        assert_eq!(mock.config().brightness, BRIGHTNESS);
+        assert!(mock.pending_config().is_none());
+
+        // Firmware without any LED
+        let mut state = mock.state();
+        state.feature_flags.remove(FeatureFlag::SmartLed);
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        let result = esparrier.set_brightness(50).await;
+        assert!(matches!(
+            result,
+            Err(Error::NotSupported {
+                command: Command::Brightness,
+                ..
+            })
+        ));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Brightness is not supported by the device, it needs firmware with the Led or SmartLed feature"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_led_color() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.set_led_color(0x12, 0x34, 0x56).await.unwrap();
+        assert_eq!(mock.led_color(), Some((0x12, 0x34, 0x56)));
+        esparrier.set_led_off().await.unwrap();
+        assert_eq!(mock.led_color(), Some((0, 0, 0)));
+
+        // A plain LED can't show colors
+        let mut state = mock.state();
+        state.feature_flags.remove(FeatureFlag::SmartLed);
+        state.feature_flags.insert(FeatureFlag::Led);
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(esparrier.supports(Command::Brightness).await.unwrap());
+        assert!(matches!(
+            esparrier.set_led_color(255, 0, 0).await,
+            Err(Error::NotSupported {
+                command: Command::LedColor,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_extended_features() {
+        // Firmware with only the 8-bit flags
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        assert_eq!(esparrier.get_extended_features().await.unwrap(), None);
+        let state = esparrier.get_state().await.unwrap();
+        assert_eq!(state.extended_features(), None);
+        assert!(state.has_feature(FeatureFlag::Clipboard));
+
+        // The extended flags take precedence over the legacy byte
+        mock.set_extended_flags(Some(0x0001_0042));
+        let esparrier = Esparrier::new(mock.clone());
+        let flags = esparrier.get_extended_features().await.unwrap().unwrap();
+        assert_eq!(flags.bits(), 0x0001_0042);
+        let state = esparrier.get_state().await.unwrap();
+        assert_eq!(state.extended_features(), Some(flags));
+        assert!(state.has_feature(FeatureFlag::Ota));
+        assert!(!state.has_feature(FeatureFlag::Clipboard));
+        assert_eq!(
+            state.features(),
+            vec![FeatureFlag::SmartLed, FeatureFlag::Ota]
+        );
+        assert!(!esparrier.supports(Command::Clipboard).await.unwrap());
+        assert!(matches!(
+            esparrier.get_clipboard().await,
+            Err(Error::NotSupported {
+                command: Command::Clipboard,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_logs() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        assert_eq!(esparrier.get_logs().await.unwrap(), "");
+        mock.push_log("INFO - Connecting to WiFi mock-wifi");
+        mock.push_log("INFO - Got IP address 192.168.1.123/24");
+        mock.push_log("WARN - Failed to connect to server 192.168.1.250:24800, retrying");
+        assert_eq!(
+            esparrier.get_logs().await.unwrap(),
+            "INFO - Connecting to WiFi mock-wifi\n\
+             INFO - Got IP address 192.168.1.123/24\n\
+             WARN - Failed to connect to server 192.168.1.250:24800, retrying\n"
+        );
+
+        // Firmware older than the log ring
+        let mut state = mock.state();
+        state.version_minor = 8;
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        let err = esparrier.get_logs().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Logs is not supported by the device, it needs firmware 0.9.0 or newer"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_image() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let format = ImageFormat::Rgb565 {
+            width: 128,
+            height: 128,
+        };
+        let image = vec![0x5A; format.image_size()];
+        // The default mock has no display
+        assert!(matches!(
+            esparrier.upload_image(&image, format).await,
+            Err(Error::NotSupported {
+                command: Command::Splash,
+                ..
+            })
+        ));
+
+        let mut state = mock.state();
+        state.feature_flags.insert(FeatureFlag::Graphics);
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.upload_image(&image, format).await.unwrap();
+        assert_eq!(mock.splash(), Some(image.clone()));
+
+        let result = esparrier
+            .upload_image(
+                &image[..64 * 64 * 2],
+                ImageFormat::Rgb565 {
+                    width: 64,
+                    height: 64,
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(Error::FormatError(msg)) if msg.contains("128x128")));
+        let result = esparrier.upload_image(&image[1..], format).await;
+        assert!(matches!(result, Err(Error::FormatError(_))));
+    }
+
+    #[test]
+    fn test_model_id() {
+        for &model in ModelId::all() {
+            assert_eq!(ModelId::from(model.id()), model);
+            assert_eq!(model.to_string().parse::<ModelId>().unwrap(), model);
+        }
+        // Every model id that maps to a model has an asset to update it from
+        for id in 0..=u8::MAX {
+            let model = ModelId::from(id);
+            if !matches!(model, ModelId::Unknown(_)) {
+                let prefix = model.asset_prefix().unwrap();
+                assert_eq!(prefix.parse::<ModelId>().unwrap().id(), id);
+            }
+        }
+        assert_eq!(ModelId::from(42), ModelId::Unknown(42));
+        assert_eq!(ModelId::Unknown(42).to_string(), "unknown-42");
+        assert_eq!(
+            "unknown-42".parse::<ModelId>().unwrap(),
+            ModelId::Unknown(42)
+        );
+        assert_eq!("3".parse::<ModelId>().unwrap(), ModelId::M5AtomS3R);
+        assert_eq!(
+            "M5AtomS3-Lite".parse::<ModelId>().unwrap(),
+            ModelId::M5AtomS3Lite
+        );
+        assert!("m5atoms4".parse::<ModelId>().is_err());
+        assert_eq!(serde_json::to_string(&ModelId::Generic).unwrap(), "255");
+        assert_eq!(
+            serde_json::from_str::<ModelId>("6").unwrap(),
+            ModelId::XiaoEsp32S3
+        );
+    }
+
+    #[test]
+    fn test_rgb888_to_rgb565() {
+        assert_eq!(
+            rgb888_to_rgb565(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]),
+            vec![0xF8, 0x00, 0x07, 0xE0, 0x00, 0x1F, 0xFF, 0xFF]
+        );
+        assert_eq!(model_display_size(3), Some((128, 128)));
+        assert_eq!(model_display_size(1), None);
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota() {
+        let firmware = app_image(10000, |i| (i * 7) as u8);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await
+            .unwrap();
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+    }
+
+    /// The original bit-by-bit implementation, kept to check the table-driven one.
+    fn crc32_bitwise(data: &[u8]) -> u32 {
+        let mut crc = 0xFFFFFFFFu32;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                if crc & 1 != 0 {
+                    crc = (crc >> 1) ^ 0xEDB88320;
+                } else {
+                    crc >>= 1;
+                }
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32_ieee(b""), 0);
+        assert_eq!(crc32_ieee(b"a"), 0xE8B7BE43);
+        assert_eq!(crc32_ieee(b"123456789"), 0xCBF43926);
+        assert_eq!(
+            crc32_ieee(b"The quick brown fox jumps over the lazy dog"),
+            0x414FA339
+        );
+
+        let mut seed = 0x9e37_79b9u32;
+        for len in [1, 63, 64, 4095, 4096, 10000, 65537] {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            assert_eq!(crc32_ieee(&data), crc32_bitwise(&data), "length {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_stream() {
+        let firmware = app_image(10000, |i| (i * 13) as u8);
+        let mut crc = Crc32::new();
+        for chunk in firmware.chunks(1000) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finalize(), crc32_ieee(&firmware));
+
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let mut updates = Vec::new();
+        esparrier
+            .upload_ota_stream(
+                &firmware[..],
+                firmware.len(),
+                crc.finalize(),
+                Some(OtaProgress::sent_total(|sent, _| updates.push(sent))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updates, vec![4096, 8192, 10000]);
+        assert_eq!(mock.ota_image().unwrap(), firmware);
+
+        // A reader that ends early aborts the session on the device, the
+        // start of the image is read before the session starts
+        let firmware = app_image(40000, |i| (i * 13) as u8);
+        let result = esparrier
+            .upload_ota_stream(
+                &firmware[..36000],
+                firmware.len(),
+                crc32_ieee(&firmware),
+                None::<fn(&OtaProgress)>,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("offset 32768")));
+        assert!(esparrier.get_ota_progress().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_stream_checks_image() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let upload = |firmware: Vec<u8>| {
+            let esparrier = &esparrier;
+            async move {
+                esparrier
+                    .upload_ota_stream(
+                        &firmware[..],
+                        firmware.len(),
+                        crc32_ieee(&firmware),
+                        None::<fn(&OtaProgress)>,
+                    )
+                    .await
+            }
+        };
+        let started = || mock.received_packets().iter().any(|p| p[0] == b'O');
+
+        // Merged image: the bootloader header passes, the partition table doesn't
+        let mut merged = vec![0xff; 0x20000];
+        merged[..24].copy_from_slice(&APP_IMAGE_HEADER);
+        merged[1] = 3;
+        merged[0x8000..0x8002].copy_from_slice(&[0xaa, 0x50]);
+        let result = upload(merged).await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("merged")));
+        assert!(!started());
+
+        // Two segments, the second header past the start read up front
+        let image = |second_addr: u32| {
+            let mut image = app_image(40000, |i| i as u8);
+            image[1] = 2;
+            image[24..28].copy_from_slice(&0x3fce_3700u32.to_le_bytes());
+            image[28..32].copy_from_slice(&0x9000u32.to_le_bytes());
+            let second = 32 + 0x9000;
+            let len = 40000 - second as u32 - 8;
+            image[second..second + 4].copy_from_slice(&second_addr.to_le_bytes());
+            image[second + 4..second + 8].copy_from_slice(&len.to_le_bytes());
+            image
+        };
+        // Only IRAM and DRAM segments: a bootloader, caught before the last chunk
+        let result = upload(image(0x4037_0000)).await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg.contains("bootloader")));
+        assert!(started());
+        assert!(esparrier.get_ota_progress().await.unwrap().is_none());
+        assert!(mock.ota_image().is_none());
+
+        let app = image(0x4200_0000);
+        upload(app.clone()).await.unwrap();
+        assert_eq!(mock.ota_image().unwrap(), app);
+    }
+
+    #[tokio::test]
+    async fn test_mock_ota_failures() {
+        let firmware = app_image(10000, |_| 0x5a);
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+
+        mock.fail_ota(Some(mock::MockOtaFailure::CrcMismatch));
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg == "CRC mismatch"));
+
+        mock.fail_ota(Some(mock::MockOtaFailure::WriteFailed(1)));
+        let result = esparrier
+            .upload_ota(&firmware, None::<fn(&OtaProgress)>)
+            .await;
+        assert!(matches!(result, Err(Error::OtaError(msg)) if msg == "OTA write failed"));
+        assert!(mock.ota_image().is_none());
+    }
+
+    #[test]
+    fn test_validate_server() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        for server in [
+            "192.168.2.59:24800",
+            "kvm.lan:24800",
+            "kvm.lan.:24800",
+            "localhost:1",
+            "my-server-01.example.com:65535",
+        ] {
+            config.server = server.to_string();
+            assert!(config.validate().is_ok(), "{server} should be valid");
+        }
+        for server in [
+            "kvm.lan",
+            "kvm lan:24800",
+            "kvm.lan:0",
+            "kvm.lan:65536",
+            "kvm.lan:",
+            ":24800",
+            "-kvm.lan:24800",
+            "kvm..lan:24800",
+            "192.168.1.300:24800",
+            "kvm_lan:24800",
+        ] {
+            config.server = server.to_string();
+            assert!(
+                matches!(
+                    config.validate(),
+                    Err(Error::ConfigError(ConfigError::InvalidEndpoint(_)))
+                ),
+                "{server} should be invalid"
+            );
+        }
+
+        // Old firmware only understands IPv4 addresses
+        config.server = "kvm.lan:24800".to_string();
+        assert!(config.validate_for_version((0, 8, 3)).is_err());
+        assert!(config
+            .validate_for_version(HOSTNAME_SERVER_MIN_VERSION)
+            .is_ok());
+        config.server = "192.168.2.59:24800".to_string();
+        assert!(config.validate_for_version((0, 8, 3)).is_ok());
+    }
+
     #[test]
-    fn test_config() {
-        let config_str = r#"{
+    fn test_validate_password() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        for password in [
+            String::new(),
+            "12345678".to_string(),
+            "x".repeat(63),
+            "0123456789abcdef".repeat(4),
+        ] {
+            config.password = password;
+            assert!(
+                config.validate().is_ok(),
+                "{} should be valid",
+                config.password
+            );
+        }
+
+        config.password = "abc".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::PasswordTooShort))
+        ));
+        config.password = "x".repeat(64);
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::InvalidPsk))
+        ));
+        config.password = "a".repeat(65);
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::FieldTooLong(field))) if field == "password"
+        ));
+    }
+
+    #[test]
+    fn test_validate_ip_addr() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        config.gateway = Some("192.168.1.1".to_string());
+        for ip in ["192.168.1.10/24", "10.0.0.2/8", "10.0.0.2/1", "10.0.0.2/32"] {
+            config.ip_addr = Some(ip.to_string());
+            assert!(config.validate().is_ok(), "{ip} should be valid");
+        }
+        for (ip, prefix) in [
+            ("192.168.1.10/200", "200"),
+            ("192.168.1.10/33", "33"),
+            ("192.168.1.10/0", "0"),
+            ("192.168.1.10/", ""),
+            ("192.168.1.10/abc", "abc"),
+        ] {
+            config.ip_addr = Some(ip.to_string());
+            assert!(
+                matches!(
+                    config.validate(),
+                    Err(Error::ConfigError(ConfigError::InvalidIpCidrPrefix(_, p))) if p == prefix
+                ),
+                "{ip} should have an invalid prefix"
+            );
+        }
+        config.ip_addr = Some("192.168.1.10".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::MissingIpCidrPrefix(_)))
+        ));
+        config.ip_addr = Some("192.168.1.300/24".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::InvalidIpAddress(_)))
+        ));
+    }
+
+    #[test]
+    fn test_validate_dns_server() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        config.dns_server = vec![
+            "1.1.1.1".to_string(),
+            "8.8.8.8".to_string(),
+            "9.9.9.9".to_string(),
+        ];
+        assert!(config.validate().is_ok());
+
+        config.dns_server.push("8.8.4.4".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::TooManyDnsServers(4)))
+        ));
+
+        config.dns_server = vec![
+            "1.1.1.1".to_string(),
+            "8.8.8.8".to_string(),
+            "8.8.8.8".to_string(),
+        ];
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::DuplicateDnsServer(d))) if d == "8.8.8.8"
+        ));
+        // Not a duplicate but not an address either, leading zeros are rejected
+        config.dns_server = vec!["1.1.1.1".to_string(), "1.1.1.01".to_string()];
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::InvalidIpAddress(_)))
+        ));
+
+        // A static address needs some way to resolve names
+        config.dns_server.clear();
+        config.ip_addr = Some("192.168.1.10/24".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::MissingResolver))
+        ));
+        config.gateway = Some("192.168.1.1".to_string());
+        assert!(config.validate().is_ok());
+        config.gateway = None;
+        config.dns_server = vec!["192.168.1.1".to_string()];
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_ranges() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        let check = |config: &EsparrierConfig, field: &str, valid: bool| {
+            let result = config.validate();
+            if valid {
+                assert!(result.is_ok(), "{field}: {result:?}");
+            } else {
+                assert!(
+                    matches!(
+                        &result,
+                        Err(Error::ConfigError(ConfigError::FieldOutOfRange(f, _, _))) if f == field
+                    ),
+                    "{field}: {result:?}"
+                );
+            }
+        };
+        for (value, valid) in [
+            (0, false),
+            (9, false),
+            (10, true),
+            (1000, true),
+            (1001, false),
+        ] {
+            config.polling_rate = value;
+            check(&config, "polling_rate", valid);
+        }
+        config.polling_rate = POLLING_RATE;
+        for (value, valid) in [(0, true), (3600, true), (3601, false)] {
+            config.jiggle_interval = value;
+            check(&config, "jiggle_interval", valid);
+        }
+        config.jiggle_interval = JIGGLE_INTERVAL;
+        for (value, valid) in [(4, false), (5, true), (300, true), (301, false)] {
+            config.watchdog_timeout = value;
+            check(&config, "watchdog_timeout", valid);
+        }
+        assert_eq!(
+            ConfigError::FieldOutOfRange("polling_rate".to_string(), 10, 1000).to_string(),
+            "Config field 'polling_rate' is out of range [10..1000]"
+        );
+    }
+
+    #[test]
+    fn test_validate_all() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        assert!(config.validate_all().is_ok());
+
+        config.brightness = 0;
+        assert!(matches!(
+            config.validate_all(),
+            Err(Error::ConfigError(ConfigError::FieldOutOfRange(f, 1, 100))) if f == "brightness"
+        ));
+
+        config.ssid.clear();
+        config.gateway = Some("gateway".to_string());
+        let Err(Error::ConfigError(ConfigError::Multiple(errors))) = config.validate_all() else {
+            panic!("expected several errors");
+        };
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                ConfigError::FieldEmpty(ssid),
+                ConfigError::FieldOutOfRange(brightness, 1, 100),
+                ConfigError::InvalidIpAddress(gateway),
+            ] if ssid == "ssid" && brightness == "brightness" && gateway == "gateway"
+        ));
+        // `validate` still stops at the first one
+        assert!(matches!(
+            config.validate(),
+            Err(Error::ConfigError(ConfigError::FieldEmpty(f))) if f == "ssid"
+        ));
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let old: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        assert!(old.diff(&old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.brightness = 50;
+        new.password = "another-secret".to_string();
+        new.ip_addr = Some("192.168.1.10/24".to_string());
+        new.dns_server = vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()];
+        // Fields skipped during serialization when default are compared too
+        new.polling_rate = 500;
+        let changes = old.diff(&new);
+        let changes = changes.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "password: ******** -> ********",
+                "polling_rate: 200 -> 500",
+                "brightness: 10 -> 50",
+                "ip_addr: (unset) -> 192.168.1.10/24",
+                "dns_server: [] -> [1.1.1.1, 8.8.8.8]",
+            ]
+        );
+        assert_eq!(old.diff_unredacted(&new)[0].new, "another-secret");
+
+        // Clearing optional fields
+        let changes = new.diff(&old);
+        assert!(changes.contains(&ConfigFieldChange {
+            field: "ip_addr".to_string(),
+            old: "192.168.1.10/24".to_string(),
+            new: "(unset)".to_string(),
+        }));
+        assert!(changes
+            .iter()
+            .any(|c| c.field == "dns_server" && c.new == "[]"));
+    }
+
+    #[test]
+    fn test_config_merge_json() {
+        use serde_json::json;
+
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        config.gateway = Some("192.168.2.1".to_string());
+        config.polling_rate = 500;
+
+        config
+            .merge_json(&json!({
+                "brightness": 50,
+                "server": "kvm.lan:24800",
+                "gateway": null,
+                "polling_rate": null,
+            }))
+            .unwrap();
+        assert_eq!(config.brightness, 50);
+        assert_eq!(config.server, "kvm.lan:24800");
+        assert_eq!(config.gateway, None);
+        assert_eq!(config.polling_rate, POLLING_RATE);
+        // Untouched
+        assert_eq!(config.ssid, "some-wifi");
+        assert_eq!(config.screen_width, 5120);
+
+        let before = config.clone();
+        assert!(matches!(
+            config.merge_json(&json!({"screen_hight": 1440})),
+            Err(Error::ConfigError(ConfigError::UnknownField(f))) if f == "screen_hight"
+        ));
+        let mut lenient = config.clone();
+        lenient
+            .merge_json_lenient(&json!({"future_option": true, "brightness": 50}))
+            .unwrap();
+        assert_eq!(lenient.extra.get("future_option"), Some(&json!(true)));
+        assert_eq!(lenient.brightness, 50);
+        assert!(matches!(
+            config.merge_json(&json!({"brightness": 0})),
+            Err(Error::ConfigError(ConfigError::FieldOutOfRange(..)))
+        ));
+        assert!(matches!(
+            config.merge_json(&json!({"brightness": "bright"})),
+            Err(Error::FormatError(_))
+        ));
+        assert!(matches!(
+            config.merge_json(&json!([1, 2])),
+            Err(Error::FormatError(_))
+        ));
+        assert_eq!(config, before);
+    }
+
+    #[test]
+    fn test_config_patch_from_assignments() {
+        use serde_json::json;
+
+        let patch = EsparrierConfig::patch_from_assignments([
+            "brightness=50",
+            "flip_wheel=true",
+            "server=192.168.2.59:24800",
+            "vid=0x1234",
+            "dns_server=1.1.1.1, 8.8.8.8",
+            "gateway=",
+            "password=",
+        ])
+        .unwrap();
+        assert_eq!(
+            patch,
+            json!({
+                "brightness": 50,
+                "flip_wheel": true,
+                "server": "192.168.2.59:24800",
+                "vid": 0x1234,
+                "dns_server": ["1.1.1.1", "8.8.8.8"],
+                "gateway": null,
+                "password": "",
+            })
+        );
+
+        assert!(matches!(
+            EsparrierConfig::patch_from_assignments(["brightnes=50"]),
+            Err(Error::ConfigError(ConfigError::UnknownFields(fields)))
+                if fields == [("brightnes".to_string(), Some("brightness"))]
+        ));
+        assert!(matches!(
+            EsparrierConfig::patch_from_assignments(["flip_wheel=yes"]),
+            Err(Error::FormatError(msg)) if msg.contains("expected true or false")
+        ));
+        assert!(matches!(
+            EsparrierConfig::patch_from_assignments(["brightness"]),
+            Err(Error::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_to_json_full() {
+        let mut config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        config.polling_rate = 500;
+        let full = config.to_json_full();
+        assert_eq!(full["polling_rate"], 500);
+        assert_eq!(full["vid"], USB_VID);
+        assert_eq!(full["watchdog_timeout"], WATCHDOG_TIMEOUT);
+        assert!(full["gateway"].is_null());
+        let names = EsparrierConfig::field_names();
+        assert_eq!(names.len(), full.as_object().unwrap().len());
+        assert!(names.iter().all(|name| full.get(name).is_some()));
+        let parsed: EsparrierConfig = serde_json::from_value(full).unwrap();
+        assert_eq!(parsed, config);
+
+        let defaults = config.default_fields();
+        assert!(defaults.contains(&"vid") && defaults.contains(&"jiggle_interval"));
+        assert!(!defaults.contains(&"polling_rate") && !defaults.contains(&"brightness"));
+        #[cfg(feature = "toml")]
+        {
+            let toml = config.to_toml_string_full().unwrap();
+            assert!(toml.contains("polling_rate = 500") && !toml.contains("gateway"));
+            assert_eq!(EsparrierConfig::from_toml_str(&toml).unwrap(), config);
+        }
+        assert_eq!(
+            EsparrierConfig::default().default_fields().len(),
+            EsparrierConfig::default().known_fields().len()
+        );
+    }
+
+    #[test]
+    fn test_config_default() {
+        let mut config = EsparrierConfig::default();
+        assert_eq!(config.screen_width, 1920);
+        assert_eq!(config.screen_height, 1080);
+        assert_eq!(config.brightness, BRIGHTNESS);
+        assert_eq!((config.vid, config.pid), (0x0d0a, 0xc0de));
+        // Serde fills missing fields with the same defaults
+        assert_eq!(config, serde_json::from_str("{}").unwrap());
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::json!({
+                "ssid": "",
+                "server": "",
+                "screen_name": "",
+                "screen_width": 1920,
+                "screen_height": 1080,
+                "flip_wheel": false,
+                "brightness": 30,
+            })
+        );
+
+        // Only the required strings are missing, the empty password is an open network
+        for (field, value) in [
+            ("ssid", "some-wifi"),
+            ("server", "192.168.1.250:24800"),
+            ("screen_name", "SAW"),
+        ] {
+            assert!(matches!(
+                config.validate(),
+                Err(Error::ConfigError(ConfigError::FieldEmpty(f))) if f == field
+            ));
+            match field {
+                "ssid" => config.ssid = value.to_string(),
+                "server" => config.server = value.to_string(),
+                _ => config.screen_name = value.to_string(),
+            }
+        }
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_from_json_strict() {
+        let config = EsparrierConfig::from_json_strict(test_config_json()).unwrap();
+        assert_eq!(config.screen_width, 5120);
+
+        let json = r#"{
             "ssid": "some-wifi",
-            "password": "magic-word",
-            "server": "192.168.2.59:24800",
+            "server": "kvm.lan:24800",
             "screen_name": "SAW",
-            "screen_width": 5120,
-            "screen_height": 2880,
-            "flip_wheel": true,
-            "brightness": 10,
-            "serial_number": "88888888",
-            "pid": 4
+            "screen_hight": 1440,
+            "brigthness": 50,
+            "colour": "red"
         }"#;
-        let config: EsparrierConfig = serde_json::from_str(config_str).unwrap();
-        println!("{config:?}");
+        let err = EsparrierConfig::from_json_strict(json).unwrap_err();
+        match &err {
+            Error::ConfigError(ConfigError::UnknownFields(fields)) => assert_eq!(
+                fields,
+                &[
+                    ("brigthness".to_string(), Some("brightness")),
+                    ("colour".to_string(), None),
+                    ("screen_hight".to_string(), Some("screen_height")),
+                ]
+            ),
+            e => panic!("unexpected error {e}"),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Unknown config fields: 'brigthness' (did you mean 'brightness'?), 'colour', \
+             'screen_hight' (did you mean 'screen_height'?)"
+        );
+        // The lenient parser keeps them
+        let config: EsparrierConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.extra.len(), 3);
+        assert!(EsparrierConfig::from_json_strict("[]").is_err());
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
     }
 
-    #[ignore = "This test needs device attached"]
-    #[tokio::test]
-    async fn test_get_state() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
-            .await
+    #[test]
+    fn test_import_client_settings() {
+        let imported = EsparrierConfig::from_client_settings(
+            "[General]\n\
+             ; InputLeap client\n\
+             screenName=laptop\n\
+             serverHostname=192.168.1.2\n\
+             port=24801\n",
+        )
+        .unwrap();
+        assert_eq!(imported.config.server, "192.168.1.2:24801");
+        assert_eq!(imported.config.screen_name, "laptop");
+        assert_eq!(imported.extracted, ["server", "screen_name"]);
+        assert_eq!(imported.missing(), ["screen_width", "screen_height"]);
+        assert_eq!(imported.config.ssid, "");
+
+        // Deskflow keeps the server in another group, the default port is implied
+        let imported = EsparrierConfig::from_client_settings(
+            "[core]\nscreenName=\"desk\"\n[client]\nremoteHost=kvm.lan\n",
+        )
+        .unwrap();
+        assert_eq!(imported.config.server, "kvm.lan:24800");
+        assert_eq!(imported.config.screen_name, "desk");
+
+        assert!(EsparrierConfig::from_client_settings("[General]\nfoo=bar\n").is_err());
+        assert!(EsparrierConfig::from_client_settings("screenWidth=wide\n").is_err());
+
+        let imported = EsparrierConfig::from_client_command_line(
+            "barrierc -f --debug INFO --name laptop 192.168.1.2:24800",
+        )
+        .unwrap();
+        assert_eq!(imported.config.server, "192.168.1.2:24800");
+        assert_eq!(imported.config.screen_name, "laptop");
+        let imported =
+            EsparrierConfig::from_client_command_line("input-leapc --name=desk").unwrap();
+        assert_eq!(imported.extracted, ["screen_name"]);
+    }
+
+    #[test]
+    fn test_screen_name() {
+        for name in ["SAW", "left_screen", "my-screen.2", "_", "a"] {
+            assert!(validate_screen_name(name).is_ok(), "{name}");
+        }
+        let invalid = |name: &str| match validate_screen_name(name) {
+            Err(ConfigError::InvalidScreenName {
+                character,
+                position,
+            }) => (character, position),
+            e => panic!("{name}: {e:?}"),
+        };
+        assert_eq!(invalid("my screen (left)"), (' ', 2));
+        assert_eq!(invalid(".hidden"), ('.', 0));
+        assert_eq!(invalid("screen-"), ('-', 6));
+        assert_eq!(invalid("-"), ('-', 0));
+        assert!(matches!(
+            validate_screen_name(""),
+            Err(ConfigError::FieldEmpty(_))
+        ));
+        assert!(matches!(
+            validate_screen_name(&"s".repeat(65)),
+            Err(ConfigError::FieldTooLong(_))
+        ));
+
+        assert_eq!(sanitize_screen_name("my screen (left)"), "my-screen-left");
+        assert_eq!(sanitize_screen_name("..hidden.."), "hidden");
+        assert_eq!(sanitize_screen_name("🖥️"), "screen");
+        assert_eq!(sanitize_screen_name(&"s-".repeat(40)).len(), 63);
+        for name in ["my screen (left)", "..hidden..", "🖥️", &"s-".repeat(40)] {
+            assert!(validate_screen_name(&sanitize_screen_name(name)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_wifi_password_and_server() {
+        for password in ["", "12345678", &"p".repeat(63), &"aB3".repeat(21)[..63]] {
+            assert!(validate_wifi_password(password).is_ok(), "{password}");
+        }
+        assert!(validate_wifi_password(&"0123456789abcdef".repeat(4)).is_ok());
+        assert!(matches!(
+            validate_wifi_password("1234567"),
+            Err(ConfigError::PasswordTooShort)
+        ));
+        assert!(matches!(
+            validate_wifi_password(&"g".repeat(64)),
+            Err(ConfigError::InvalidPsk)
+        ));
+        assert!(matches!(
+            validate_wifi_password(&"p".repeat(65)),
+            Err(ConfigError::FieldTooLong(_))
+        ));
+
+        assert!(validate_server_address("192.168.1.2:24800", (0, 8, 0)).is_ok());
+        assert!(validate_server_address("barrier.lan:24800", (0, 9, 0)).is_ok());
+        assert!(matches!(
+            validate_server_address("barrier.lan:24800", (0, 8, 0)),
+            Err(ConfigError::InvalidEndpoint(_))
+        ));
+        assert!(matches!(
+            validate_server_address("192.168.1.2", (0, 9, 0)),
+            Err(ConfigError::InvalidEndpoint(_))
+        ));
+        assert!(matches!(
+            validate_server_address("", (0, 9, 0)),
+            Err(ConfigError::FieldEmpty(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_env_expansion() {
+        let lookup = |name: &str| (name == "WIFI_PASSWORD").then(|| "from-env-$".to_string());
+        let mut config = EsparrierConfig {
+            ssid: "${WIFI_SSID:-home}".to_string(),
+            password: "${WIFI_PASSWORD}".to_string(),
+            server: "$${literal}".to_string(),
+            screen_name: "pa$$word".to_string(),
+            dns_server: vec!["${DNS:-1.1.1.1}".to_string()],
+            ..Default::default()
+        };
+        config.expand_env_with(lookup).unwrap();
+        assert_eq!(config.ssid, "home");
+        assert_eq!(config.password, "from-env-$");
+        assert_eq!(config.server, "${literal}");
+        assert_eq!(config.screen_name, "pa$$word");
+        assert_eq!(config.dns_server, ["1.1.1.1"]);
+
+        let mut config = EsparrierConfig {
+            password: "${ESPARRIER_TEST_UNSET}".to_string(),
+            ..Default::default()
+        };
+        let result = config.expand_env_with(lookup);
+        assert!(matches!(
+            result,
+            Err(Error::ConfigError(ConfigError::MissingEnvVar(name))) if name == "ESPARRIER_TEST_UNSET"
+        ));
+        assert_eq!(config.password, "${ESPARRIER_TEST_UNSET}");
+        config.password = "${UNTERMINATED".to_string();
+        assert!(matches!(
+            config.expand_env_with(lookup),
+            Err(Error::FormatError(_))
+        ));
+
+        let config = EsparrierConfig::from_json_with_env(
+            r#"{"ssid": "${ESPARRIER_TEST_UNSET:-office}", "server": "kvm.lan:24800", "screen_name": "S"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.ssid, "office");
+    }
+
+    #[test]
+    fn test_config_preserves_unknown_fields() {
+        let mut value: serde_json::Value = serde_json::from_str(test_config_json()).unwrap();
+        value["future_option"] = serde_json::json!(true);
+        let mut config: EsparrierConfig = serde_json::from_value(value).unwrap();
+        assert_eq!(config.extra["future_option"], true);
+
+        config.brightness = 42;
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["future_option"], true);
+        assert_eq!(json["brightness"], 42);
+
+        let mut changed = config.clone();
+        changed
+            .extra
+            .insert("future_option".to_string(), false.into());
+        changed.extra.insert("another_option".to_string(), 3.into());
+        assert_eq!(
+            config
+                .diff(&changed)
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "future_option: true -> false",
+                "another_option: (unset) -> 3"
+            ]
+        );
+
+        config
+            .merge_json(&serde_json::json!({"future_option": null}))
             .unwrap();
-        let state = esparrier.get_state().await;
-        println!("{state:?}");
+        assert!(config.extra.is_empty());
+    }
+
+    #[test]
+    fn test_config_redacted() {
+        let config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        let redacted = config.redacted();
+        assert_eq!(redacted.password, REDACTED);
+        assert_eq!(redacted.ssid, config.ssid);
+        assert!(!format!("{config:?}").contains("magic-word"));
+        assert!(format!("{config:?}").contains(REDACTED));
+
+        // An empty password stays empty so it's still skipped when serialized
+        let mut open = config.clone();
+        open.password.clear();
+        assert!(open.redacted().password.is_empty());
+        assert!(serde_json::to_value(open.redacted())
+            .unwrap()
+            .get("password")
+            .is_none());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_config_toml() {
+        let config = EsparrierConfig::from_toml_str(
+            r#"
+            ssid = "some-wifi"
+            password = "magic-word"
+            server = "192.168.2.59:24800"
+            screen_name = "SAW"
+            flip_wheel = true
+            ip_addr = "192.168.2.100/24"
+            dns_server = ["192.168.2.1", "8.8.8.8"]
+            pid = 4
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.screen_width, get_default_screen_width());
+        assert_eq!(config.ip_addr.as_deref(), Some("192.168.2.100/24"));
+        assert_eq!(config.dns_server, vec!["192.168.2.1", "8.8.8.8"]);
+        assert_eq!(config.gateway, None);
+        assert_eq!(config.pid, 4);
+        let toml = config.to_toml_string().unwrap();
+        assert!(!toml.contains("gateway"));
+        assert_eq!(EsparrierConfig::from_toml_str(&toml).unwrap(), config);
+
+        // Unset optional fields are omitted and stay unset
+        let config: EsparrierConfig = serde_json::from_str(test_config_json()).unwrap();
+        let toml = config.to_toml_string().unwrap();
+        assert!(!toml.contains("ip_addr"));
+        assert!(!toml.contains("dns_server"));
+        assert_eq!(EsparrierConfig::from_toml_str(&toml).unwrap(), config);
+
+        // The position of the error is reported
+        assert!(matches!(
+            EsparrierConfig::from_toml_str("ssid = \"x\"\nserver = "),
+            Err(Error::FormatError(msg)) if msg.contains("line 2")
+        ));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_config_yaml() {
+        let config = EsparrierConfig::from_yaml_str(
+            r#"
+            ssid: some-wifi
+            password: magic-word
+            server: "192.168.2.59:24800"
+            screen_name: SAW
+            ip_addr: 192.168.2.100/24
+            dns_server:
+              - 192.168.2.1
+              - 8.8.8.8
+            vid: 0x0d0a
+            pid: 0xc0de
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.vid, 0x0d0a);
+        assert_eq!(config.pid, 0xc0de);
+        assert_eq!(config.dns_server, vec!["192.168.2.1", "8.8.8.8"]);
+        assert_eq!(config.gateway, None);
+        let yaml = config.to_yaml_string().unwrap();
+        assert!(!yaml.contains("gateway"));
+        assert_eq!(EsparrierConfig::from_yaml_str(&yaml).unwrap(), config);
+
+        assert!(matches!(
+            EsparrierConfig::from_yaml_str("ssid: x\nvid: [1"),
+            Err(Error::FormatError(msg)) if msg.contains("line 2")
+        ));
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_config_json_schema() {
+        let schema = EsparrierConfig::json_schema();
+        assert_eq!(
+            schema["required"],
+            serde_json::json!(["ssid", "server", "screen_name"])
+        );
+        let properties = &schema["properties"];
+        assert_eq!(properties["ssid"]["maxLength"], 32);
+        assert!(properties["ssid"].get("default").is_none());
+        assert_eq!(properties["brightness"]["minimum"], 1);
+        assert_eq!(properties["brightness"]["maximum"], 100);
+        assert_eq!(properties["brightness"]["default"], BRIGHTNESS);
+        assert_eq!(properties["screen_width"]["maximum"], 32767);
+        assert_eq!(properties["screen_height"]["minimum"], 1);
+        assert_eq!(properties["vid"]["default"], USB_VID);
+        assert_eq!(properties["ip_addr"]["pattern"], IP_CIDR_PATTERN);
+        assert_eq!(properties["dns_server"]["items"]["format"], "ipv4");
+        assert_eq!(properties["dns_server"]["maxItems"], MAX_DNS_SERVERS);
     }
 
-    #[ignore = "This test needs device attached"]
     #[tokio::test]
-    async fn test_get_config() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
+    async fn test_mock_get_config_full() {
+        let mock = mock::MockEsparrier::default();
+        let mut config = mock.config();
+        config.password = "secret-password".to_string();
+        Esparrier::new(mock.clone())
+            .set_config(config.clone())
             .await
             .unwrap();
-        let config = esparrier.get_config().await.unwrap();
-        println!("{config:?}");
+
+        // 0.9.1 only returns the redacted config
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(matches!(
+            esparrier.get_config_full().await,
+            Err(Error::NotSupported {
+                command: Command::FullConfig,
+                ..
+            })
+        ));
+
+        let mut state = mock.state();
+        (
+            state.version_major,
+            state.version_minor,
+            state.version_patch,
+        ) = FULL_CONFIG_MIN_VERSION;
+        mock.set_state(state);
+        let esparrier = Esparrier::new(mock.clone());
+        assert_eq!(esparrier.get_config_full().await.unwrap(), config);
+        assert_eq!(esparrier.get_config().await.unwrap().password, "");
     }
 
-    #[ignore = "This test needs device attached"]
     #[tokio::test]
-    async fn test_set_config() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
+    async fn test_mock_backup_restore() {
+        let mock = mock::MockEsparrier::default();
+        let original = mock.config();
+
+        // 0.9.1 doesn't return the password, it can't be restored then
+        let backup = Esparrier::new(mock.clone()).backup().await.unwrap();
+        assert!(!backup.password_included);
+        assert_eq!(backup.config.password, REDACTED);
+        assert_eq!(backup.model_id, Some(mock.state().model_id));
+        assert_eq!(backup.version, Some(mock.state().version()));
+        let json = serde_json::to_string(&backup).unwrap();
+        assert_eq!(ConfigBackup::from_json(&json).unwrap(), backup);
+        assert!(matches!(
+            Esparrier::new(mock.clone()).restore(&backup).await,
+            Err(Error::ConfigError(ConfigError::PasswordRequired))
+        ));
+        assert_eq!(mock.commits(), 0);
+
+        let mut state = mock.state();
+        (
+            state.version_major,
+            state.version_minor,
+            state.version_patch,
+        ) = FULL_CONFIG_MIN_VERSION;
+        mock.set_state(state);
+        let full = Esparrier::new(mock.clone()).backup().await.unwrap();
+        assert!(full.password_included);
+        assert_eq!(full.config, original);
+
+        let mut changed = original.clone();
+        changed.screen_name = "CHANGED".to_string();
+        changed.brightness = 10;
+        changed.password = "changed-password".to_string();
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.set_config(changed.clone()).await.unwrap();
+        esparrier.commit_config().await.unwrap();
+
+        let json = serde_json::to_string(&full).unwrap();
+        let restored = ConfigBackup::from_json(&json).unwrap();
+        Esparrier::new(mock.clone())
+            .restore(&restored)
             .await
             .unwrap();
-        let config = serde_json::from_str(
-            r#"{
-            "ssid": "some-wifi",
-            "password": "magic-word",
-            "server": "192.168.2.59:24800",
-            "screen_name": "SAW",
-            "screen_width": 5120,
-            "screen_height": 2880,
-            "flip_wheel": true,
-            "brightness": 10,
-            "serial_number": "88888888",
-            "pid": 4
-        }"#,
-        )
-        .unwrap();
-        println!("{}", serde_json::to_string_pretty(&config).unwrap());
-        esparrier.set_config(config).await.unwrap();
+        assert_eq!(mock.config(), original);
+
+        // The redacted backup keeps the current password
+        let esparrier = Esparrier::new(mock.clone());
+        esparrier.set_config(changed).await.unwrap();
+        esparrier.commit_config().await.unwrap();
+        Esparrier::new(mock.clone()).restore(&backup).await.unwrap();
+        assert_eq!(mock.config().password, "changed-password");
+        assert_eq!(mock.config().redacted(), original.redacted());
+
+        // Older backups are the full configuration
+        let json = serde_json::to_string(&original).unwrap();
+        let old = ConfigBackup::from_json(&json).unwrap();
+        assert!(old.password_included);
+        assert_eq!(old.config, original);
+        assert_eq!(old.model_id, None);
     }
 
-    #[ignore = "This test needs device attached"]
     #[tokio::test]
-    async fn test_set_config_1() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
-            .await
-            .unwrap();
-        let mut config = esparrier.get_config().await.unwrap();
-        config.ssid = "test".to_string();
-        esparrier.set_config(config).await.unwrap();
+    async fn test_mock_state_stream() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        let mut changes = std::pin::pin!(esparrier.state_stream(Duration::from_millis(10)));
+
+        let first = changes.next().await.unwrap().unwrap();
+        assert_eq!(first.changed, StateField::ALL);
+
+        // Unchanged samples are skipped, errors don't stop polling
+        mock.fail_sends(2, 1);
+        assert!(matches!(
+            changes.next().await,
+            Some(Err(Error::TransferFailed(_)))
+        ));
+        let mut state = mock.state();
+        state.server_connected = !state.server_connected;
+        state.ip_prefix = 16;
+        mock.set_state(state);
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(
+            change.changed,
+            [StateField::ServerConnected, StateField::IpAddress]
+        );
+        assert!(!change.has_changed(StateField::Active));
+        assert_eq!(change.state.ip_prefix, 16);
     }
 
-    #[ignore = "This will reset the device"]
     #[tokio::test]
-    async fn test_commit_config() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
+    async fn test_mock_patch_config() {
+        use serde_json::json;
+
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(matches!(
+            esparrier.patch_config(&json!({"brightness": 50})).await,
+            Err(Error::ConfigError(ConfigError::PasswordRequired))
+        ));
+        let config = esparrier
+            .patch_config(&json!({"brightness": 50, "password": "mock-password"}))
             .await
             .unwrap();
-        let mut config = esparrier.get_config().await.unwrap();
-        config.ssid = "test".to_string();
-        esparrier.set_config(config).await.unwrap();
-        esparrier.commit_config().await.unwrap();
+        assert_eq!(config.brightness, 50);
+        let pending = mock.pending_config().unwrap();
+        assert_eq!(pending, config);
+        assert_eq!(pending.ssid, "mock-wifi");
+
+        let mut config = pending.clone();
+        config.screen_name = "VERIFIED".to_string();
+        esparrier.set_config_verified(config.clone()).await.unwrap();
+        assert_eq!(mock.pending_config().unwrap(), config);
+
+        // Firmware reading back the committed configuration can't verify it
+        mock.read_committed_config(true);
+        config.screen_name = "UNVERIFIED".to_string();
+        esparrier.set_config_verified(config.clone()).await.unwrap();
+        assert_eq!(mock.pending_config().unwrap(), config);
+        // The password is never compared
+        config.password = "other-password".to_string();
+        esparrier.set_config_verified(config).await.unwrap();
+    }
+
+    #[test]
+    fn test_device_filter() {
+        let device = DeviceSummary {
+            bus_id: "3".to_string(),
+            address: 5,
+            vendor_id: USB_VID,
+            product_id: USB_PID,
+            manufacturer: None,
+            product: None,
+            serial_number: Some("ABCD1234".to_string()),
+            model_id: None,
+            version: None,
+            ip_address: None,
+            probe_error: None,
+        };
+        assert!(DeviceFilter::default().matches(&device));
+        let filter = |serial: &str| DeviceFilter {
+            serial: Some(serial.to_string()),
+            ..Default::default()
+        };
+        assert!(filter("abcd1234").matches(&device));
+        assert!(!filter("abcd").matches(&device));
+        let no_serial = DeviceSummary {
+            serial_number: None,
+            ..device.clone()
+        };
+        assert!(!filter("abcd1234").matches(&no_serial));
+        let filter = DeviceFilter {
+            vid: Some(USB_VID),
+            bus: Some("03".to_string()),
+            address: Some(5),
+            ..Default::default()
+        };
+        assert!(filter.matches(&device));
+        let filter = DeviceFilter {
+            pid: Some(0x1234),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&device));
+    }
+
+    #[test]
+    fn test_compare_bus_ids() {
+        let mut ids = vec!["10", "3", "1-2", "001", "1-10"];
+        ids.sort_by(|a, b| compare_bus_ids(a, b));
+        assert_eq!(ids, vec!["001", "3", "10", "1-10", "1-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_track() {
+        use watch::{track, RawEvent};
+
+        let device = |address: u8, product_id: u16| DeviceSummary {
+            bus_id: "1".to_string(),
+            address,
+            vendor_id: USB_VID,
+            product_id,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            model_id: None,
+            version: None,
+            ip_address: None,
+            probe_error: None,
+        };
+        let filter = DeviceFilter {
+            vid: Some(USB_VID),
+            pid: Some(USB_PID),
+            ..Default::default()
+        };
+        let initial = vec![RawEvent::Connected(1, device(1, USB_PID), ())];
+        let events = futures::stream::iter([
+            // Reported by both the initial listing and the watcher
+            RawEvent::Connected(1, device(1, USB_PID), ()),
+            // Not an Esparrier
+            RawEvent::Connected(2, device(2, 0x1234), ()),
+            RawEvent::Disconnected(2),
+            RawEvent::Connected(3, device(3, USB_PID), ()),
+            RawEvent::Disconnected(1),
+            // Already gone
+            RawEvent::Disconnected(1),
+        ]);
+        let events = track(filter, initial, events)
+            .map(|(event, _)| match event {
+                EsparrierEvent::Connected(d) => format!("+{}", d.address),
+                EsparrierEvent::Opened(d, _) => format!("*{}", d.address),
+                EsparrierEvent::Disconnected { bus, address } => format!("-{bus}/{address}"),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events, vec!["+1", "+3", "-1/1"]);
+    }
+
+    /// A backend with a fixed list of devices, opening them fails with the
+    /// given error or connects to a mock.
+    struct FakeBackend(Vec<FakeDevice>);
+
+    struct FakeDevice {
+        summary: DeviceSummary,
+        vendor_interface: bool,
+        open_error: Option<fn() -> Error>,
+    }
+
+    impl UsbBackend for FakeBackend {
+        type Device = usize;
+        type Transport = mock::MockEsparrier;
+
+        async fn list_devices(&self) -> Result<Vec<usize>, Error> {
+            Ok((0..self.0.len()).collect())
+        }
+
+        fn summary(&self, device: &usize) -> DeviceSummary {
+            self.0[*device].summary.clone()
+        }
+
+        fn has_vendor_interface(&self, device: &usize) -> bool {
+            self.0[*device].vendor_interface
+        }
+
+        async fn open(&self, device: usize) -> Result<mock::MockEsparrier, Error> {
+            match self.0[device].open_error {
+                Some(error) => Err(error()),
+                None => Ok(mock::MockEsparrier::default()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_backend() {
+        let device = |address: u8, vendor_id: u16, vendor_interface, open_error| FakeDevice {
+            summary: DeviceSummary {
+                bus_id: "1".to_string(),
+                address,
+                vendor_id,
+                product_id: USB_PID,
+                manufacturer: None,
+                product: None,
+                serial_number: Some(format!("SERIAL{address}")),
+                model_id: None,
+                version: None,
+                ip_address: None,
+                probe_error: None,
+            },
+            vendor_interface,
+            open_error,
+        };
+        let backend = FakeBackend(vec![
+            // Custom VID, found by the vendor interface
+            device(1, 0x1234, true, None),
+            device(2, USB_VID, true, Some(|| Error::PermissionDenied)),
+            // Not an Esparrier
+            device(3, 0x1234, false, None),
+            device(4, USB_VID, false, Some(|| Error::DeviceBusy)),
+        ]);
+        let open = |serial: Option<&str>, allow_ambiguous: bool| {
+            let filter = DeviceFilter {
+                serial: serial.map(|s| s.to_string()),
+                ..Default::default()
+            };
+            let options = OpenOptions {
+                allow_ambiguous,
+                ..Default::default()
+            };
+            let backend = &backend;
+            async move { Esparrier::with_backend(backend, &filter, &options).await }
+        };
+
+        let esparrier = open(Some("serial1"), false).await.unwrap();
+        assert_eq!(esparrier.get_state().await.unwrap().model_id, 2);
+        assert!(matches!(
+            open(None, false).await,
+            Err(Error::AmbiguousDevice(devices)) if devices.len() == 3
+        ));
+        assert!(open(None, true).await.is_ok());
+        assert!(matches!(
+            open(Some("SERIAL3"), false).await,
+            Err(Error::DeviceNotFound)
+        ));
+        // The permission problem is reported rather than the busy device
+        let backend = FakeBackend(backend.0.into_iter().skip(1).collect());
+        let filter = DeviceFilter::default();
+        let options = OpenOptions {
+            allow_ambiguous: true,
+            ..Default::default()
+        };
+        match Esparrier::with_backend(&backend, &filter, &options).await {
+            Err(Error::OpenFailed { device, source }) => {
+                assert_eq!(device.address, 2);
+                assert!(matches!(*source, Error::PermissionDenied));
+            }
+            _ => panic!("expected OpenFailed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_backend_wait() {
+        let device = |open_error| FakeDevice {
+            summary: DeviceSummary {
+                bus_id: "1".to_string(),
+                address: 1,
+                vendor_id: USB_VID,
+                product_id: USB_PID,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+                model_id: None,
+                version: None,
+                ip_address: None,
+                probe_error: None,
+            },
+            vendor_interface: true,
+            open_error,
+        };
+        let filter = DeviceFilter::default();
+        let options = OpenOptions {
+            wait: true,
+            timeout: Some(Duration::from_millis(1200)),
+            ..Default::default()
+        };
+        let open = |backend: FakeBackend| {
+            let (filter, options) = (&filter, &options);
+            async move { Esparrier::with_backend(&backend, filter, options).await }
+        };
+
+        assert!(open(FakeBackend(vec![device(None)])).await.is_ok());
+        // Missing and busy devices are waited for
+        assert!(matches!(
+            open(FakeBackend(vec![])).await,
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            open(FakeBackend(vec![device(Some(|| Error::DeviceBusy))])).await,
+            Err(Error::Timeout)
+        ));
+        // Other failures don't go away by waiting
+        assert!(matches!(
+            open(FakeBackend(vec![device(Some(|| Error::PermissionDenied))])).await,
+            Err(Error::OpenFailed { source, .. }) if matches!(*source, Error::PermissionDenied)
+        ));
     }
 
-    #[ignore = "This will reset the device"]
     #[tokio::test]
-    async fn test_reboot() {
-        let esparrier = Esparrier::auto_detect(false, None, None, None, None)
+    async fn test_diagnose() {
+        let device = |address: u8, vendor_interface, open_error| FakeDevice {
+            summary: DeviceSummary {
+                bus_id: "1".to_string(),
+                address,
+                vendor_id: USB_VID,
+                product_id: USB_PID,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+                model_id: None,
+                version: None,
+                ip_address: None,
+                probe_error: None,
+            },
+            vendor_interface,
+            open_error,
+        };
+        let backend = FakeBackend(vec![
+            device(5, true, Some(|| Error::Timeout)),
+            device(1, true, None),
+            device(2, true, Some(|| Error::PermissionDenied)),
+            device(3, true, Some(|| Error::DeviceBusy)),
+            device(4, false, None),
+        ]);
+        let diagnoses = doctor::diagnose_with(&backend, None, None, |_| None)
             .await
             .unwrap();
-        esparrier.reboot_device().await.unwrap();
+        let health = diagnoses
+            .iter()
+            .map(|d| (d.device.address, d.health.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            health,
+            vec![
+                (1, DeviceHealth::Ok { version: (0, 9, 1) }),
+                (2, DeviceHealth::PermissionDenied),
+                (3, DeviceHealth::Busy),
+                (4, DeviceHealth::NoVendorInterface),
+                (
+                    5,
+                    DeviceHealth::OpenFailed {
+                        error: Error::Timeout.to_string()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            diagnoses
+                .iter()
+                .filter(|d| d.is_usable())
+                .map(|d| d.device.address)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_open_permission_denied() {
+        use transport::is_permission_denied;
+
+        assert!(is_permission_denied(
+            nusb::ErrorKind::PermissionDenied,
+            None
+        ));
+        // A missing udev rule, nusb reports EACCES as `Other`
+        assert_eq!(
+            is_permission_denied(nusb::ErrorKind::Other, Some(13)),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            is_permission_denied(nusb::ErrorKind::Other, Some(1)),
+            cfg!(target_os = "linux")
+        );
+        assert!(!is_permission_denied(nusb::ErrorKind::Other, Some(2)));
+        assert!(!is_permission_denied(nusb::ErrorKind::Busy, Some(16)));
+        assert!(!is_permission_denied(nusb::ErrorKind::Other, None));
+    }
+
+    #[test]
+    fn test_device_summary_display() {
+        let mut summary = DeviceSummary {
+            bus_id: "1".to_string(),
+            address: 5,
+            vendor_id: USB_VID,
+            product_id: USB_PID,
+            manufacturer: Some(USB_MANUFACTURER.to_string()),
+            product: Some(USB_PRODUCT.to_string()),
+            serial_number: None,
+            model_id: None,
+            version: None,
+            ip_address: None,
+            probe_error: None,
+        };
+        assert_eq!(summary.to_string(), "Bus: 1, Address: 5");
+        let mut busy = summary.clone();
+        busy.set_probe_result(Err(Error::DeviceBusy));
+        assert_eq!(busy.to_string(), "Bus: 1, Address: 5, Status: busy");
+        summary.serial_number = Some(USB_SERIAL_NUMBER.to_string());
+        let mut state = EsparrierState::try_from_bytes(&STATE_RESPONSE).unwrap();
+        state.model_id = 2;
+        summary.set_probe_result(Ok(state));
+        assert_eq!(summary.model_name(), Some("m5atoms3"));
+        assert_eq!(
+            summary.to_string(),
+            "Bus: 1, Address: 5, Serial: 88888888, Model: m5atoms3, Firmware: 0.9.1, IP: 192.168.1.123"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_apply_config_to_all() {
+        let mocks = (0..6)
+            .map(|_| mock::MockEsparrier::default())
+            .collect::<Vec<_>>();
+        // This one never answers
+        mocks[2].drop_responses(usize::MAX);
+        let mut devices = mocks
+            .iter()
+            .map(|m| Esparrier::new(m.clone()))
+            .collect::<Vec<_>>();
+        devices[2].set_timeout(Duration::from_millis(50));
+
+        let mut config = mocks[0].config();
+        config.screen_name = "FLEET".to_string();
+        let results = Esparrier::apply_config_to_all(devices, &config, true).await;
+        assert_eq!(results.len(), 6);
+        for (idx, (mock, result)) in mocks.iter().zip(results).enumerate() {
+            if idx == 2 {
+                assert!(matches!(result, Err(Error::Timeout)));
+                assert_eq!(mock.commits(), 0);
+            } else {
+                result.unwrap();
+                assert_eq!(mock.commits(), 1);
+                assert_eq!(mock.config().screen_name, "FLEET");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_toggle_keep_awake() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = Esparrier::new(mock.clone());
+        assert!(!esparrier.keep_awake_status().await.unwrap());
+        assert!(esparrier.toggle_keep_awake().await.unwrap());
+        assert!(mock.state().keep_awake);
+        assert!(esparrier.keep_awake_status().await.unwrap());
+        assert!(!esparrier.toggle_keep_awake().await.unwrap());
+        assert!(!mock.state().keep_awake);
+    }
+
+    #[tokio::test]
+    async fn test_mock_commit_and_reconnect() {
+        let identity = |serial: &str, port_chain: &[u8]| DeviceIdentity {
+            vendor_id: USB_VID,
+            product_id: USB_PID,
+            serial_number: Some(serial.to_string()),
+            bus_id: "1".to_string(),
+            address: 5,
+            port_chain: port_chain.to_vec(),
+            manufacturer: None,
+            product: None,
+        };
+        let this = identity("11111111", &[]);
+        assert_eq!(this.to_string(), "0d0a:c0de @ bus 1 addr 5 (SN 11111111)");
+
+        let mock = mock::MockEsparrier::default();
+        Esparrier::new(mock.clone()).commit_config().await.unwrap();
+        assert_eq!(mock.commits(), 1);
+
+        // Another Esparrier shows up first, then the one that restarted
+        let other = mock::MockEsparrier::default();
+        let same = mock::MockEsparrier::default();
+        let candidates = futures::stream::iter([
+            (identity("22222222", &[]), other.clone()),
+            (identity("11111111", &[]), same.clone()),
+        ]);
+        let esparrier = reconnect(&this, candidates, Duration::from_secs(1), |m| async {
+            Ok(m)
+        })
+        .await
+        .unwrap();
+        esparrier.keep_awake(true).await.unwrap();
+        assert!(same.state().keep_awake);
+        assert!(!other.state().keep_awake);
+
+        // The physical port wins over the serial number when both are known
+        let this = identity("88888888", &[1, 2]);
+        assert!(this.is_same_device(&identity("12345678", &[1, 2])));
+        assert!(!this.is_same_device(&identity("88888888", &[1, 3])));
+
+        // Never shows up
+        let candidates = futures::stream::iter([(identity("22222222", &[]), other)])
+            .chain(futures::stream::pending());
+        let result = reconnect(
+            &identity("11111111", &[]),
+            candidates,
+            Duration::from_millis(50),
+            |m| async { Ok(m) },
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_mock() {
+        let mock = mock::MockEsparrier::default();
+        let esparrier = blocking::Esparrier::new(mock.clone()).unwrap();
+        assert_eq!(esparrier.get_state().unwrap().version(), (0, 9, 1));
+        let mut config = esparrier.get_config().unwrap();
+        config.screen_name = "BLOCKING".to_string();
+        config.password = "mock-password".to_string();
+        esparrier.set_config(config).unwrap();
+        esparrier.commit_config().unwrap();
+        assert_eq!(mock.config().screen_name, "BLOCKING");
     }
 }
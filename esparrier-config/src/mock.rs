@@ -0,0 +1,623 @@
+//! An emulated Esparrier device for testing without hardware.
+//!
+//! [`MockEsparrier`] implements the firmware side of the vendor protocol and can
+//! be plugged into [`Esparrier::new`](crate::Esparrier::new) as a transport:
+//!
+//! ```
+//! use esparrier_config::{mock::MockEsparrier, Esparrier};
+//!
+//! # futures::executor::block_on(async {
+//! let mock = MockEsparrier::default();
+//! let esparrier = Esparrier::new(mock.clone());
+//! esparrier.keep_awake(true).await.unwrap();
+//! assert!(mock.state().keep_awake);
+//! # });
+//! ```
+
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use nusb::transfer::TransferError;
+
+use crate::{
+    crc32_ieee, model_display_size, Error, EsparrierConfig, EsparrierState, FeatureFlags,
+    Transport, CLIPBOARD_MAX_SIZE,
+};
+
+/// Failures the mock can be told to simulate during OTA.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockOtaFailure {
+    /// The OtaStart command fails because there is no OTA partition.
+    PartitionNotFound,
+    /// Writing the chunk with the given index (0-based) to flash fails.
+    WriteFailed(usize),
+    /// Writing the chunk with the given index fails once, but the session
+    /// stays open so the chunk can be sent again.
+    FlakyWrite(usize),
+    /// The device stops responding when it receives the chunk with the given index.
+    Stall(usize),
+    /// All data is received but the checksum doesn't match.
+    CrcMismatch,
+}
+
+/// What the emulated firmware expects to receive next.
+enum Incoming {
+    Command,
+    ConfigBlocks {
+        remaining: usize,
+        data: Vec<u8>,
+    },
+    OtaPackets {
+        remaining: usize,
+        length: usize,
+        data: Vec<u8>,
+    },
+    ClipboardBlocks {
+        length: usize,
+        data: Vec<u8>,
+    },
+    ImageBlocks {
+        length: usize,
+        data: Vec<u8>,
+    },
+}
+
+struct OtaSession {
+    total: usize,
+    crc: u32,
+    data: Vec<u8>,
+    chunks: usize,
+}
+
+struct MockDevice {
+    state: EsparrierState,
+    config: EsparrierConfig,
+    pending_config: Option<EsparrierConfig>,
+    read_committed_config: bool,
+    incoming: Incoming,
+    ota: Option<OtaSession>,
+    ota_failure: Option<MockOtaFailure>,
+    ota_image: Option<Vec<u8>>,
+    clipboard: Vec<u8>,
+    brightness: Option<u8>,
+    led_color: Option<(u8, u8, u8)>,
+    splash: Option<Vec<u8>>,
+    logs: String,
+    extended_flags: Option<u32>,
+    commits: usize,
+    reboots: usize,
+    truncate_responses: Option<usize>,
+    drop_responses: usize,
+    fail_sends: Option<(usize, usize)>,
+    received: Vec<Vec<u8>>,
+}
+
+/// An emulated Esparrier device implementing the firmware side of the protocol.
+///
+/// Clones share the same device, so a test can keep one clone for inspection
+/// and hand another to [`Esparrier::new`](crate::Esparrier::new).
+#[derive(Clone)]
+pub struct MockEsparrier {
+    device: Arc<Mutex<MockDevice>>,
+    tx: UnboundedSender<Vec<u8>>,
+    rx: Arc<futures::lock::Mutex<UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl Default for MockEsparrier {
+    fn default() -> Self {
+        Self::new(
+            EsparrierState {
+                version_major: 0,
+                version_minor: 9,
+                version_patch: 1,
+                feature_flags: FeatureFlags::from_bits(0b1100_0010),
+                ip_address: Ipv4Addr::new(192, 168, 1, 123),
+                ip_prefix: 24,
+                server_connected: true,
+                active: false,
+                keep_awake: false,
+                model_id: 2,
+                extra: Vec::new(),
+                extended_flags: None,
+            },
+            EsparrierConfig {
+                ssid: "mock-wifi".to_string(),
+                password: "mock-password".to_string(),
+                server: "192.168.1.250:24800".to_string(),
+                screen_name: "MOCK".to_string(),
+                ..Default::default()
+            },
+        )
+    }
+}
+
+impl MockEsparrier {
+    /// Create a device reporting `state` and holding `config` as its stored configuration.
+    pub fn new(state: EsparrierState, config: EsparrierConfig) -> Self {
+        let (tx, rx) = unbounded();
+        Self {
+            device: Arc::new(Mutex::new(MockDevice {
+                state,
+                config,
+                pending_config: None,
+                read_committed_config: false,
+                incoming: Incoming::Command,
+                ota: None,
+                ota_failure: None,
+                ota_image: None,
+                clipboard: Vec::new(),
+                brightness: None,
+                led_color: None,
+                splash: None,
+                logs: String::new(),
+                extended_flags: None,
+                commits: 0,
+                reboots: 0,
+                truncate_responses: None,
+                drop_responses: 0,
+                fail_sends: None,
+                received: Vec::new(),
+            })),
+            tx,
+            rx: Arc::new(futures::lock::Mutex::new(rx)),
+        }
+    }
+
+    /// The state the device currently reports.
+    pub fn state(&self) -> EsparrierState {
+        self.device.lock().unwrap().state.clone()
+    }
+
+    /// Replace the state the device reports.
+    pub fn set_state(&self, state: EsparrierState) {
+        self.device.lock().unwrap().state = state;
+    }
+
+    /// The stored (committed) configuration, including the password.
+    pub fn config(&self) -> EsparrierConfig {
+        self.device.lock().unwrap().config.clone()
+    }
+
+    /// The configuration written with `w` but not committed yet.
+    pub fn pending_config(&self) -> Option<EsparrierConfig> {
+        self.device.lock().unwrap().pending_config.clone()
+    }
+
+    /// Read back the committed configuration with `r` and `R` until the
+    /// commit, instead of the uploaded one.
+    pub fn read_committed_config(&self, committed: bool) {
+        self.device.lock().unwrap().read_committed_config = committed;
+    }
+
+    /// Number of `c` (CommitConfig) commands received.
+    pub fn commits(&self) -> usize {
+        self.device.lock().unwrap().commits
+    }
+
+    /// Number of `b` (Reboot) commands received.
+    pub fn reboots(&self) -> usize {
+        self.device.lock().unwrap().reboots
+    }
+
+    /// The firmware image of the last successfully completed OTA.
+    pub fn ota_image(&self) -> Option<Vec<u8>> {
+        self.device.lock().unwrap().ota_image.clone()
+    }
+
+    /// The clipboard content of the device.
+    pub fn clipboard(&self) -> Vec<u8> {
+        self.device.lock().unwrap().clipboard.clone()
+    }
+
+    /// Replace the clipboard content, as if received from the server.
+    pub fn set_clipboard(&self, data: impl Into<Vec<u8>>) {
+        self.device.lock().unwrap().clipboard = data.into();
+    }
+
+    /// The brightness set with `i`, `None` while the configured one is used.
+    pub fn brightness(&self) -> Option<u8> {
+        self.device.lock().unwrap().brightness
+    }
+
+    /// The color set with `l`, `None` while the LED shows the status.
+    pub fn led_color(&self) -> Option<(u8, u8, u8)> {
+        self.device.lock().unwrap().led_color
+    }
+
+    /// The boot splash image uploaded with `I`.
+    pub fn splash(&self) -> Option<Vec<u8>> {
+        self.device.lock().unwrap().splash.clone()
+    }
+
+    /// Append a line to the log returned by `L`.
+    pub fn push_log(&self, line: &str) {
+        let mut device = self.device.lock().unwrap();
+        device.logs.push_str(line);
+        device.logs.push('\n');
+    }
+
+    /// Report `flags` with `F`, `None` to reject it like firmware that
+    /// only has the 8-bit flags.
+    pub fn set_extended_flags(&self, flags: Option<u32>) {
+        self.device.lock().unwrap().extended_flags = flags;
+    }
+
+    /// `(received, total)` of the OTA session in progress, if any.
+    pub fn ota_progress(&self) -> Option<(usize, usize)> {
+        let device = self.device.lock().unwrap();
+        device.ota.as_ref().map(|ota| (ota.data.len(), ota.total))
+    }
+
+    /// Make the next OTA fail in the given way, `None` to succeed again.
+    pub fn fail_ota(&self, failure: Option<MockOtaFailure>) {
+        self.device.lock().unwrap().ota_failure = failure;
+    }
+
+    /// Truncate every response to at most `len` bytes, simulating short reads.
+    pub fn truncate_responses(&self, len: Option<usize>) {
+        self.device.lock().unwrap().truncate_responses = len;
+    }
+
+    /// Silently drop the next `count` response packets, simulating lost packets.
+    pub fn drop_responses(&self, count: usize) {
+        self.device.lock().unwrap().drop_responses = count;
+    }
+
+    /// Queue an unsolicited packet, it will be received before any later response.
+    pub fn inject_response(&self, packet: impl Into<Vec<u8>>) {
+        let _ = self.tx.unbounded_send(packet.into());
+    }
+
+    /// Let `after` more packets through, then fail the next `count` sends
+    /// with a transfer error before they reach the device.
+    pub fn fail_sends(&self, after: usize, count: usize) {
+        self.device.lock().unwrap().fail_sends = Some((after, count));
+    }
+
+    /// All packets received from the host so far.
+    pub fn received_packets(&self) -> Vec<Vec<u8>> {
+        self.device.lock().unwrap().received.clone()
+    }
+
+    fn handle_packet(&self, packet: &[u8]) {
+        let mut device = self.device.lock().unwrap();
+        device.received.push(packet.to_vec());
+        let responses = device.handle_packet(packet);
+        for mut response in responses {
+            if device.drop_responses > 0 {
+                device.drop_responses -= 1;
+                continue;
+            }
+            if let Some(len) = device.truncate_responses {
+                response.truncate(len);
+            }
+            let _ = self.tx.unbounded_send(response);
+        }
+    }
+}
+
+impl Transport for MockEsparrier {
+    async fn send_packet(&self, data: &[u8]) -> Result<(), Error> {
+        assert!(
+            data.len() <= 64,
+            "Buffer size must be less than or equal to 64 bytes"
+        );
+        {
+            let mut device = self.device.lock().unwrap();
+            if let Some((after, count)) = device.fail_sends {
+                if after > 0 {
+                    device.fail_sends = Some((after - 1, count));
+                } else {
+                    device.fail_sends = (count > 1).then(|| (0, count - 1));
+                    return Err(Error::TransferFailed(TransferError::Stall));
+                }
+            }
+        }
+        self.handle_packet(data);
+        Ok(())
+    }
+
+    async fn recv_packet(&self) -> Result<Vec<u8>, Error> {
+        // Like the real device, this waits until there is something to read
+        let mut rx = self.rx.lock().await;
+        rx.next().await.ok_or(Error::DeviceNotFound)
+    }
+}
+
+impl MockDevice {
+    fn handle_packet(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        match std::mem::replace(&mut self.incoming, Incoming::Command) {
+            Incoming::Command => self.handle_command(packet),
+            Incoming::ConfigBlocks {
+                remaining,
+                mut data,
+            } => {
+                data.extend_from_slice(packet);
+                if remaining > 1 {
+                    self.incoming = Incoming::ConfigBlocks {
+                        remaining: remaining - 1,
+                        data,
+                    };
+                    return vec![];
+                }
+                match serde_json::from_slice::<EsparrierConfig>(&data) {
+                    Ok(config) => {
+                        self.pending_config = Some(config);
+                        vec![b"o".to_vec()]
+                    }
+                    Err(_) => vec![b"e".to_vec()],
+                }
+            }
+            Incoming::OtaPackets {
+                remaining,
+                length,
+                mut data,
+            } => {
+                data.extend_from_slice(packet);
+                if remaining > 1 {
+                    self.incoming = Incoming::OtaPackets {
+                        remaining: remaining - 1,
+                        length,
+                        data,
+                    };
+                    return vec![];
+                }
+                data.truncate(length);
+                self.handle_ota_chunk(data)
+            }
+            Incoming::ClipboardBlocks { length, mut data } => {
+                data.extend_from_slice(packet);
+                if data.len() < length {
+                    self.incoming = Incoming::ClipboardBlocks { length, data };
+                    return vec![];
+                }
+                if data.len() > length {
+                    return vec![b"e".to_vec()];
+                }
+                self.clipboard = data;
+                vec![b"o".to_vec()]
+            }
+            Incoming::ImageBlocks { length, mut data } => {
+                data.extend_from_slice(packet);
+                if data.len() < length {
+                    self.incoming = Incoming::ImageBlocks { length, data };
+                    return vec![];
+                }
+                if data.len() > length {
+                    return vec![b"e".to_vec()];
+                }
+                self.splash = Some(data);
+                vec![b"o".to_vec()]
+            }
+        }
+    }
+
+    fn handle_command(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let Some(&command) = packet.first() else {
+            return vec![b"e".to_vec()];
+        };
+        match command {
+            b's' => {
+                let mut response = vec![b's'];
+                response.extend_from_slice(&state_to_bytes(&self.state));
+                vec![response]
+            }
+            b'r' | b'R' => {
+                // The uploaded but uncommitted config is read back by default,
+                // and only ReadFullConfig sends the password back
+                let mut config = match &self.pending_config {
+                    Some(config) if !self.read_committed_config => config.clone(),
+                    _ => self.config.clone(),
+                };
+                if command == b'r' {
+                    config.password.clear();
+                }
+                let data = serde_json::to_vec(&config).unwrap();
+                let mut responses = vec![vec![command, data.chunks(64).len() as u8]];
+                responses.extend(data.chunks(64).map(|c| c.to_vec()));
+                responses
+            }
+            b'w' if packet.len() >= 2 => {
+                if packet[1] == 0 {
+                    return vec![b"e".to_vec()];
+                }
+                self.incoming = Incoming::ConfigBlocks {
+                    remaining: packet[1] as usize,
+                    data: Vec::new(),
+                };
+                vec![]
+            }
+            b'c' => {
+                if let Some(config) = self.pending_config.take() {
+                    self.config = config;
+                }
+                self.commits += 1;
+                self.brightness = None;
+                self.led_color = None;
+                vec![b"o".to_vec()]
+            }
+            b'b' => {
+                self.reboots += 1;
+                self.brightness = None;
+                self.led_color = None;
+                vec![b"o".to_vec()]
+            }
+            b'k' if packet.len() >= 2 => {
+                self.state.keep_awake = packet[1] != 0;
+                vec![b"o".to_vec()]
+            }
+            b'i' if packet.len() >= 2 => {
+                if !(1..=100).contains(&packet[1]) {
+                    return vec![b"e".to_vec()];
+                }
+                self.brightness = Some(packet[1]);
+                vec![b"o".to_vec()]
+            }
+            b'l' if packet.len() >= 4 => {
+                self.led_color = Some((packet[1], packet[2], packet[3]));
+                vec![b"o".to_vec()]
+            }
+            b'O' if packet.len() >= 9 => {
+                if self.ota.is_some() {
+                    return vec![b"eOa".to_vec()];
+                }
+                if self.ota_failure == Some(MockOtaFailure::PartitionNotFound) {
+                    return vec![b"eOp".to_vec()];
+                }
+                let total = u32::from_le_bytes(packet[1..5].try_into().unwrap()) as usize;
+                let crc = u32::from_le_bytes(packet[5..9].try_into().unwrap());
+                if total == 0 || total > 0x100000 {
+                    return vec![b"eOs".to_vec()];
+                }
+                self.ota = Some(OtaSession {
+                    total,
+                    crc,
+                    data: Vec::with_capacity(total),
+                    chunks: 0,
+                });
+                vec![b"o".to_vec()]
+            }
+            b'D' if packet.len() >= 4 => {
+                if self.ota.is_none() {
+                    return vec![b"eOn".to_vec()];
+                }
+                let packets = packet[1] as usize;
+                let length = u16::from_le_bytes([packet[2], packet[3]]) as usize;
+                if packets == 0 {
+                    return self.handle_ota_chunk(Vec::new());
+                }
+                self.incoming = Incoming::OtaPackets {
+                    remaining: packets,
+                    length,
+                    data: Vec::new(),
+                };
+                vec![]
+            }
+            b'p' if packet.len() >= 3 => {
+                let length = u16::from_le_bytes([packet[1], packet[2]]) as usize;
+                if length > CLIPBOARD_MAX_SIZE {
+                    return vec![b"e".to_vec()];
+                }
+                if length == 0 {
+                    self.clipboard.clear();
+                    return vec![b"o".to_vec()];
+                }
+                self.incoming = Incoming::ClipboardBlocks {
+                    length,
+                    data: Vec::with_capacity(length),
+                };
+                vec![]
+            }
+            b'g' => {
+                let mut header = vec![b'g'];
+                header.extend_from_slice(&(self.clipboard.len() as u16).to_le_bytes());
+                let mut responses = vec![header];
+                responses.extend(self.clipboard.chunks(64).map(|c| c.to_vec()));
+                responses
+            }
+            b'I' if packet.len() >= 6 => {
+                let width = u16::from_le_bytes([packet[2], packet[3]]);
+                let height = u16::from_le_bytes([packet[4], packet[5]]);
+                if packet[1] != 0
+                    || model_display_size(self.state.model_id) != Some((width, height))
+                {
+                    return vec![b"e".to_vec()];
+                }
+                self.incoming = Incoming::ImageBlocks {
+                    length: width as usize * height as usize * 2,
+                    data: Vec::new(),
+                };
+                vec![]
+            }
+            b'L' => {
+                let mut data = self.logs.as_bytes().to_vec();
+                // The firmware pads the last block
+                data.resize(data.len().div_ceil(64) * 64, 0);
+                let mut responses = vec![vec![b'L', data.chunks(64).len() as u8]];
+                responses.extend(data.chunks(64).map(|c| c.to_vec()));
+                responses
+            }
+            b'E' => vec![packet.to_vec()],
+            b'F' => match self.extended_flags {
+                Some(flags) => {
+                    let mut response = vec![b'F'];
+                    response.extend_from_slice(&flags.to_le_bytes());
+                    vec![response]
+                }
+                None => vec![b"e".to_vec()],
+            },
+            b'A' => {
+                self.ota = None;
+                vec![b"o".to_vec()]
+            }
+            b'P' => match &self.ota {
+                Some(ota) => vec![progress_response(ota)],
+                None => vec![b"o".to_vec()],
+            },
+            _ => vec![b"e".to_vec()],
+        }
+    }
+
+    fn handle_ota_chunk(&mut self, chunk: Vec<u8>) -> Vec<Vec<u8>> {
+        let failure = self.ota_failure;
+        let Some(ota) = self.ota.as_mut() else {
+            return vec![b"eOn".to_vec()];
+        };
+        if failure == Some(MockOtaFailure::WriteFailed(ota.chunks)) {
+            self.ota = None;
+            return vec![b"eOw".to_vec()];
+        }
+        if failure == Some(MockOtaFailure::Stall(ota.chunks)) {
+            return vec![];
+        }
+        if failure == Some(MockOtaFailure::FlakyWrite(ota.chunks)) {
+            self.ota_failure = None;
+            return vec![b"eOw".to_vec()];
+        }
+        ota.chunks += 1;
+        ota.data.extend_from_slice(&chunk);
+        if ota.data.len() < ota.total {
+            return vec![progress_response(ota)];
+        }
+        let ota = self.ota.take().unwrap();
+        if failure == Some(MockOtaFailure::CrcMismatch) || crc32_ieee(&ota.data) != ota.crc {
+            return vec![b"eOc".to_vec()];
+        }
+        self.ota_image = Some(ota.data);
+        self.reboots += 1;
+        vec![b"C".to_vec()]
+    }
+}
+
+fn progress_response(ota: &OtaSession) -> Vec<u8> {
+    let mut response = vec![b'P'];
+    response.extend_from_slice(&(ota.data.len() as u32).to_le_bytes());
+    response.extend_from_slice(&(ota.total as u32).to_le_bytes());
+    response
+}
+
+/// Encode the state the way the firmware does, without the leading command byte.
+fn state_to_bytes(state: &EsparrierState) -> Vec<u8> {
+    let mut bytes = vec![
+        state.version_major,
+        state.version_minor,
+        state.version_patch,
+        state.feature_flags.bits(),
+    ];
+    bytes.extend(state.ip_address.octets());
+    bytes.extend([
+        state.ip_prefix,
+        state.server_connected as u8,
+        state.active as u8,
+        state.keep_awake as u8,
+        state.model_id,
+    ]);
+    bytes.extend(&state.extra);
+    bytes
+}
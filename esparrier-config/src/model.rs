@@ -0,0 +1,128 @@
+//! The device models, as reported in [`EsparrierState::model_id`](crate::EsparrierState::model_id).
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// A device model, serialized as its numeric id.
+///
+/// Use [`From<u8>`] to get one from a raw id, it never yields `Unknown` for a
+/// known id, so comparing models with `==` is reliable.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum ModelId {
+    M5AtomS3Lite,
+    M5AtomS3,
+    M5AtomS3R,
+    DevKitC10,
+    DevKitC11,
+    XiaoEsp32S3,
+    Esp32S3Eth,
+    Generic,
+    Unknown(u8),
+}
+
+const KNOWN_MODELS: [ModelId; 8] = [
+    ModelId::M5AtomS3Lite,
+    ModelId::M5AtomS3,
+    ModelId::M5AtomS3R,
+    ModelId::DevKitC10,
+    ModelId::DevKitC11,
+    ModelId::XiaoEsp32S3,
+    ModelId::Esp32S3Eth,
+    ModelId::Generic,
+];
+
+impl ModelId {
+    /// All known models, in id order.
+    pub fn all() -> &'static [ModelId] {
+        &KNOWN_MODELS
+    }
+
+    /// The numeric id reported by the firmware.
+    pub fn id(&self) -> u8 {
+        match self {
+            ModelId::M5AtomS3Lite => 1,
+            ModelId::M5AtomS3 => 2,
+            ModelId::M5AtomS3R => 3,
+            ModelId::DevKitC10 => 4,
+            ModelId::DevKitC11 => 5,
+            ModelId::XiaoEsp32S3 => 6,
+            ModelId::Esp32S3Eth => 7,
+            ModelId::Generic => 255,
+            ModelId::Unknown(id) => *id,
+        }
+    }
+
+    /// The firmware asset name prefix in GitHub releases, `None` for unknown models.
+    pub fn asset_prefix(&self) -> Option<&'static str> {
+        match self {
+            ModelId::M5AtomS3Lite => Some("m5atoms3-lite"),
+            ModelId::M5AtomS3 => Some("m5atoms3"),
+            ModelId::M5AtomS3R => Some("m5atoms3r"),
+            ModelId::DevKitC10 => Some("devkitc-1_0"),
+            ModelId::DevKitC11 => Some("devkitc-1_1"),
+            ModelId::XiaoEsp32S3 => Some("xiao-esp32s3"),
+            ModelId::Esp32S3Eth => Some("esp32-s3-eth"),
+            ModelId::Generic => Some("generic"),
+            ModelId::Unknown(_) => None,
+        }
+    }
+
+    /// The display size, `None` if the model has no display.
+    pub fn display_size(&self) -> Option<(u16, u16)> {
+        match self {
+            ModelId::M5AtomS3 | ModelId::M5AtomS3R => Some((128, 128)),
+            _ => None,
+        }
+    }
+}
+
+impl From<u8> for ModelId {
+    fn from(id: u8) -> Self {
+        KNOWN_MODELS
+            .into_iter()
+            .find(|model| model.id() == id)
+            .unwrap_or(ModelId::Unknown(id))
+    }
+}
+
+impl From<ModelId> for u8 {
+    fn from(model: ModelId) -> Self {
+        model.id()
+    }
+}
+
+/// Formats as the asset prefix, e.g. `m5atoms3`, or `unknown-<id>`.
+impl Display for ModelId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.asset_prefix() {
+            Some(prefix) => f.write_str(prefix),
+            None => write!(f, "unknown-{}", self.id()),
+        }
+    }
+}
+
+/// Parses the asset prefix, the `Display` output or the numeric id.
+impl FromStr for ModelId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let id = s.strip_prefix("unknown-").unwrap_or(s);
+        if let Ok(id) = id.parse::<u8>() {
+            return Ok(id.into());
+        }
+        KNOWN_MODELS
+            .into_iter()
+            .find(|model| {
+                model
+                    .asset_prefix()
+                    .is_some_and(|p| p.eq_ignore_ascii_case(s))
+            })
+            .ok_or_else(|| Error::FormatError(format!("Unknown model '{s}'")))
+    }
+}
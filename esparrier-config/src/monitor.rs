@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use futures::Stream;
+use futures_timer::Delay;
+use serde::Serialize;
+
+use crate::{Error, Esparrier, EsparrierState, Transport};
+
+/// A field of [`EsparrierState`] watched by [`Esparrier::state_stream`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateField {
+    ServerConnected,
+    Active,
+    KeepAwake,
+    /// The IP address or its prefix length.
+    IpAddress,
+}
+
+impl StateField {
+    /// All watched fields, in the order they're reported.
+    pub const ALL: [StateField; 4] = [
+        StateField::ServerConnected,
+        StateField::Active,
+        StateField::KeepAwake,
+        StateField::IpAddress,
+    ];
+
+    fn differs(self, a: &EsparrierState, b: &EsparrierState) -> bool {
+        match self {
+            StateField::ServerConnected => a.server_connected != b.server_connected,
+            StateField::Active => a.active != b.active,
+            StateField::KeepAwake => a.keep_awake != b.keep_awake,
+            StateField::IpAddress => (a.ip_address, a.ip_prefix) != (b.ip_address, b.ip_prefix),
+        }
+    }
+}
+
+/// A new state sample, see [`Esparrier::state_stream`].
+#[derive(Clone, Debug, Serialize)]
+pub struct StateChange {
+    pub state: EsparrierState,
+    /// The fields that differ from the previous sample, all of them for the first one.
+    pub changed: Vec<StateField>,
+}
+
+impl StateChange {
+    /// Check if `field` changed since the previous sample.
+    pub fn has_changed(&self, field: StateField) -> bool {
+        self.changed.contains(&field)
+    }
+}
+
+impl<T: Transport> Esparrier<T> {
+    /// Poll the state every `interval` and report when a watched field changes.
+    ///
+    /// The first sample is always reported. Errors, e.g. a timeout while the
+    /// device is busy, are reported too and polling goes on, later samples are
+    /// compared with the last successful one. Polling stops when the stream
+    /// is dropped.
+    pub fn state_stream(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<StateChange, Error>> + '_ {
+        futures::stream::unfold(
+            (None::<EsparrierState>, false),
+            move |(mut previous, mut wait)| async move {
+                loop {
+                    if wait {
+                        Delay::new(interval).await;
+                    }
+                    wait = true;
+                    let state = match self.get_state().await {
+                        Ok(state) => state,
+                        Err(e) => return Some((Err(e), (previous, wait))),
+                    };
+                    let changed = StateField::ALL
+                        .into_iter()
+                        .filter(|f| previous.as_ref().is_none_or(|p| f.differs(p, &state)))
+                        .collect::<Vec<_>>();
+                    if changed.is_empty() {
+                        previous = Some(state);
+                        continue;
+                    }
+                    let change = StateChange {
+                        state: state.clone(),
+                        changed,
+                    };
+                    return Some((Ok(change), (Some(state), wait)));
+                }
+            },
+        )
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::Error;
+
+/// What a device handle has transferred, see [`Esparrier::stats`](crate::Esparrier::stats).
+///
+/// OTA uploads are included, so throughput can be derived from two snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TransferStats {
+    /// Commands completed, successfully or not.
+    pub commands: u64,
+    pub failed_commands: u64,
+    pub packets_out: u64,
+    pub packets_in: u64,
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    /// Transfers sent again after a transient failure.
+    pub retries: u64,
+    /// When the last command failed.
+    pub last_error: Option<SystemTime>,
+}
+
+/// A command completed, passed to the hook set with
+/// [`Esparrier::with_command_hook`](crate::Esparrier::with_command_hook).
+#[derive(Debug)]
+pub struct CommandEvent<'a> {
+    /// The name of the method, e.g. `get_config`.
+    pub command: &'static str,
+    /// Time from sending the command to the end of the response.
+    pub duration: Duration,
+    pub result: Result<(), &'a Error>,
+}
+
+pub(crate) type CommandHook = Arc<dyn Fn(&CommandEvent<'_>) + Send + Sync>;
+
+/// The counters behind [`TransferStats`], updated without locking.
+#[derive(Default)]
+pub(crate) struct TransferCounters {
+    commands: AtomicU64,
+    failed_commands: AtomicU64,
+    packets_out: AtomicU64,
+    packets_in: AtomicU64,
+    bytes_out: AtomicU64,
+    bytes_in: AtomicU64,
+    retries: AtomicU64,
+    /// Milliseconds since the Unix epoch, 0 if no command failed yet.
+    last_error: AtomicU64,
+}
+
+impl TransferCounters {
+    pub(crate) fn sent(&self, len: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, len: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn completed(&self, failed: bool) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failed_commands.fetch_add(1, Ordering::Relaxed);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |d| d.as_millis().max(1) as u64);
+            self.last_error.store(now, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> TransferStats {
+        let last_error = self.last_error.load(Ordering::Relaxed);
+        TransferStats {
+            commands: self.commands.load(Ordering::Relaxed),
+            failed_commands: self.failed_commands.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            last_error: (last_error != 0).then(|| UNIX_EPOCH + Duration::from_millis(last_error)),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::future::Future;
+
+use async_lock::Mutex;
+use nusb::{
+    transfer::{Buffer, Bulk, Direction, EndpointDirection, In, Out, TransferError},
+    DeviceInfo, Endpoint, ErrorKind,
+};
+
+use crate::{DeviceSummary, Error};
+
+/// The packet channel between the host and the device.
+///
+/// The protocol layer in [`Esparrier`](crate::Esparrier) only ever exchanges
+/// single packets of up to 64 bytes, so anything that can carry them, e.g. a real
+/// USB bulk endpoint pair or an in-memory fake, can drive the device.
+pub trait Transport: Send + Sync {
+    /// Send a single packet, `data` is at most 64 bytes.
+    fn send_packet(&self, data: &[u8]) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Receive a single packet of at most 64 bytes.
+    fn recv_packet(&self) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+
+    /// Describe the device at the other end, if it's a USB device.
+    fn device_summary(&self) -> Option<DeviceSummary> {
+        None
+    }
+}
+
+/// The default transport, the vendor interface bulk endpoints of a USB device.
+pub struct NusbTransport {
+    device_info: DeviceInfo,
+    ep_in: Mutex<Endpoint<Bulk, In>>,
+    ep_out: Mutex<Endpoint<Bulk, Out>>,
+}
+
+impl NusbTransport {
+    /// Open the device and claim its Esparrier vendor interface.
+    pub async fn open(di: DeviceInfo) -> Result<Self, Error> {
+        let device = di.open().await.map_err(|e| {
+            if is_permission_denied(e.kind(), e.os_error()) {
+                Error::PermissionDenied
+            } else if e.kind() == ErrorKind::Busy {
+                Error::DeviceBusy
+            } else {
+                e.into()
+            }
+        })?;
+        let cfg = device.active_configuration()?;
+
+        // Find the interface with class 0xFF, subclass 0x0D, and protocol 0x0A
+        let iface_alt = cfg
+            .interface_alt_settings()
+            .find(|i| i.class() == 0xFF && i.subclass() == 0x0D && i.protocol() == 0x0A)
+            .ok_or(Error::UnknownDevice)?;
+
+        // Claim this interface
+        let interface = device
+            .claim_interface(iface_alt.interface_number())
+            .await
+            .map_err(|e| {
+                if is_permission_denied(e.kind(), e.os_error()) {
+                    Error::PermissionDenied
+                } else {
+                    Error::DeviceBusy
+                }
+            })?;
+
+        // Find the bulk IN and OUT endpoints
+        let alt = interface.descriptors().next().ok_or(Error::UnknownDevice)?;
+        let ep_in_addr = alt
+            .endpoints()
+            .find(|ep| ep.direction() == Direction::In)
+            .ok_or(Error::UnknownDevice)?
+            .address();
+        let ep_out_addr = alt
+            .endpoints()
+            .find(|ep| ep.direction() == Direction::Out)
+            .ok_or(Error::UnknownDevice)?
+            .address();
+
+        // Open the bulk endpoints
+        let ep_in = interface.endpoint::<Bulk, In>(ep_in_addr)?;
+        let ep_out = interface.endpoint::<Bulk, Out>(ep_out_addr)?;
+
+        Ok(Self {
+            device_info: di,
+            ep_in: Mutex::new(ep_in),
+            ep_out: Mutex::new(ep_out),
+        })
+    }
+
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+}
+
+impl Transport for NusbTransport {
+    async fn send_packet(&self, data: &[u8]) -> Result<(), Error> {
+        assert!(
+            data.len() <= 64,
+            "Buffer size must be less than or equal to 64 bytes"
+        );
+        let mut buf = Buffer::new(64);
+        buf.extend_from_slice(data);
+
+        let mut ep_out = self.ep_out.lock().await;
+        // A previous transfer may have been abandoned by a timeout
+        cancel_pending(&mut ep_out).await;
+        ep_out.submit(buf);
+        let completion = ep_out.next_complete().await;
+        if completion.status == Err(TransferError::Stall) {
+            let _ = ep_out.clear_halt().await;
+        }
+        completion.status.map_err(|e| e.into())
+    }
+
+    async fn recv_packet(&self) -> Result<Vec<u8>, Error> {
+        let buf = Buffer::new(64);
+
+        let mut ep_in = self.ep_in.lock().await;
+        // A previous transfer may have been abandoned by a timeout
+        cancel_pending(&mut ep_in).await;
+        ep_in.submit(buf);
+        let completion = ep_in.next_complete().await;
+        if completion.status == Err(TransferError::Stall) {
+            let _ = ep_in.clear_halt().await;
+        }
+        completion.status?;
+        Ok(completion.buffer[..completion.actual_len].to_vec())
+    }
+
+    fn device_summary(&self) -> Option<DeviceSummary> {
+        Some(DeviceSummary::from_device_info(&self.device_info))
+    }
+}
+
+/// Check if opening or claiming the device failed for lack of permission.
+///
+/// On Linux nusb only reports EPERM as `PermissionDenied`, while a missing
+/// udev rule makes opening the device node fail with EACCES.
+pub(crate) fn is_permission_denied(kind: ErrorKind, os_error: Option<u32>) -> bool {
+    const EPERM: u32 = 1;
+    const EACCES: u32 = 13;
+    kind == ErrorKind::PermissionDenied
+        || (cfg!(target_os = "linux") && matches!(os_error, Some(EPERM | EACCES)))
+}
+
+/// Cancel and reap any transfer left over from an interrupted operation,
+/// so the endpoint is clean for the next command.
+async fn cancel_pending<D: EndpointDirection>(ep: &mut Endpoint<Bulk, D>) {
+    if ep.pending() == 0 {
+        return;
+    }
+    ep.cancel_all();
+    while ep.pending() > 0 {
+        ep.next_complete().await;
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, hash::Hash};
+
+use futures::{future::ready, Stream, StreamExt};
+use log::debug;
+use nusb::hotplug::HotplugEvent;
+
+use crate::{open_new_device, DeviceFilter, DeviceSummary, Error, Esparrier};
+
+/// A device event, see [`Esparrier::watch`].
+pub enum EsparrierEvent {
+    /// A matching device was connected, or was already connected when watching started.
+    /// When auto-opening, this means the device couldn't be opened.
+    Connected(DeviceSummary),
+    /// A matching device was connected and opened, only sent when auto-opening.
+    Opened(DeviceSummary, Box<Esparrier>),
+    /// A device reported as connected before was disconnected.
+    Disconnected { bus: String, address: u8 },
+}
+
+/// A hotplug event, generic over the device id and what's needed to open it.
+pub(crate) enum RawEvent<I, D> {
+    Connected(I, DeviceSummary, D),
+    Disconnected(I),
+}
+
+/// Turn the raw events into [`EsparrierEvent`]s for the matching devices,
+/// `Connected` events come with what's needed to open the device.
+///
+/// Devices are tracked by id, as nusb only reports the id on disconnection,
+/// and a device reported both by the initial listing and the watcher is only
+/// reported once.
+pub(crate) fn track<I, D, S>(
+    filter: DeviceFilter,
+    initial: Vec<RawEvent<I, D>>,
+    events: S,
+) -> impl Stream<Item = (EsparrierEvent, Option<D>)>
+where
+    I: Eq + Hash,
+    S: Stream<Item = RawEvent<I, D>>,
+{
+    futures::stream::iter(initial)
+        .chain(events)
+        .scan(HashMap::new(), move |known, event| {
+            let event = match event {
+                RawEvent::Connected(id, device, data) => {
+                    if filter.matches(&device) && !known.contains_key(&id) {
+                        known.insert(id, device.clone());
+                        Some((EsparrierEvent::Connected(device), Some(data)))
+                    } else {
+                        None
+                    }
+                }
+                RawEvent::Disconnected(id) => known.remove(&id).map(|device| {
+                    let event = EsparrierEvent::Disconnected {
+                        bus: device.bus_id,
+                        address: device.address,
+                    };
+                    (event, None)
+                }),
+            };
+            ready(Some(event))
+        })
+        .filter_map(ready)
+}
+
+impl Esparrier {
+    /// Watch for matching devices being connected and disconnected.
+    ///
+    /// Unset VID and PID in `filter` default to the Esparrier ones. Devices already
+    /// connected are reported first. If `open` is true, connected devices are opened
+    /// and reported with [`EsparrierEvent::Opened`].
+    pub async fn watch(
+        filter: DeviceFilter,
+        open: bool,
+    ) -> Result<impl Stream<Item = EsparrierEvent>, Error> {
+        let filter = filter.with_default_ids();
+        // Watch before listing so nothing connected in between is missed
+        let watch = nusb::watch_devices()?;
+        let initial = match nusb::list_devices().await {
+            Ok(devices) => devices
+                .map(|di| RawEvent::Connected(di.id(), DeviceSummary::from_device_info(&di), di))
+                .collect(),
+            Err(e) => {
+                // Not fatal, devices will be reported once they're reconnected
+                debug!("Failed to list devices: {e}");
+                Vec::new()
+            }
+        };
+        let events = watch.map(|event| match event {
+            HotplugEvent::Connected(di) => {
+                RawEvent::Connected(di.id(), DeviceSummary::from_device_info(&di), di)
+            }
+            HotplugEvent::Disconnected(id) => RawEvent::Disconnected(id),
+        });
+        Ok(
+            track(filter, initial, events).then(move |(event, di)| async move {
+                match (event, di) {
+                    (EsparrierEvent::Connected(device), Some(di)) if open => {
+                        match open_new_device(di).await {
+                            Ok(transport) => {
+                                EsparrierEvent::Opened(device, Box::new(Esparrier::new(transport)))
+                            }
+                            Err(e) => {
+                                debug!("Failed to open device {device}: {e}");
+                                EsparrierEvent::Connected(device)
+                            }
+                        }
+                    }
+                    (event, _) => event,
+                }
+            }),
+        )
+    }
+}